@@ -0,0 +1,136 @@
+//! Throughput of the TFTP server.
+//!
+//! `send_data` measures the frame builder alone, `process` the boot of an emulated
+//! iPXE client through `PxeSocket::process` over a lossless link, for every
+//! windowsize the client asks for. File sizes are in MiB (1024 * 1024 bytes).
+//! The boots share a file cache, so the file is read from disk once per size.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+use rs_pxe::dhcp::parse::FirmwareType;
+use rs_pxe::emulator::{ClientConfig, Emulator, LinkConfig};
+use rs_pxe::tftp::cache::FileCache;
+use rs_pxe::tftp::construct::{TestTftp, TftpConnection, TftpOptionEnum, Transfer};
+use rs_pxe::tftp::error::Error;
+use rs_pxe::{PxeSocket, MAX_FRAME_LEN};
+
+const MIB: u64 = 1024 * 1024;
+
+/// One byte more than a multiple of every block size, the server never sends an
+/// empty last block
+const FILE_SIZES: [u64; 3] = [MIB + 1, 64 * MIB + 1, 512 * MIB + 1];
+
+const BLKSIZES: [usize; 3] = [512, 1456, 8192];
+
+/// 64 is the largest window the server agrees to
+const WINDOWSIZES: [usize; 3] = [1, 8, 64];
+
+/// Creates a sparse file of `len` bytes in the temp directory
+fn bench_file(len: u64) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rs_pxe_bench_{}MiB", len / MIB));
+    let file = File::create(&path).unwrap();
+    file.set_len(len).unwrap();
+    path
+}
+
+/// Data frames of a transfer, the last block is short
+fn throughput(len: u64, blksize: usize) -> Throughput {
+    Throughput::ElementsAndBytes {
+        elements: len / blksize as u64 + 1,
+        bytes: len,
+    }
+}
+
+fn connection() -> TftpConnection {
+    TftpConnection {
+        server_ip: Ipv4Address::new(192, 168, 178, 97),
+        server_mac: EthernetAddress([0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]),
+        client_ip: Ipv4Address::new(192, 168, 178, 75),
+        client_mac: EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+        server_port: 69,
+        client_port: 2071,
+    }
+}
+
+/// Sends every block of `path` and returns the number of frames
+fn send_file(path: &Path, blksize: usize, buffer: &mut [u8]) -> usize {
+    let handle = TestTftp::new(File::open(path).unwrap());
+    let now = Instant::from_secs(0);
+    let mut transfer = Transfer::new(handle, connection(), false, now);
+    transfer.options.add(TftpOptionEnum::Blksize, blksize);
+
+    let mut frames = 0;
+    loop {
+        match transfer.send_data(transfer.last_block_num, buffer, now) {
+            Ok(len) => {
+                black_box(&buffer[..len]);
+                frames += 1;
+            }
+            Err(Error::TftpEndOfFile) => return frames,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+fn send_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send_data");
+    group.sample_size(10);
+    let mut buffer = vec![0; MAX_FRAME_LEN];
+
+    for len in FILE_SIZES {
+        let path = bench_file(len);
+        for blksize in BLKSIZES {
+            group.throughput(throughput(len, blksize));
+            let id = BenchmarkId::new(format!("{}MiB", len / MIB), format!("blksize={}", blksize));
+            group.bench_function(id, |b| b.iter(|| send_file(&path, blksize, &mut buffer)));
+        }
+    }
+    group.finish();
+}
+
+/// Boots an emulated iPXE client that downloads `path`
+fn boot(path: &Path, files: &FileCache, blksize: usize, windowsize: usize) -> usize {
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress([0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let mut socket = PxeSocket::new(server_ip, server_mac, path, path);
+    socket.set_file_cache(files.clone());
+    let mut emulator = Emulator::new(&mut socket, LinkConfig::default());
+
+    let config = ClientConfig {
+        firmware: FirmwareType::IPxe,
+        blksize: Some(blksize),
+        windowsize: Some(windowsize),
+        ..Default::default()
+    };
+    let res = emulator.boot(config).unwrap();
+    black_box(res.data.len())
+}
+
+fn process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process");
+    group.sample_size(10);
+
+    for len in FILE_SIZES {
+        let path = bench_file(len);
+        let files = FileCache::new();
+        for blksize in BLKSIZES {
+            for windowsize in WINDOWSIZES {
+                group.throughput(throughput(len, blksize));
+                let id = BenchmarkId::new(
+                    format!("{}MiB", len / MIB),
+                    format!("blksize={},windowsize={}", blksize, windowsize),
+                );
+                group.bench_function(id, |b| b.iter(|| boot(&path, &files, blksize, windowsize)));
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, send_data, process);
+criterion_main!(benches);
@@ -58,6 +58,7 @@
         runtimeDeps = with pkgs; [
           qemu
           cargo-watch
+          cargo-fuzz
           rust-analyzer-nightly
           dhcpcd
           dnsmasq
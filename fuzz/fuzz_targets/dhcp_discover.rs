@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_pxe::dhcp::parse::pxe_discover;
+use smoltcp::wire::DhcpPacket;
+
+fuzz_target!(|data: &[u8]| {
+    let dhcp = match DhcpPacket::new_checked(data) {
+        Ok(dhcp) => dhcp,
+        Err(_) => return,
+    };
+    if let Ok(info) = pxe_discover(dhcp) {
+        // The client identifier is printed in most log lines
+        let _ = info.client_identifier.to_string();
+    }
+});
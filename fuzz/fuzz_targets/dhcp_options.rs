@@ -0,0 +1,47 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_pxe::dhcp::options::*;
+
+// The first byte selects the DHCP option, the rest is its payload
+fuzz_target!(|data: &[u8]| {
+    let (kind, value) = match data.split_first() {
+        Some((kind, value)) => (*kind, value),
+        None => return,
+    };
+
+    match SubsetDhcpOption::try_from(kind) {
+        Ok(SubsetDhcpOption::ClientUuid) => {
+            let _ = PxeUuid::try_from(value);
+        }
+        Ok(SubsetDhcpOption::ClientIdentifier) => {
+            if let Ok(id) = ClientIdentifier::try_from(value) {
+                let _ = id.to_string();
+            }
+        }
+        Ok(SubsetDhcpOption::ClientNetworkInterfaceIdentifier) => {
+            let _ = NetworkInterfaceVersion::try_from(value);
+        }
+        Ok(SubsetDhcpOption::ClientSystemArchitecture) => {
+            let _ = ClientArchType::try_from(value);
+        }
+        Ok(SubsetDhcpOption::VendorClassIdentifier) => {
+            if let Ok(vendor_id) = VendorClassIdentifier::try_from(value) {
+                let _ = vendor_id.pxe_class();
+            }
+        }
+        Ok(SubsetDhcpOption::VendorOptions) => {
+            let _ = PxeDiscoverControl::try_from(value);
+        }
+        Ok(SubsetDhcpOption::ServerIdentifier) => {
+            let _ = PxeServerIdentifier::try_from(value);
+        }
+        Ok(SubsetDhcpOption::RelayAgentInformation) => {
+            let _ = RelayAgentInformation::try_from(value);
+        }
+        Ok(SubsetDhcpOption::IpxeEncapsulated) => {
+            let _ = IpxeFeatures::try_from(value);
+        }
+        _ => (),
+    }
+});
@@ -0,0 +1,82 @@
+#![no_main]
+
+use std::path::Path;
+
+use libfuzzer_sys::fuzz_target;
+use rs_pxe::{tftp::construct::RETRY_TIMEOUT, PxeSocket};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpProtocol, Ipv4Address,
+    Ipv4Packet, UdpPacket,
+};
+
+// Server of the captures in assets/, so the seed corpus replays whole boots
+const SERVER_IP: Ipv4Address = Ipv4Address([192, 168, 178, 97]);
+const SERVER_MAC: EthernetAddress = EthernetAddress([0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+const STAGE_ONE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/ipxe.pxe");
+const STAGE_TWO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/kernel.elf");
+
+// The input is a sequence of ethernet frames, each prefixed with its length as big endian u16.
+// Every frame goes through the same socket so the fuzzer can walk the DHCP and TFTP state machines.
+fuzz_target!(|data: &[u8]| {
+    let mut socket = PxeSocket::new(
+        SERVER_IP,
+        SERVER_MAC,
+        Path::new(STAGE_ONE),
+        Path::new(STAGE_TWO),
+    );
+
+    let mut now = Instant::from_secs(0);
+    let mut rest = data;
+    while let [hi, lo, tail @ ..] = rest {
+        let len = usize::from(u16::from_be_bytes([*hi, *lo])).min(tail.len());
+        let (frame, tail) = tail.split_at(len);
+        rest = tail;
+
+        let mut frame = frame.to_vec();
+        fix_headers(&mut frame);
+        // Every frame arrives a retransmission timeout after the previous one
+        now += RETRY_TIMEOUT;
+        let _ = socket.process(&frame, now);
+        let _ = socket.process_timeout(now);
+    }
+});
+
+/// Rewrites the length fields and checksums of the IPv4 and UDP headers.
+/// Without this nearly every mutation would be dropped by the header checks
+/// before it reaches the DHCP and TFTP parsers.
+fn fix_headers(frame: &mut [u8]) {
+    let mut ether = match EthernetFrame::new_checked(frame) {
+        Ok(ether) => ether,
+        Err(_) => return,
+    };
+    if ether.ethertype() != EthernetProtocol::Ipv4 {
+        return;
+    }
+
+    let payload = ether.payload_mut();
+    let total_len = payload.len();
+    if total_len < 20 {
+        return;
+    }
+    let mut ipv4 = Ipv4Packet::new_unchecked(payload);
+    let header_len = usize::from(ipv4.header_len());
+    if header_len < 20 || header_len > total_len {
+        return;
+    }
+    ipv4.set_total_len(total_len as u16);
+    ipv4.fill_checksum();
+    if ipv4.next_header() != IpProtocol::Udp {
+        return;
+    }
+
+    let (src_addr, dst_addr) = (ipv4.src_addr(), ipv4.dst_addr());
+    let payload = ipv4.payload_mut();
+    let udp_len = payload.len();
+    if udp_len < 8 {
+        return;
+    }
+    let mut udp = UdpPacket::new_unchecked(payload);
+    udp.set_len(udp_len as u16);
+    udp.fill_checksum(&IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr));
+}
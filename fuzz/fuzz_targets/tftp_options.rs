@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_pxe::tftp::parse::TftpOptsReader;
+
+fuzz_target!(|data: &[u8]| {
+    for opt in TftpOptsReader::new(data).options() {
+        let _ = opt.value.parse::<usize>();
+    }
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rs_pxe::tftp::parse::{Packet, Repr};
+
+fuzz_target!(|data: &[u8]| {
+    let packet = match Packet::new_checked(data) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    let repr = match Repr::parse(&packet) {
+        Ok(repr) => repr,
+        Err(_) => return,
+    };
+
+    // A parsed packet has to survive the accessors and emitting it again
+    let opts = match repr {
+        Repr::ReadRequest { opts, .. }
+        | Repr::WriteRequest { opts, .. }
+        | Repr::OptionAck { opts } => Some(opts),
+        _ => None,
+    };
+    if let Some(opts) = opts {
+        opts.options().for_each(drop);
+    }
+
+    let mut buffer = vec![0; repr.buffer_len()];
+    let mut packet = Packet::new_unchecked(&mut buffer[..]);
+    let _ = repr.emit(&mut packet);
+});
@@ -0,0 +1,318 @@
+//! Answers ARP requests for the server address and remembers the clients it saw.
+//!
+//! The raw socket bypasses the host network stack, so if the host does not own
+//! the server ip nobody else answers the who-has of a PXE client before TFTP.
+//!
+//! Unicast DHCP and TFTP replies are addressed to the mac a client announced
+//! last, so a transfer follows a client whose address moved to another NIC.
+
+use std::collections::HashMap;
+
+use log::*;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+    Ipv4Address, Ipv4Packet,
+};
+
+use crate::dhcp::utils::arp_reply;
+use crate::prelude::*;
+
+/// How long a learned entry stays valid
+pub const ARP_CACHE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Maximum number of clients kept in the cache
+pub const ARP_CACHE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ArpEntry {
+    mac: EthernetAddress,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct ArpCache {
+    entries: HashMap<Ipv4Address, ArpEntry>,
+}
+
+impl ArpCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the mac address of `ip` if it was seen within `ARP_CACHE_TIMEOUT`
+    pub fn lookup(&self, ip: &Ipv4Address, now: Instant) -> Option<EthernetAddress> {
+        self.entries
+            .get(ip)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.mac)
+    }
+
+    pub fn fill(&mut self, ip: Ipv4Address, mac: EthernetAddress, now: Instant) {
+        if !ip.is_unicast() || !mac.is_unicast() {
+            return;
+        }
+
+        if self.entries.len() >= ARP_CACHE_SIZE && !self.entries.contains_key(&ip) {
+            self.entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if self.entries.len() >= ARP_CACHE_SIZE && !self.entries.contains_key(&ip) {
+            // Still full, make room by dropping the entry closest to expiry
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(ip, _)| *ip)
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let entry = ArpEntry {
+            mac,
+            expires_at: now + ARP_CACHE_TIMEOUT,
+        };
+        if self.entries.insert(ip, entry).map(|e| e.mac) != Some(mac) {
+            debug!("Learned arp entry {} -> {}", ip, mac);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Learns the sender of an ARP frame, other frames are ignored.
+    /// Senders are learned even if the host answers the requests.
+    pub fn learn(&mut self, rx_buffer: &[u8], server_mac: &EthernetAddress, now: Instant) {
+        let ether = match EthernetFrame::new_checked(rx_buffer) {
+            Ok(e) if e.ethertype() == EthernetProtocol::Arp && e.src_addr() != *server_mac => e,
+            _ => return,
+        };
+        let arp =
+            ArpPacket::new_checked(ether.payload()).and_then(|packet| ArpRepr::parse(&packet));
+        if let Ok(ArpRepr::EthernetIpv4 {
+            source_hardware_addr,
+            source_protocol_addr,
+            ..
+        }) = arp
+        {
+            self.fill(source_protocol_addr, source_hardware_addr, now);
+        }
+    }
+
+    /// Sets the destination mac of a unicast IPv4 reply to the cached mac of its
+    /// destination ip. Broadcasts and unknown destinations are not touched.
+    pub fn address(&self, frame: &mut [u8], now: Instant) {
+        let mut ether = match EthernetFrame::new_checked(frame) {
+            Ok(e) if e.ethertype() == EthernetProtocol::Ipv4 && e.dst_addr().is_unicast() => e,
+            _ => return,
+        };
+        let dst_ip = match Ipv4Packet::new_checked(ether.payload()) {
+            Ok(ipv4) => ipv4.dst_addr(),
+            Err(_) => return,
+        };
+        if let Some(mac) = self.lookup(&dst_ip, now) {
+            if mac != ether.dst_addr() {
+                debug!("Addressing {} at its announced mac {}", dst_ip, mac);
+                ether.set_dst_addr(mac);
+            }
+        }
+    }
+}
+
+/// Answers who-has requests for `server_ip`.
+/// Returns `Error::IgnoreNoLog` for frames that are not ARP.
+pub fn arp_respond(
+    rx_buffer: &[u8],
+    server_ip: &Ipv4Address,
+    server_mac: &EthernetAddress,
+) -> Result<Vec<u8>> {
+    let ether = match EthernetFrame::new_checked(rx_buffer) {
+        Ok(e) if e.ethertype() == EthernetProtocol::Arp => e,
+        _ => return Err(Error::IgnoreNoLog("Not an arp packet".to_string())),
+    };
+    if ether.src_addr() == *server_mac {
+        return Err(Error::IgnoreNoLog("Own arp packet".to_string()));
+    }
+    if ether.dst_addr() != *server_mac && !ether.dst_addr().is_broadcast() {
+        return Err(Error::IgnoreNoLog(
+            "Arp packet for another host".to_string(),
+        ));
+    }
+
+    let arp = ArpPacket::new_checked(ether.payload())
+        .and_then(|packet| ArpRepr::parse(&packet))
+        .map_err(|e| Error::Ignore(f!("Parsing arp packet failed: {}", e)))?;
+
+    let (operation, source_hardware_addr, source_protocol_addr, target_protocol_addr) = match arp {
+        ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        } => (
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+        ),
+        _ => return Err(Error::Ignore("Unsupported arp packet".to_string())),
+    };
+
+    if operation != ArpOperation::Request || target_protocol_addr != *server_ip {
+        return Err(Error::IgnoreNoLog(f!(
+            "Arp {:?} for {}",
+            operation,
+            target_protocol_addr
+        )));
+    }
+    // Address conflict detection of another host, not a question for us
+    if source_protocol_addr == *server_ip {
+        warn!(
+            "Host {} probes for our address {}",
+            source_hardware_addr, server_ip
+        );
+        return Err(Error::Ignore("Arp probe for our address".to_string()));
+    }
+
+    debug!(
+        "Answering arp request of {} ({})",
+        source_protocol_addr, source_hardware_addr
+    );
+    let reply = ArpRepr::EthernetIpv4 {
+        operation: ArpOperation::Reply,
+        source_hardware_addr: *server_mac,
+        source_protocol_addr: *server_ip,
+        target_hardware_addr: source_hardware_addr,
+        target_protocol_addr: source_protocol_addr,
+    };
+    Ok(arp_reply(reply))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::read_frames;
+    use crate::PxeSocket;
+    use std::path::Path;
+
+    #[test]
+    fn test_arp_reply() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 21);
+        let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+        let client_ip = Ipv4Address::new(192, 168, 178, 75);
+        let client_mac = EthernetAddress::from_bytes(&[0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe]);
+
+        let frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+
+        // Broadcast who-has of the PXE client
+        let reply = arp_respond(&frames[8], &server_ip, &server_mac).unwrap();
+        let ether = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(ether.dst_addr(), client_mac);
+        let arp = ArpRepr::parse(&ArpPacket::new_checked(ether.payload()).unwrap()).unwrap();
+        assert_eq!(
+            arp,
+            ArpRepr::EthernetIpv4 {
+                operation: ArpOperation::Reply,
+                source_hardware_addr: server_mac,
+                source_protocol_addr: server_ip,
+                target_hardware_addr: client_mac,
+                target_protocol_addr: client_ip,
+            }
+        );
+
+        // Our own reply and gratuitous arp are ignored
+        assert!(arp_respond(&frames[9], &server_ip, &server_mac).is_err());
+        assert!(arp_respond(&frames[2], &server_ip, &server_mac).is_err());
+
+        // Not an arp packet
+        assert!(matches!(
+            arp_respond(&frames[3], &server_ip, &server_mac),
+            Err(Error::IgnoreNoLog(_))
+        ));
+    }
+
+    #[test]
+    fn test_arp_cache() {
+        let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+        let client_ip = Ipv4Address::new(192, 168, 178, 75);
+        let client_mac = EthernetAddress::from_bytes(&[0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe]);
+        let now = Instant::from_secs(0);
+
+        let frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+        let mut cache = ArpCache::new();
+
+        // The who-has of the PXE client, our own reply is not learned
+        cache.learn(&frames[8], &server_mac, now);
+        cache.learn(&frames[9], &server_mac, now);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.lookup(&client_ip, now), Some(client_mac));
+        assert_eq!(cache.lookup(&client_ip, now + ARP_CACHE_TIMEOUT), None);
+
+        // Unicast frames to the client follow it to its new mac
+        let moved = EthernetAddress::from_bytes(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+        cache.fill(client_ip, moved, now);
+        let mut reply = frames[10].clone();
+        reply[0..6].copy_from_slice(client_mac.as_bytes());
+        reply[30..34].copy_from_slice(client_ip.as_bytes());
+        cache.address(&mut reply, now);
+        assert_eq!(
+            EthernetFrame::new_checked(&reply[..]).unwrap().dst_addr(),
+            moved
+        );
+
+        // Broadcasts keep their address
+        let mut offer = frames[4].clone();
+        cache.address(&mut offer, now);
+        assert_eq!(offer, frames[4]);
+    }
+
+    #[test]
+    fn test_arp_opt_out() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 21);
+        let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+        let client_ip = Ipv4Address::new(192, 168, 178, 75);
+        let now = Instant::from_secs(1);
+        let mut pxe_socket = PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        );
+        let frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+
+        // The host owns the address, it answers and announces it itself
+        pxe_socket.set_arp(false);
+        assert!(pxe_socket.process(&frames[8], now).is_err());
+        assert!(pxe_socket.process_timeout(now).is_err());
+
+        // The client is still learned
+        assert!(pxe_socket.get_arp_cache().lookup(&client_ip, now).is_some());
+
+        pxe_socket.set_arp(true);
+        assert!(pxe_socket.process(&frames[8], now).is_ok());
+    }
+
+    #[test]
+    fn test_arp_cache_size() {
+        let mut cache = ArpCache::new();
+        let mac = EthernetAddress::from_bytes(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let now = Instant::from_secs(0);
+
+        for i in 0..(ARP_CACHE_SIZE as u8 + 10) {
+            let now = now + Duration::from_secs(i.into());
+            cache.fill(Ipv4Address::new(10, 0, 0, i + 1), mac, now);
+        }
+        assert_eq!(cache.len(), ARP_CACHE_SIZE);
+        assert_eq!(cache.lookup(&Ipv4Address::new(10, 0, 0, 1), now), None);
+
+        // Probes from unconfigured hosts are not learned
+        cache.fill(Ipv4Address::UNSPECIFIED, mac, now);
+        assert_eq!(cache.lookup(&Ipv4Address::UNSPECIFIED, now), None);
+    }
+}
@@ -0,0 +1,335 @@
+//! Classic BPF filter for the raw socket.
+//!
+//! Without a filter the kernel copies every frame on the link to userspace,
+//! where all but a few are dropped again by the parsers. The filter only lets
+//! through what the state machines answer:
+//! - UDP to the DHCP (67, 68), TFTP (69) and PXE (4011) ports. Transfers
+//!   are answered from port 69 as well, there are no ephemeral TFTP ports.
+//! - ARP requests for one of our addresses
+//! - If rs_pxe answers ICMP, see [`crate::PxeSocket::set_icmp`], ICMP and UDP
+//!   to any other port for one of our addresses. Otherwise the host answers them.
+
+use std::os::unix::io::AsRawFd;
+
+use smoltcp::wire::Ipv4Address;
+
+use crate::icmp::SERVED_UDP_PORTS;
+use crate::prelude::*;
+
+// Instruction classes and fields, see linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xa0;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+const BPF_K: u16 = 0x00;
+
+// Ancillary data of the packet instead of its bytes, see linux/filter.h
+const SKF_AD_OFF: u32 = (-0x1000i32) as u32;
+const SKF_AD_VLAN_TAG_PRESENT: u32 = 48;
+
+const ETH_P_IP: u32 = 0x0800;
+const ETH_P_ARP: u32 = 0x0806;
+const ARP_OP_REQUEST: u32 = 1;
+
+/// Bytes of a frame passed to userspace, more than any frame we receive
+const ACCEPT_LEN: u32 = 0x40000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Next,
+    Accept,
+    Drop,
+    Ipv4,
+    OwnIp,
+}
+
+/// Minimal assembler resolving jumps to labels into relative offsets
+#[derive(Debug, Default)]
+struct Program {
+    instructions: Vec<(u16, Label, Label, u32)>,
+    labels: Vec<(Label, usize)>,
+}
+
+impl Program {
+    fn stmt(&mut self, code: u16, k: u32) {
+        self.instructions.push((code, Label::Next, Label::Next, k));
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: Label, jf: Label) {
+        self.instructions.push((code, jt, jf, k));
+    }
+
+    fn label(&mut self, label: Label) {
+        self.labels.push((label, self.instructions.len()));
+    }
+
+    /// Jumps to `Accept` if the accumulator holds one of `ips`, to `Drop` otherwise
+    fn match_ips(&mut self, ips: &[Ipv4Address]) {
+        for (i, ip) in ips.iter().enumerate() {
+            let jf = if i + 1 == ips.len() {
+                Label::Drop
+            } else {
+                Label::Next
+            };
+            self.jump(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                u32::from_be_bytes(ip.0),
+                Label::Accept,
+                jf,
+            );
+        }
+    }
+
+    fn offset(&self, from: usize, label: Label) -> Result<u8> {
+        if label == Label::Next {
+            return Ok(0);
+        }
+        let target = self
+            .labels
+            .iter()
+            .find(|(l, _)| *l == label)
+            .map(|(_, pos)| *pos)
+            .ok_or_else(|| Error::Config(f!("Undefined bpf label {:?}", label)))?;
+        u8::try_from(target - from - 1)
+            .map_err(|_| Error::Config("Bpf filter is too long, too many addresses".to_string()))
+    }
+
+    fn assemble(&self) -> Result<Vec<libc::sock_filter>> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .map(|(i, (code, jt, jf, k))| {
+                Ok(libc::sock_filter {
+                    code: *code,
+                    jt: self.offset(i, *jt)?,
+                    jf: self.offset(i, *jf)?,
+                    k: *k,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builds the filter for a socket serving `server_ips`.
+/// With `icmp` the frames `icmp::icmp_respond` answers are passed as well.
+///
+/// The kernel removes 802.1Q tags before the socket sees a frame and keeps the
+/// tag in the packet metadata. Tagged frames belong to the VLAN interfaces and
+/// are dropped, see [`crate::vlan`].
+pub fn pxe_filter(server_ips: &[Ipv4Address], icmp: bool) -> Result<Vec<libc::sock_filter>> {
+    if server_ips.is_empty() {
+        return Err(Error::Config("Bpf filter without server ip".to_string()));
+    }
+
+    let mut prog = Program::default();
+    prog.stmt(
+        BPF_LD | BPF_W | BPF_ABS,
+        SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT,
+    );
+    prog.jump(BPF_JMP | BPF_JEQ | BPF_K, 0, Label::Next, Label::Drop);
+
+    // Offset of the ethertype
+    let off = 12;
+    prog.stmt(BPF_LD | BPF_H | BPF_ABS, off);
+    prog.jump(
+        BPF_JMP | BPF_JEQ | BPF_K,
+        ETH_P_IP,
+        Label::Ipv4,
+        Label::Next,
+    );
+    prog.jump(
+        BPF_JMP | BPF_JEQ | BPF_K,
+        ETH_P_ARP,
+        Label::Next,
+        Label::Drop,
+    );
+    // Arp opcode
+    prog.stmt(BPF_LD | BPF_H | BPF_ABS, off + 2 + 6);
+    prog.jump(
+        BPF_JMP | BPF_JEQ | BPF_K,
+        ARP_OP_REQUEST,
+        Label::Next,
+        Label::Drop,
+    );
+    // Arp target protocol address
+    prog.stmt(BPF_LD | BPF_W | BPF_ABS, off + 2 + 24);
+    prog.match_ips(server_ips);
+
+    // Frames the host answers, or rs_pxe with `icmp`
+    let unserved = if icmp { Label::OwnIp } else { Label::Drop };
+
+    prog.label(Label::Ipv4);
+    prog.stmt(BPF_LD | BPF_B | BPF_ABS, off + 2 + 9);
+    prog.jump(BPF_JMP | BPF_JEQ | BPF_K, 1, unserved, Label::Next);
+    prog.jump(BPF_JMP | BPF_JEQ | BPF_K, 17, Label::Next, Label::Drop);
+    // Only the first fragment has a udp header
+    prog.stmt(BPF_LD | BPF_H | BPF_ABS, off + 2 + 6);
+    prog.jump(BPF_JMP | BPF_JSET | BPF_K, 0x1fff, Label::Drop, Label::Next);
+    // X = ipv4 header length
+    prog.stmt(BPF_LDX | BPF_B | BPF_MSH, off + 2);
+    // Udp destination port
+    prog.stmt(BPF_LD | BPF_H | BPF_IND, off + 2 + 2);
+    prog.jump(BPF_JMP | BPF_JEQ | BPF_K, 68, Label::Accept, Label::Next);
+    for (i, port) in SERVED_UDP_PORTS.iter().enumerate() {
+        let jf = if i + 1 == SERVED_UDP_PORTS.len() {
+            unserved
+        } else {
+            Label::Next
+        };
+        prog.jump(BPF_JMP | BPF_JEQ | BPF_K, (*port).into(), Label::Accept, jf);
+    }
+
+    if icmp {
+        prog.label(Label::OwnIp);
+        // Ipv4 destination address
+        prog.stmt(BPF_LD | BPF_W | BPF_ABS, off + 2 + 16);
+        prog.match_ips(server_ips);
+    }
+
+    prog.label(Label::Accept);
+    prog.stmt(BPF_RET | BPF_K, ACCEPT_LEN);
+    prog.label(Label::Drop);
+    prog.stmt(BPF_RET | BPF_K, 0);
+
+    prog.assemble()
+}
+
+/// Attaches `filter` to the AF_PACKET socket of `device`
+pub fn attach_filter<D: AsRawFd>(device: &D, filter: &[libc::sock_filter]) -> Result<()> {
+    let prog = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+
+    let res = unsafe {
+        libc::setsockopt(
+            device.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &prog as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::read_frames;
+    use std::path::Path;
+
+    /// Interprets the subset of classic BPF emitted by `pxe_filter`.
+    /// `vlan` is the tag the kernel removed from `frame`.
+    fn run_tagged(filter: &[libc::sock_filter], frame: &[u8], vlan: Option<u16>) -> u32 {
+        let load = |offset: usize, size: usize| -> Option<u32> {
+            if offset == (SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT) as usize {
+                return Some(vlan.is_some().into());
+            }
+            let bytes = frame.get(offset..offset + size)?;
+            Some(bytes.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b)))
+        };
+
+        let (mut acc, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let ins = filter[pc];
+            let k = ins.k as usize;
+            pc += 1;
+            let loaded = match ins.code {
+                c if c == BPF_LD | BPF_W | BPF_ABS => load(k, 4),
+                c if c == BPF_LD | BPF_H | BPF_ABS => load(k, 2),
+                c if c == BPF_LD | BPF_B | BPF_ABS => load(k, 1),
+                c if c == BPF_LD | BPF_H | BPF_IND => load(x as usize + k, 2),
+                c if c == BPF_LDX | BPF_B | BPF_MSH => {
+                    match load(k, 1) {
+                        Some(b) => x = (b & 0xf) * 4,
+                        None => return 0,
+                    }
+                    continue;
+                }
+                c if c == BPF_JMP | BPF_JEQ | BPF_K || c == BPF_JMP | BPF_JSET | BPF_K => {
+                    let taken = if c & BPF_JSET == BPF_JSET {
+                        acc & ins.k != 0
+                    } else {
+                        acc == ins.k
+                    };
+                    pc += usize::from(if taken { ins.jt } else { ins.jf });
+                    continue;
+                }
+                c if c == BPF_RET | BPF_K => return ins.k,
+                c => panic!("Unsupported bpf instruction {:#x}", c),
+            };
+            // Out of bounds loads drop the frame like in the kernel
+            match loaded {
+                Some(value) => acc = value,
+                None => return 0,
+            }
+        }
+    }
+
+    fn run(filter: &[libc::sock_filter], frame: &[u8]) -> u32 {
+        run_tagged(filter, frame, None)
+    }
+
+    #[test]
+    fn test_pxe_filter() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let filter = pxe_filter(&[server_ip], false).unwrap();
+
+        // Dhcp discover, request and tftp read request
+        let dhcp = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        assert_eq!(run(&filter, &dhcp[0]), ACCEPT_LEN);
+        let tftp = read_frames(Path::new("./assets/intel_bios_tftp.pcapng"));
+        assert_eq!(run(&filter, &tftp[0]), ACCEPT_LEN);
+
+        // Udp to a port we do not serve
+        let mut closed = tftp[0].clone();
+        closed[14 + 20 + 2..14 + 20 + 4].copy_from_slice(&5000u16.to_be_bytes());
+        assert_eq!(run(&filter, &closed), 0);
+
+        // Unless rs_pxe answers with port unreachable, then only for our address
+        let icmp_filter = pxe_filter(&[server_ip], true).unwrap();
+        assert_eq!(run(&icmp_filter, &closed), ACCEPT_LEN);
+        assert_eq!(run(&icmp_filter, &tftp[0]), ACCEPT_LEN);
+        let icmp_filter = pxe_filter(&[Ipv4Address::new(10, 0, 0, 1)], true).unwrap();
+        assert_eq!(run(&icmp_filter, &closed), 0);
+
+        // Arp requests are only passed for our address
+        let server_ip = Ipv4Address::new(192, 168, 178, 21);
+        let filter = pxe_filter(&[Ipv4Address::new(10, 0, 0, 1), server_ip], false).unwrap();
+        let arp = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+        assert_eq!(run(&filter, &arp[8]), ACCEPT_LEN);
+        let filter = pxe_filter(&[Ipv4Address::new(10, 0, 0, 1)], false).unwrap();
+        assert_eq!(run(&filter, &arp[8]), 0);
+
+        // Arp replies are answered by nobody
+        let filter = pxe_filter(&[server_ip], false).unwrap();
+        let mut reply = arp[8].clone();
+        reply[14 + 6..14 + 8].copy_from_slice(&2u16.to_be_bytes());
+        assert_eq!(run(&filter, &reply), 0);
+    }
+
+    #[test]
+    fn test_pxe_filter_vlan() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let filter = pxe_filter(&[server_ip], false).unwrap();
+
+        // Frames of a VLAN are served on its VLAN interface
+        let dhcp = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        assert_eq!(run_tagged(&filter, &dhcp[0], None), ACCEPT_LEN);
+        assert_eq!(run_tagged(&filter, &dhcp[0], Some(100)), 0);
+
+        assert!(pxe_filter(&[], false).is_err());
+    }
+}
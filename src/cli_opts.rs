@@ -7,6 +7,7 @@ use log::*;
 use std::env;
 use std::fs::File;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use std::process;
 use std::str::{self, FromStr};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -16,6 +17,9 @@ use smoltcp::phy::TunTapInterface;
 use smoltcp::phy::{Device, FaultInjector, Medium, Tracer};
 use smoltcp::phy::{PcapMode, PcapWriter};
 use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{EthernetAddress, Ipv4Cidr};
+
+use rs_pxe::vlan::VlanConfig;
 
 pub fn setup_logging(level: LevelFilter) {
     Builder::new()
@@ -51,7 +55,12 @@ pub fn setup_logging(level: LevelFilter) {
 pub fn create_options() -> (Options, Vec<&'static str>) {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
-    opts.optopt("i", "interface", "Interface to use", "enp2s0");
+    opts.optmulti(
+        "i",
+        "interface",
+        "Interface to use. Can be given multiple times, settings after the name override the global options",
+        "enp2s0[,ip=<ip>/<prefix>][,mac=<mac>][,ipxe=<path>][,kernel=<path>][,vlan=<vlan>][,back-off]",
+    );
     opts.optopt("", "ipxe", "Path to custom ipxe image", "./build/ipxe.pxe");
     opts.optopt(
         "k",
@@ -59,11 +68,39 @@ pub fn create_options() -> (Options, Vec<&'static str>) {
         "Path to kernel image serve",
         "./build/vmlinuz",
     );
-    opts.optopt("", "ip", "Ip address of interface", "192.168.178.25/24");
+    opts.optopt(
+        "",
+        "ip",
+        "Ip address of interface, required with --tap",
+        "192.168.178.25/24",
+    );
     opts.optflag("", "raw", "Interface to use");
     opts.optflag("", "tun", "TUN interface to use");
     opts.optflag("", "tap", "TAP interface to use");
     opts.optopt("", "mac", "MAC address of interface", "98:fa:9b:4b:b2:c4");
+    opts.optmulti(
+        "",
+        "dhcp-option",
+        "Extra DHCP option to send in PXE offers and acks. Type is one of ip, string, u32, hex. Can be restricted to hosts with @mac=<mac>, @arch=<number>, @firmware=<name>, @circuit-id=<id> or @remote-id=<id>",
+        "66:string:tftp.lan[@mac=52:54:00:12:34:56]",
+    );
+    opts.optmulti(
+        "",
+        "vlan",
+        "802.1Q VLAN to serve on a trunk port. Can be given multiple times, append :<ip>/<prefix>, :ipxe=<path> or :kernel=<path> to serve a VLAN with its own address or images",
+        "100[:10.0.100.1/24][:ipxe=<path>][:kernel=<path>]",
+    );
+    opts.optflag(
+        "",
+        "back-off",
+        "Do not answer DHCP discovers while another PXE server is active on the network",
+    );
+    opts.optopt(
+        "",
+        "record",
+        "Write every received and sent frame to a pcapng file, for example to turn a failed boot into a regression test",
+        "session.pcapng",
+    );
     opts.optopt(
         "l",
         "level",
@@ -73,6 +110,56 @@ pub fn create_options() -> (Options, Vec<&'static str>) {
     (opts, Vec::new())
 }
 
+/// Options of the `replay` subcommand
+pub fn create_replay_options() -> (Options, Vec<&'static str>) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt(
+        "",
+        "server-mac",
+        "MAC address of the server in the capture",
+        "98:fa:9b:4b:b2:c4",
+    );
+    opts.optopt(
+        "",
+        "server-ip",
+        "Ip address of the server in the capture",
+        "192.168.178.97",
+    );
+    opts.optopt("", "ipxe", "Path to custom ipxe image", "./build/ipxe.pxe");
+    opts.optopt(
+        "k",
+        "kernel",
+        "Path to kernel image serve",
+        "./build/vmlinuz",
+    );
+    opts.optmulti(
+        "",
+        "dhcp-option",
+        "Extra DHCP option the captured server sent",
+        "66:string:tftp.lan[@mac=52:54:00:12:34:56]",
+    );
+    opts.optopt("", "vlan", "802.1Q VLAN the capture was tagged with", "100");
+    opts.optflag(
+        "",
+        "ignore-volatile",
+        "Do not compare the ipv4 ident, checksums and DHCP secs, which change between runs",
+    );
+    opts.optopt(
+        "o",
+        "out",
+        "Directory to write got.pcapng and wanted.pcapng to",
+        "./target",
+    );
+    opts.optopt(
+        "l",
+        "level",
+        "debug level",
+        "[OFF, ERROR, WARN, INFO, DEBUG, TRACE]",
+    );
+    (opts, vec!["replay", "<capture.pcapng>"])
+}
+
 pub fn parse_options(options: &Options, free: Vec<&str>) -> Matches {
     match options.parse(env::args().skip(1)) {
         Err(err) => {
@@ -97,3 +184,83 @@ pub fn parse_options(options: &Options, free: Vec<&str>) -> Matches {
         }
     }
 }
+
+/// Settings of one `--interface`. Unset settings fall back to the global options.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    pub name: String,
+    pub ip: Option<Ipv4Cidr>,
+    pub mac: Option<EthernetAddress>,
+    pub ipxe: Option<PathBuf>,
+    pub kernel: Option<PathBuf>,
+    pub vlans: Vec<VlanConfig>,
+    pub back_off: bool,
+}
+
+impl InterfaceConfig {
+    /// Settings to serve `vlan` with, its own address and images override ours
+    pub fn vlan_config(&self, vlan: &VlanConfig) -> InterfaceConfig {
+        InterfaceConfig {
+            ip: vlan.ip.or(self.ip),
+            ipxe: vlan.ipxe.clone().or_else(|| self.ipxe.clone()),
+            kernel: vlan.kernel.clone().or_else(|| self.kernel.clone()),
+            vlans: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Interfaces to open raw sockets on. The kernel removes 802.1Q tags before
+    /// raw sockets see a frame, so every VLAN is served untagged on its
+    /// `<name>.<id>` subinterface.
+    pub fn raw_interfaces(&self) -> Vec<InterfaceConfig> {
+        if self.vlans.is_empty() {
+            return vec![self.clone()];
+        }
+        self.vlans
+            .iter()
+            .map(|vlan| InterfaceConfig {
+                name: format!("{}.{}", self.name, vlan.id),
+                ..self.vlan_config(vlan)
+            })
+            .collect()
+    }
+}
+
+impl FromStr for InterfaceConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = s.split(',');
+        let mut config = InterfaceConfig {
+            name: settings.next().unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+        if config.name.is_empty() {
+            return Err(format!("Missing interface name in {}", s));
+        }
+
+        for setting in settings {
+            match setting.split_once('=') {
+                Some(("ip", ip)) => {
+                    let ip =
+                        Ipv4Cidr::from_str(ip).map_err(|_| format!("Invalid ipv4 cidr: {}", ip))?;
+                    config.ip = Some(ip);
+                }
+                Some(("mac", mac)) => {
+                    let mac = EthernetAddress::from_str(mac)
+                        .map_err(|_| format!("Invalid mac address: {}", mac))?;
+                    config.mac = Some(mac);
+                }
+                Some(("ipxe", path)) => config.ipxe = Some(PathBuf::from(path)),
+                Some(("kernel", path)) => config.kernel = Some(PathBuf::from(path)),
+                Some(("vlan", vlan)) => {
+                    let vlan = VlanConfig::from_str(vlan).map_err(|e| e.to_string())?;
+                    config.vlans.push(vlan);
+                }
+                None if setting == "back-off" => config.back_off = true,
+                _ => return Err(format!("Unknown interface setting: {}", setting)),
+            }
+        }
+        Ok(config)
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(unused_imports)]
 
+use crate::dhcp::extra_options::ExtraDhcpOption;
 use crate::dhcp::options::*;
 use crate::dhcp::parse::PxeClientInfo;
 use crate::prelude::*;
@@ -55,19 +56,37 @@ pub struct DhcpReprWrapper {
     pub repr: DhcpRepr<'this>,
 }
 
-pub fn pxe_ack(info: &PxeClientInfo, server_ip: Ipv4Address, boot_file: &str) -> DhcpReprWrapper {
+/// Fields of a PXE reply that differ between offer and ack
+struct PxeReply {
+    message_type: DhcpMessageType,
+    client_ip: Ipv4Address,
+    broadcast: bool,
+}
+
+fn pxe_reply(
+    info: &PxeClientInfo,
+    reply: PxeReply,
+    server_ip: Ipv4Address,
+    boot_file: &str,
+    mut options: Vec<DhcpOptionWrapper>,
+) -> DhcpReprWrapper {
     const IP_NULL: Ipv4Address = Ipv4Address([0, 0, 0, 0]);
 
+    // RFC 3046: The server copies the relay agent information option into the reply,
+    // it has to be the last option before the end option
+    if let Some(relay_agent_info) = &info.relay_agent_info {
+        options.push(relay_agent_info.clone().into());
+    }
+
+    // pxe_discover only keeps ethernet client identifiers, anything else is broadcast
     let client_addr = match info.client_identifier.hardware_type {
         HardwareType::Ethernet => {
             let mac = &info.client_identifier.hardware_address;
             EthernetAddress::from_bytes(mac)
         }
-        t => panic!("Unsupported hardware type: {:#?}", t),
+        HardwareType::DomainName => EthernetAddress::BROADCAST,
     };
 
-    let options = vec![];
-
     DhcpReprWrapperBuilder {
         mdata: options,
         boot_file: boot_file.to_owned(),
@@ -79,15 +98,15 @@ pub fn pxe_ack(info: &PxeClientInfo, server_ip: Ipv4Address, boot_file: &str) ->
             DhcpRepr {
                 sname: None,
                 boot_file: Some(boot_file),
-                message_type: DhcpMessageType::Ack,
+                message_type: reply.message_type,
                 transaction_id: info.transaction_id,
                 client_hardware_address: client_addr,
                 secs: info.secs,
-                client_ip: IP_NULL,
+                client_ip: reply.client_ip,
                 your_ip: IP_NULL,
                 server_ip,
-                broadcast: false,
-                relay_agent_ip: IP_NULL,
+                broadcast: reply.broadcast,
+                relay_agent_ip: info.relay_agent_ip,
 
                 // unimportant
                 router: None,
@@ -108,21 +127,62 @@ pub fn pxe_ack(info: &PxeClientInfo, server_ip: Ipv4Address, boot_file: &str) ->
     .build()
 }
 
-pub fn pxe_offer(
+fn matching_extra_options<'a>(
+    info: &'a PxeClientInfo,
+    extra_options: &'a [ExtraDhcpOption],
+) -> impl Iterator<Item = DhcpOptionWrapper> + 'a {
+    extra_options
+        .iter()
+        .filter(|opt| opt.selector.matches(info))
+        .map(DhcpOptionWrapper::from)
+}
+
+pub fn pxe_ack(
     info: &PxeClientInfo,
-    server_ip: &Ipv4Address,
+    server_ip: Ipv4Address,
     boot_file: &str,
+    extra_options: &[ExtraDhcpOption],
 ) -> DhcpReprWrapper {
-    const IP_NULL: Ipv4Address = Ipv4Address([0, 0, 0, 0]);
+    let options: Vec<DhcpOptionWrapper> = matching_extra_options(info, extra_options).collect();
 
-    let client_addr = match info.client_identifier.hardware_type {
-        HardwareType::Ethernet => {
-            let mac = &info.client_identifier.hardware_address;
-            EthernetAddress::from_bytes(mac)
-        }
-        t => panic!("Unsupported hardware type: {:#?}", t),
+    let reply = PxeReply {
+        message_type: DhcpMessageType::Ack,
+        client_ip: Ipv4Address::UNSPECIFIED,
+        broadcast: false,
     };
+    pxe_reply(info, reply, server_ip, boot_file, options)
+}
+
+/// Answers a DHCPINFORM of a client that already has an address.
+/// Like a proxyDHCP offer it carries the PXE vendor class and the boot file.
+pub fn pxe_inform_ack(
+    info: &PxeClientInfo,
+    server_ip: Ipv4Address,
+    client_ip: Ipv4Address,
+    boot_file: &str,
+    extra_options: &[ExtraDhcpOption],
+) -> DhcpReprWrapper {
+    let vendor_id = VendorClassIdentifier::try_from("PXEClient".as_bytes()).unwrap();
+    let server_id = PxeServerIdentifier::try_from(server_ip.as_bytes()).unwrap();
+
+    let mut options: Vec<DhcpOptionWrapper> = vec![server_id.into(), vendor_id.into()];
+    options.extend(matching_extra_options(info, extra_options));
+
+    // RFC 2131: The server MUST NOT send a lease expiration time or fill in yiaddr
+    let reply = PxeReply {
+        message_type: DhcpMessageType::Ack,
+        client_ip,
+        broadcast: false,
+    };
+    pxe_reply(info, reply, server_ip, boot_file, options)
+}
 
+pub fn pxe_offer(
+    info: &PxeClientInfo,
+    server_ip: &Ipv4Address,
+    boot_file: &str,
+    extra_options: &[ExtraDhcpOption],
+) -> DhcpReprWrapper {
     let vendor_id = VendorClassIdentifier::try_from("PXEClient".as_bytes()).unwrap();
     let server_id = PxeServerIdentifier::try_from(server_ip.clone().as_bytes()).unwrap();
 
@@ -138,55 +198,68 @@ pub fn pxe_offer(
     //     vec![pxe_discover_control.into()]
     // };
 
-    let options: Vec<DhcpOptionWrapper> = vec![
-        info.client_identifier.clone().into(),
-        info.client_uuid.clone().into(),
+    let mut options: Vec<DhcpOptionWrapper> = vec![info.client_identifier.clone().into()];
+    if let Some(client_uuid) = &info.client_uuid {
+        options.push(client_uuid.clone().into());
+    }
+    options.extend([
         server_id.into(),
         vendor_id.into(),
         //     vendor_options.as_slice().into(),
-    ];
-
-    DhcpReprWrapperBuilder {
-        mdata: options,
-        boot_file: boot_file.to_owned(),
-        options_builder: |mdata: &Vec<DhcpOptionWrapper>| {
-            let options: Vec<DhcpOption> = mdata.iter().map(|x| x.into()).collect();
-            options
-        },
-        repr_builder: |options: &Vec<DhcpOption>, boot_file: &String| {
-            DhcpRepr {
-                sname: None,
-                boot_file: Some(boot_file),
-                message_type: DhcpMessageType::Offer,
-                transaction_id: info.transaction_id,
-                client_hardware_address: client_addr,
-                secs: info.secs,
-                client_ip: IP_NULL,
-                your_ip: IP_NULL,
-                server_ip: server_ip.to_owned(),
-                broadcast: true,
-                relay_agent_ip: IP_NULL,
+    ]);
+    options.extend(matching_extra_options(info, extra_options));
 
-                // unimportant
-                router: None,
-                subnet_mask: None,
-                requested_ip: None,
-                client_identifier: None,
-                server_identifier: None,
-                parameter_request_list: None,
-                dns_servers: None,
-                max_size: None,
-                lease_duration: None,
-                renew_duration: None,
-                rebind_duration: None,
-                additional_options: options,
-            }
-        },
-    }
-    .build()
+    let reply = PxeReply {
+        message_type: DhcpMessageType::Offer,
+        client_ip: Ipv4Address::UNSPECIFIED,
+        broadcast: true,
+    };
+    pxe_reply(info, reply, *server_ip, boot_file, options)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::dhcp::parse::FirmwareType;
+
+    #[test]
+    fn test_relay_agent_information_is_echoed() {
+        let relay_agent_info =
+            RelayAgentInformation::try_from([0x01, 0x03, b'p', b'1', b'2'].as_slice()).unwrap();
+        let info = PxeClientInfo {
+            client_arch: ClientArchType::X86Bios,
+            vendor_id: None,
+            vendor_class: None,
+            client_uuid: PxeUuid::try_from([0x00; 17].as_slice()).ok(),
+            msg_type: DhcpMessageType::Discover,
+            network_interface_version: Some(NetworkInterfaceVersion {
+                interface_type: NetworkInterfaceType::Undi,
+                major: 2,
+                minor: 1,
+            }),
+            client_identifier: ClientIdentifier {
+                hardware_type: HardwareType::Ethernet,
+                hardware_address: vec![0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            },
+            transaction_id: 0x1337,
+            secs: 0,
+            firmware_type: FirmwareType::Intel,
+            ipxe_features: None,
+            relay_agent_info: Some(relay_agent_info),
+            relay_agent_ip: Ipv4Address::new(10, 0, 1, 1),
+        };
+        let extra: Vec<ExtraDhcpOption> = vec!["66:string:tftp.lan".parse().unwrap()];
+        let server_ip = Ipv4Address::new(10, 0, 0, 1);
+
+        let offer = pxe_offer(&info, &server_ip, "ipxe.pxe", &extra);
+        let repr = offer.borrow_repr();
+        assert_eq!(repr.relay_agent_ip, Ipv4Address::new(10, 0, 1, 1));
+        let last = repr.additional_options.last().unwrap();
+        assert_eq!(last.kind, 82);
+        assert_eq!(last.data, [0x01, 0x03, b'p', b'1', b'2'].as_slice());
+
+        let ack = pxe_ack(&info, server_ip, "ipxe.pxe", &extra);
+        let last = ack.borrow_repr().additional_options.last().unwrap();
+        assert_eq!(last.kind, 82);
+    }
 }
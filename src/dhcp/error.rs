@@ -12,6 +12,8 @@ pub enum Error {
     IgnoreNoLog(String),
     #[error("Invalid Packet: {0}")]
     Malformed(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
     #[error(transparent)]
     TryFromSlice(#[from] std::array::TryFromSliceError),
     #[error("DHCP Protocol Finished")]
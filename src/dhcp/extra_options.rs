@@ -0,0 +1,388 @@
+//! User defined DHCP options appended to the PXE offer and ack.
+//!
+//! An option is written as `<code>:<type>:<value>[@<selector>]`, e.g.
+//! `66:string:tftp.lan`, `17:string:iscsi:10.0.0.1::::iqn.x@mac=52:54:00:12:34:56`
+//! `175:hex:b10501808610@arch=7` or `67:string:rack1.efi@circuit-id=eth1/0/12`.
+//! Relay agent ids are given as text or, prefixed with `0x`, as hex bytes.
+
+use std::str::FromStr;
+
+use smoltcp::wire::{DhcpOption, EthernetAddress, Ipv4Address};
+
+use super::error::*;
+use super::options::{ClientArchType, DhcpOptionWrapper, DhcpOptionWrapperBuilder, HardwareType};
+use super::parse::{FirmwareType, PxeClientInfo};
+
+/// Options that are managed by rs_pxe itself and may not be overridden.
+/// The replies already carry 54, 60, 61 and 97, a second copy would be ambiguous
+/// to the client.
+const RESERVED_OPTIONS: [u8; 8] = [0, 53, 54, 60, 61, 82, 97, 255];
+
+const SELECTOR_PREFIXES: [&str; 5] = ["mac=", "arch=", "firmware=", "circuit-id=", "remote-id="];
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ExtraOptionValue {
+    /// One or more IPv4 addresses separated by commas
+    Ip(Vec<Ipv4Address>),
+    String(String),
+    U32(u32),
+    Hex(Vec<u8>),
+}
+
+impl ExtraOptionValue {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ExtraOptionValue::Ip(ips) => ips.iter().flat_map(|ip| ip.0).collect(),
+            ExtraOptionValue::String(s) => s.as_bytes().to_vec(),
+            ExtraOptionValue::U32(v) => v.to_be_bytes().to_vec(),
+            ExtraOptionValue::Hex(data) => data.clone(),
+        }
+    }
+
+    fn parse(kind: &str, value: &str) -> Result<Self> {
+        let res = match kind {
+            "ip" => {
+                let ips = value
+                    .split(',')
+                    .map(|ip| {
+                        Ipv4Address::from_str(ip.trim())
+                            .map_err(|_| Error::Malformed(f!("Invalid ip address: {}", ip)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                ExtraOptionValue::Ip(ips)
+            }
+            "string" => ExtraOptionValue::String(value.to_string()),
+            "u32" => {
+                let v = value
+                    .parse::<u32>()
+                    .map_err(|e| Error::Malformed(f!("Invalid u32 value {}: {}", value, e)))?;
+                ExtraOptionValue::U32(v)
+            }
+            "hex" => {
+                let value = value.trim_start_matches("0x");
+                if value.len() % 2 != 0 {
+                    return Err(Error::Malformed(f!(
+                        "Hex value must have an even number of digits: {}",
+                        value
+                    )));
+                }
+                let data = (0..value.len())
+                    .step_by(2)
+                    .map(|i| {
+                        u8::from_str_radix(&value[i..i + 2], 16)
+                            .map_err(|e| Error::Malformed(f!("Invalid hex value {}: {}", value, e)))
+                    })
+                    .collect::<Result<Vec<u8>>>()?;
+                ExtraOptionValue::Hex(data)
+            }
+            t => {
+                return Err(Error::Malformed(f!(
+                    "Unknown option type {}. Expected one of ip, string, u32, hex",
+                    t
+                )))
+            }
+        };
+        Ok(res)
+    }
+}
+
+/// Decides which clients receive an extra option
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HostSelector {
+    Any,
+    Mac(EthernetAddress),
+    Arch(ClientArchType),
+    Firmware(FirmwareType),
+    /// Circuit id of the relay agent information option, usually the switch port
+    CircuitId(Vec<u8>),
+    /// Remote id of the relay agent information option, usually the switch itself
+    RemoteId(Vec<u8>),
+}
+
+impl HostSelector {
+    pub fn matches(&self, info: &PxeClientInfo) -> bool {
+        match self {
+            HostSelector::Any => true,
+            HostSelector::Mac(mac) => {
+                info.client_identifier.hardware_type == HardwareType::Ethernet
+                    && info.client_identifier.hardware_address == mac.as_bytes()
+            }
+            HostSelector::Arch(arch) => info.client_arch == *arch,
+            HostSelector::Firmware(firmware) => info.firmware_type == *firmware,
+            HostSelector::CircuitId(id) => info
+                .relay_agent_info
+                .as_ref()
+                .and_then(|r| r.circuit_id.as_ref())
+                .map_or(false, |c| c == id),
+            HostSelector::RemoteId(id) => info
+                .relay_agent_info
+                .as_ref()
+                .and_then(|r| r.remote_id.as_ref())
+                .map_or(false, |r| r == id),
+        }
+    }
+}
+
+impl FromStr for HostSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("mac", mac)) => EthernetAddress::from_str(mac)
+                .map(HostSelector::Mac)
+                .map_err(|_| Error::Malformed(f!("Invalid mac address: {}", mac))),
+            Some(("arch", arch)) => {
+                let arch = arch
+                    .parse::<u16>()
+                    .map_err(|e| Error::Malformed(f!("Invalid arch {}: {}", arch, e)))?;
+                let arch = ClientArchType::try_from(arch.to_be_bytes().as_slice())?;
+                Ok(HostSelector::Arch(arch))
+            }
+            Some(("firmware", name)) => {
+                let firmware: FirmwareType = name.parse()?;
+                if !firmware.is_served() {
+                    return Err(Error::Config(f!(
+                        "Firmware {} is not served, only intel and ipxe clients are answered",
+                        name
+                    )));
+                }
+                Ok(HostSelector::Firmware(firmware))
+            }
+            Some(("circuit-id", id)) => Ok(HostSelector::CircuitId(parse_relay_agent_id(id)?)),
+            Some(("remote-id", id)) => Ok(HostSelector::RemoteId(parse_relay_agent_id(id)?)),
+            _ => Err(Error::Malformed(f!(
+                "Unknown host selector {}. Expected mac=<mac>, arch=<number>, firmware=<name>, circuit-id=<id> or remote-id=<id>",
+                s
+            ))),
+        }
+    }
+}
+
+fn parse_relay_agent_id(id: &str) -> Result<Vec<u8>> {
+    if id.starts_with("0x") {
+        Ok(ExtraOptionValue::parse("hex", id)?.to_bytes())
+    } else {
+        Ok(id.as_bytes().to_vec())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExtraDhcpOption {
+    pub kind: u8,
+    pub value: ExtraOptionValue,
+    pub selector: HostSelector,
+}
+
+impl FromStr for ExtraDhcpOption {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Only treat the part after the last '@' as selector if it looks like one,
+        // string values may contain an '@' themselves
+        let (option, selector) = match s.rsplit_once('@') {
+            Some((option, selector))
+                if SELECTOR_PREFIXES.iter().any(|p| selector.starts_with(p)) =>
+            {
+                (option, HostSelector::from_str(selector)?)
+            }
+            _ => (s, HostSelector::Any),
+        };
+
+        let mut parts = option.splitn(3, ':');
+        let (kind, value_type, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(value_type), Some(value)) => (kind, value_type, value),
+            _ => {
+                return Err(Error::Malformed(f!(
+                    "Invalid dhcp option {}. Expected <code>:<type>:<value>",
+                    s
+                )))
+            }
+        };
+
+        let kind = kind
+            .parse::<u8>()
+            .map_err(|e| Error::Malformed(f!("Invalid option code {}: {}", kind, e)))?;
+        if RESERVED_OPTIONS.contains(&kind) {
+            return Err(Error::Malformed(f!(
+                "Option {} is managed by rs_pxe and can not be set",
+                kind
+            )));
+        }
+
+        let value = ExtraOptionValue::parse(value_type, value)?;
+        if value.to_bytes().len() > u8::MAX as usize {
+            return Err(Error::Malformed(f!(
+                "Value of option {} is longer than 255 bytes",
+                kind
+            )));
+        }
+
+        Ok(ExtraDhcpOption {
+            kind,
+            value,
+            selector,
+        })
+    }
+}
+
+impl From<&ExtraDhcpOption> for DhcpOptionWrapper {
+    fn from(val: &ExtraDhcpOption) -> Self {
+        let kind = val.kind;
+        DhcpOptionWrapperBuilder {
+            mdata: val.value.to_bytes(),
+            option_builder: |data| DhcpOption { kind, data },
+        }
+        .build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dhcp::options::*;
+    use smoltcp::wire::DhcpMessageType;
+
+    fn client_info() -> PxeClientInfo {
+        PxeClientInfo {
+            client_arch: ClientArchType::X64Uefi,
+            vendor_id: None,
+            vendor_class: None,
+            client_uuid: PxeUuid::try_from([0x00; 17].as_slice()).ok(),
+            msg_type: DhcpMessageType::Discover,
+            network_interface_version: Some(NetworkInterfaceVersion {
+                interface_type: NetworkInterfaceType::Undi,
+                major: 3,
+                minor: 16,
+            }),
+            client_identifier: ClientIdentifier {
+                hardware_type: HardwareType::Ethernet,
+                hardware_address: vec![0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            },
+            transaction_id: 0x1337,
+            secs: 0,
+            firmware_type: FirmwareType::Intel,
+            ipxe_features: None,
+            relay_agent_info: None,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+        }
+    }
+
+    #[test]
+    fn test_parse_values() {
+        let opt = ExtraDhcpOption::from_str("66:string:tftp.lan").unwrap();
+        assert_eq!(opt.kind, 66);
+        assert_eq!(opt.value.to_bytes(), b"tftp.lan");
+        assert_eq!(opt.selector, HostSelector::Any);
+
+        let opt = ExtraDhcpOption::from_str("6:ip:10.0.0.1,10.0.0.2").unwrap();
+        assert_eq!(opt.value.to_bytes(), vec![10, 0, 0, 1, 10, 0, 0, 2]);
+
+        let opt = ExtraDhcpOption::from_str("211:u32:300").unwrap();
+        assert_eq!(opt.value.to_bytes(), vec![0, 0, 1, 44]);
+
+        let opt = ExtraDhcpOption::from_str("175:hex:0xb10501").unwrap();
+        assert_eq!(opt.value.to_bytes(), vec![0xb1, 0x05, 0x01]);
+
+        // The replies carry no vendor options of their own
+        let opt = ExtraDhcpOption::from_str("43:hex:060108").unwrap();
+        assert_eq!(opt.value.to_bytes(), vec![0x06, 0x01, 0x08]);
+
+        // Colons and '@' inside of string values are kept
+        let opt = ExtraDhcpOption::from_str("17:string:iscsi:user@10.0.0.1::::iqn").unwrap();
+        assert_eq!(opt.value.to_bytes(), b"iscsi:user@10.0.0.1::::iqn");
+        assert_eq!(opt.selector, HostSelector::Any);
+    }
+
+    #[test]
+    fn test_parse_selectors() {
+        let opt = ExtraDhcpOption::from_str("67:string:ipxe.efi@arch=7").unwrap();
+        assert_eq!(opt.selector, HostSelector::Arch(ClientArchType::X64Uefi));
+
+        let opt = ExtraDhcpOption::from_str("67:string:ipxe.efi@mac=52:54:00:12:34:56").unwrap();
+        assert_eq!(
+            opt.selector,
+            HostSelector::Mac(EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]))
+        );
+
+        let info = client_info();
+        assert!(opt.selector.matches(&info));
+        assert!(HostSelector::Arch(ClientArchType::X64Uefi).matches(&info));
+        assert!(!HostSelector::Arch(ClientArchType::X86Bios).matches(&info));
+        assert!(!HostSelector::Mac(EthernetAddress([0; 6])).matches(&info));
+
+        let opt = ExtraDhcpOption::from_str("67:string:ipxe.efi@firmware=intel").unwrap();
+        assert_eq!(opt.selector, HostSelector::Firmware(FirmwareType::Intel));
+        assert!(opt.selector.matches(&info));
+        assert!(!HostSelector::Firmware(FirmwareType::IPxe).matches(&info));
+
+        // Detected, but never answered
+        assert!(matches!(
+            ExtraDhcpOption::from_str("67:string:boot.scr@firmware=u-boot"),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_relay_agent_selectors() {
+        let opt = ExtraDhcpOption::from_str("67:string:rack1.efi@circuit-id=eth1/0/12").unwrap();
+        assert_eq!(opt.selector, HostSelector::CircuitId(b"eth1/0/12".to_vec()));
+
+        let opt = ExtraDhcpOption::from_str("67:string:rack1.efi@remote-id=0x001122").unwrap();
+        assert_eq!(opt.selector, HostSelector::RemoteId(vec![0x00, 0x11, 0x22]));
+
+        let mut info = client_info();
+        assert!(!opt.selector.matches(&info));
+
+        info.relay_agent_info = Some(RelayAgentInformation {
+            circuit_id: Some(b"eth1/0/12".to_vec()),
+            remote_id: Some(vec![0x00, 0x11, 0x22]),
+            data: vec![],
+        });
+        assert!(opt.selector.matches(&info));
+        assert!(HostSelector::CircuitId(b"eth1/0/12".to_vec()).matches(&info));
+        assert!(!HostSelector::CircuitId(b"eth1/0/13".to_vec()).matches(&info));
+
+        assert!(ExtraDhcpOption::from_str("82:hex:0102").is_err());
+        assert!(ExtraDhcpOption::from_str("66:string:foo@remote-id=0xabc").is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(ExtraDhcpOption::from_str("66:string").is_err());
+        assert!(ExtraDhcpOption::from_str("300:string:foo").is_err());
+        assert!(ExtraDhcpOption::from_str("53:u32:1").is_err());
+        assert!(ExtraDhcpOption::from_str("60:string:PXEClient").is_err());
+        assert!(ExtraDhcpOption::from_str("54:ip:10.0.0.1").is_err());
+        assert!(ExtraDhcpOption::from_str("97:hex:00").is_err());
+        assert!(ExtraDhcpOption::from_str("66:float:1.0").is_err());
+        assert!(ExtraDhcpOption::from_str("3:ip:10.0.0").is_err());
+        assert!(ExtraDhcpOption::from_str("66:string:foo@arch=9999").is_err());
+    }
+
+    #[test]
+    fn test_offer_contains_extra_options() {
+        let info = client_info();
+        let extra = vec![
+            ExtraDhcpOption::from_str("66:string:tftp.lan").unwrap(),
+            ExtraDhcpOption::from_str("67:string:bios.pxe@arch=0").unwrap(),
+            ExtraDhcpOption::from_str("67:string:uefi.efi@arch=7").unwrap(),
+        ];
+        let server_ip = Ipv4Address::new(10, 0, 0, 1);
+
+        let offer = crate::dhcp::construct::pxe_offer(&info, &server_ip, "ipxe.efi", &extra);
+        let options = offer.borrow_repr().additional_options;
+        assert!(options
+            .iter()
+            .any(|opt| opt.kind == 66 && opt.data == b"tftp.lan"));
+        assert!(options
+            .iter()
+            .any(|opt| opt.kind == 67 && opt.data == b"uefi.efi"));
+        assert!(!options.iter().any(|opt| opt.data == b"bios.pxe"));
+
+        let ack = crate::dhcp::construct::pxe_ack(&info, server_ip, "ipxe.efi", &extra);
+        let options = ack.borrow_repr().additional_options;
+        assert!(options
+            .iter()
+            .any(|opt| opt.kind == 66 && opt.data == b"tftp.lan"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Clients answered by the sockets of all interfaces.
+//!
+//! When several interfaces or VLANs see the same broadcast domain, every socket
+//! would answer the same discover and the client would boot from whichever offer
+//! arrived first. The first socket that answers a client claims it, the others
+//! leave the client alone until it releases the claim or stays silent for
+//! `LEASE_TIMEOUT`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use log::*;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol,
+    Ipv4Address, Ipv4Packet, UdpPacket,
+};
+
+use super::utils;
+
+/// How long a claim holds after the last reply to the client
+pub const LEASE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound of remembered clients, anyone on the network can send discovers
+/// with made up hardware addresses
+const MAX_LEASES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    /// Address of the socket serving the client
+    pub server_ip: Ipv4Address,
+    pub vlan: Option<u16>,
+    pub expires_at: Instant,
+}
+
+/// Clients and the socket serving them. Clones share the table, give every socket
+/// a clone of the same one.
+#[derive(Debug, Clone, Default)]
+pub struct Leases {
+    leases: Rc<RefCell<HashMap<EthernetAddress, Lease>>>,
+}
+
+impl Leases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the unexpired lease of `client`
+    pub fn get(&self, client: &EthernetAddress, now: Instant) -> Option<Lease> {
+        self.leases
+            .borrow()
+            .get(client)
+            .filter(|lease| lease.expires_at > now)
+            .copied()
+    }
+
+    /// Claims `client` for the socket with `server_ip` on `vlan`, or renews the claim
+    pub fn claim(
+        &self,
+        client: EthernetAddress,
+        server_ip: Ipv4Address,
+        vlan: Option<u16>,
+        now: Instant,
+    ) {
+        let mut leases = self.leases.borrow_mut();
+        if leases.len() >= MAX_LEASES && !leases.contains_key(&client) {
+            leases.retain(|_, lease| lease.expires_at > now);
+        }
+        if leases.len() >= MAX_LEASES && !leases.contains_key(&client) {
+            let oldest = leases
+                .iter()
+                .min_by_key(|(_, lease)| lease.expires_at)
+                .map(|(mac, _)| *mac);
+            if let Some(oldest) = oldest {
+                leases.remove(&oldest);
+            }
+        }
+
+        let lease = Lease {
+            server_ip,
+            vlan,
+            expires_at: now + LEASE_TIMEOUT,
+        };
+        if leases.insert(client, lease).is_none() {
+            debug!("Client {} is served by {}", client, server_ip);
+        }
+    }
+
+    /// Drops the lease of `client`, any socket may answer it again
+    pub fn release(&self, client: &EthernetAddress) {
+        if self.leases.borrow_mut().remove(client).is_some() {
+            debug!("Client {} released its lease", client);
+        }
+    }
+
+    /// Number of remembered clients, including expired ones
+    pub fn len(&self) -> usize {
+        self.leases.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leases.borrow().is_empty()
+    }
+}
+
+/// Returns the client hardware address and message type of a DHCP client frame
+/// to the server or proxyDHCP port. Everything else returns `None`.
+pub fn dhcp_client(rx_buffer: &[u8]) -> Option<(EthernetAddress, DhcpMessageType)> {
+    let ether = EthernetFrame::new_checked(rx_buffer).ok()?;
+    if ether.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).ok()?;
+    if ipv4.next_header() != IpProtocol::Udp {
+        return None;
+    }
+    let udp = UdpPacket::new_checked(ipv4.payload()).ok()?;
+    if !matches!(udp.dst_port(), 67 | 4011) {
+        return None;
+    }
+    let dhcp = DhcpPacket::new_checked(udp.payload()).ok()?;
+    let msg_type = utils::dhcp_message_type(&dhcp).ok()?;
+    Some((dhcp.client_hardware_address(), msg_type))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::read_frames;
+    use std::path::Path;
+
+    const CLIENT: EthernetAddress = EthernetAddress([0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe]);
+    const SERVER_IP: Ipv4Address = Ipv4Address([192, 168, 178, 97]);
+
+    #[test]
+    fn test_leases() {
+        let leases = Leases::new();
+        let shared = leases.clone();
+        let now = Instant::from_secs(10);
+
+        assert_eq!(leases.get(&CLIENT, now), None);
+        leases.claim(CLIENT, SERVER_IP, Some(100), now);
+        let lease = shared.get(&CLIENT, now).unwrap();
+        assert_eq!(lease.server_ip, SERVER_IP);
+        assert_eq!(lease.vlan, Some(100));
+
+        // Expired leases are not returned
+        assert_eq!(shared.get(&CLIENT, now + LEASE_TIMEOUT), None);
+
+        shared.release(&CLIENT);
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn test_leases_size() {
+        let leases = Leases::new();
+        let now = Instant::from_secs(0);
+        for i in 0..MAX_LEASES + 16 {
+            let mut mac = [0x02, 0, 0, 0, 0, 0];
+            mac[4..].copy_from_slice(&(i as u16).to_be_bytes());
+            leases.claim(
+                EthernetAddress(mac),
+                SERVER_IP,
+                None,
+                now + Duration::from_millis(i as u64),
+            );
+        }
+        assert_eq!(leases.len(), MAX_LEASES);
+        // The oldest clients were dropped
+        let first = EthernetAddress([0x02, 0, 0, 0, 0, 0]);
+        assert_eq!(leases.get(&first, now), None);
+    }
+
+    #[test]
+    fn test_dhcp_client() {
+        let frames = read_frames(Path::new("./assets/ipxe_dhcp.pcapng"));
+        let (mac, msg_type) = dhcp_client(&frames[0]).unwrap();
+        assert_eq!(mac, CLIENT);
+        assert_eq!(msg_type, DhcpMessageType::Discover);
+    }
+}
@@ -1,5 +1,8 @@
 pub mod construct;
 pub mod error;
+pub mod extra_options;
+pub mod lease;
+pub mod monitor;
 pub mod options;
 pub mod parse;
 pub mod socket;
@@ -0,0 +1,182 @@
+//! Watches the DHCP traffic of other servers on the network.
+//!
+//! On shared networks another PXE server (dnsmasq, WDS, ...) may answer the same
+//! clients. The monitor records every foreign DHCP server that sends an offer or ack,
+//! warns about competing proxyDHCP responders and logs which server a client picked.
+
+use std::collections::HashMap;
+
+use log::*;
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol,
+    Ipv4Address, Ipv4Packet, UdpPacket,
+};
+
+use super::options::SubsetDhcpOption;
+use super::utils;
+
+/// How long a foreign PXE server counts as active after its last reply
+pub const PXE_SERVER_ACTIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound of remembered servers, anyone on the network can send offers
+/// with made up server identifiers
+const MAX_SERVERS: usize = 32;
+
+/// Offset of the 'file' field in the BOOTP header
+const BOOT_FILE_OFFSET: usize = 108;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignServer {
+    pub ip: Ipv4Address,
+    pub mac: EthernetAddress,
+    /// The server sent PXE boot information (vendor class `PXEClient` or a boot file)
+    pub is_pxe: bool,
+    /// The server did not hand out an address, so it is a proxyDHCP server
+    pub is_proxy: bool,
+    pub last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct ServerMonitor {
+    servers: HashMap<Ipv4Address, ForeignServer>,
+}
+
+impl ServerMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Foreign DHCP servers seen recently. A server is forgotten once it has been
+    /// silent for `PXE_SERVER_ACTIVE_TIMEOUT`.
+    pub fn servers(&self) -> impl Iterator<Item = &ForeignServer> {
+        self.servers.values()
+    }
+
+    /// Returns a foreign PXE server that answered within the last `PXE_SERVER_ACTIVE_TIMEOUT`
+    pub fn active_pxe_server(&self, now: Instant) -> Option<&ForeignServer> {
+        self.servers
+            .values()
+            .filter(|s| s.is_pxe)
+            .find(|s| s.last_seen + PXE_SERVER_ACTIVE_TIMEOUT > now)
+    }
+
+    /// Inspects a received frame. Server replies are recorded, client requests
+    /// are checked for the server they selected. Everything else is ignored.
+    pub fn observe(&mut self, rx_buffer: &[u8], server_mac: &EthernetAddress, now: Instant) {
+        let ether = match EthernetFrame::new_checked(rx_buffer) {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+        if ether.src_addr() == *server_mac || ether.ethertype() != EthernetProtocol::Ipv4 {
+            return;
+        }
+        let ipv4 = match Ipv4Packet::new_checked(ether.payload()) {
+            Ok(i) if i.next_header() == IpProtocol::Udp => i,
+            _ => return,
+        };
+        let udp = match UdpPacket::new_checked(ipv4.payload()) {
+            Ok(u) => u,
+            Err(_) => return,
+        };
+        let dhcp = match (udp.src_port(), udp.dst_port()) {
+            (67, 68) | (68, 67) | (_, 4011) => match DhcpPacket::new_checked(udp.payload()) {
+                Ok(d) => d,
+                Err(_) => return,
+            },
+            _ => return,
+        };
+        let msg_type = match utils::dhcp_message_type(&dhcp) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+
+        let server_identifier = dhcp
+            .options()
+            .find(|opt| opt.kind == u8::from(SubsetDhcpOption::ServerIdentifier))
+            .filter(|opt| opt.data.len() == 4)
+            .map(|opt| Ipv4Address::from_bytes(opt.data));
+
+        match msg_type {
+            DhcpMessageType::Offer | DhcpMessageType::Ack => {
+                let ip = server_identifier.unwrap_or_else(|| ipv4.src_addr());
+                let has_boot_file = udp
+                    .payload()
+                    .get(BOOT_FILE_OFFSET)
+                    .map_or(false, |b| *b != 0);
+                self.record_server(ip, ether.src_addr(), &dhcp, msg_type, has_boot_file, now);
+            }
+            DhcpMessageType::Request => {
+                if let Some(ip) = server_identifier {
+                    info!(
+                        "Client {} selected DHCP server {}",
+                        dhcp.client_hardware_address(),
+                        ip
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn record_server(
+        &mut self,
+        ip: Ipv4Address,
+        mac: EthernetAddress,
+        dhcp: &DhcpPacket<&[u8]>,
+        msg_type: DhcpMessageType,
+        has_boot_file: bool,
+        now: Instant,
+    ) {
+        let is_pxe = has_boot_file
+            || dhcp.options().any(|opt| {
+                opt.kind == u8::from(SubsetDhcpOption::VendorClassIdentifier)
+                    && opt.data.starts_with(b"PXEClient")
+            })
+            || dhcp.options().any(|opt| opt.kind == 67);
+        let is_proxy = dhcp.your_ip().is_unspecified();
+
+        let server = ForeignServer {
+            ip,
+            mac,
+            is_pxe,
+            is_proxy,
+            last_seen: now,
+        };
+
+        self.servers
+            .retain(|_, s| s.last_seen + PXE_SERVER_ACTIVE_TIMEOUT > now);
+        if self.servers.len() >= MAX_SERVERS && !self.servers.contains_key(&ip) {
+            let oldest = self
+                .servers
+                .values()
+                .min_by_key(|s| s.last_seen)
+                .map(|s| s.ip);
+            if let Some(oldest) = oldest {
+                self.servers.remove(&oldest);
+            }
+        }
+
+        match self.servers.insert(ip, server) {
+            Some(_) => debug!(
+                "DHCP {:?} from known server {} for client {}",
+                msg_type,
+                ip,
+                dhcp.client_hardware_address()
+            ),
+            None if is_pxe && is_proxy => warn!(
+                "Competing proxyDHCP server {} ({}) answered client {}",
+                ip,
+                mac,
+                dhcp.client_hardware_address()
+            ),
+            None if is_pxe => warn!(
+                "Competing PXE capable DHCP server {} ({}) answered client {}",
+                ip,
+                mac,
+                dhcp.client_hardware_address()
+            ),
+            None => info!("Detected DHCP server {} ({})", ip, mac),
+        }
+    }
+}
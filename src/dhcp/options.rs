@@ -62,6 +62,8 @@ pub enum SubsetDhcpOption {
     ServerIdentifier = 54,
     MaximumMessageSize = 57,
     UserClassInformation = 77,
+    RelayAgentInformation = 82,
+    IpxeEncapsulated = 175,
     End = 255,
 }
 
@@ -86,6 +88,8 @@ impl TryFrom<u8> for SubsetDhcpOption {
             54 => Ok(SubsetDhcpOption::ServerIdentifier),
             57 => Ok(SubsetDhcpOption::MaximumMessageSize),
             77 => Ok(SubsetDhcpOption::UserClassInformation),
+            82 => Ok(SubsetDhcpOption::RelayAgentInformation),
+            175 => Ok(SubsetDhcpOption::IpxeEncapsulated),
             255 => Ok(SubsetDhcpOption::End),
             e => Err(Error::UnknownDhcpValue(e.into())),
         }
@@ -334,8 +338,17 @@ impl TryFrom<&[u8]> for ClientIdentifier {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        let hardware_type = HardwareType::try_from(value[0])?;
-        let hardware_address = value[1..].to_vec();
+        let (hardware_type, hardware_address) = value
+            .split_first()
+            .ok_or_else(|| Error::Malformed("Empty client identifier".to_string()))?;
+        let hardware_type = HardwareType::try_from(*hardware_type)?;
+        if hardware_type == HardwareType::Ethernet && hardware_address.len() != 6 {
+            return Err(Error::Malformed(f!(
+                "Ethernet client identifier of {} bytes",
+                hardware_address.len()
+            )));
+        }
+        let hardware_address = hardware_address.to_vec();
         Ok(ClientIdentifier {
             hardware_type,
             hardware_address,
@@ -428,7 +441,7 @@ impl TryFrom<&[u8]> for PxeUuid {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self> {
-        if value[0] != 0 {
+        if value.first() != Some(&0) {
             return Err(Error::Malformed("Type of UUID must be 0".to_string()));
         }
 
@@ -484,6 +497,134 @@ impl From<VendorClassIdentifier> for DhcpOptionWrapper {
     }
 }
 
+/// Architecture and UNDI version announced in the vendor class,
+/// `PXEClient:Arch:xxxxx:UNDI:yyyzzz` or `HTTPClient:Arch:xxxxx:UNDI:yyyzzz`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PxeVendorClass {
+    /// The client asked for UEFI HTTP boot instead of PXE
+    pub http: bool,
+    pub arch: Option<ClientArchType>,
+    pub undi_major: Option<u8>,
+    pub undi_minor: Option<u8>,
+}
+
+impl VendorClassIdentifier {
+    /// Parses the vendor class of PXE and UEFI HTTP clients, returns `None` for other clients
+    pub fn pxe_class(&self) -> Option<PxeVendorClass> {
+        let mut fields = self.data.split(':');
+        let http = match fields.next() {
+            Some("PXEClient") => false,
+            Some("HTTPClient") => true,
+            _ => return None,
+        };
+
+        let mut class = PxeVendorClass {
+            http,
+            arch: None,
+            undi_major: None,
+            undi_minor: None,
+        };
+        while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+            match key {
+                "Arch" => {
+                    class.arch = value
+                        .parse::<u16>()
+                        .ok()
+                        .and_then(|a| ClientArchType::try_from(a.to_be_bytes().as_slice()).ok());
+                }
+                "UNDI" if value.len() == 6 && value.is_ascii() => {
+                    class.undi_major = value[..3].parse().ok();
+                    class.undi_minor = value[3..].parse().ok();
+                }
+                _ => (),
+            }
+        }
+        Some(class)
+    }
+}
+
+/// Feature indicators sent by iPXE in the encapsulated option 175
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IpxeFeature {
+    PxeExt = 16,
+    Iscsi = 17,
+    Aoe = 18,
+    Http = 19,
+    Https = 20,
+    Tftp = 21,
+    Ftp = 22,
+    Dns = 23,
+    BzImage = 24,
+    Multiboot = 25,
+    Slam = 26,
+    Srp = 27,
+    Nbi = 32,
+    Pxe = 33,
+    Elf = 34,
+    Comboot = 35,
+    Efi = 36,
+    Fcoe = 37,
+    Vlan = 38,
+    Menu = 39,
+    Sdi = 40,
+    Nfs = 41,
+}
+
+const IPXE_VERSION: u8 = 235;
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct IpxeFeatures {
+    /// Major, minor and patch version of iPXE
+    pub version: Option<(u8, u8, u8)>,
+    /// Codes of all enabled features
+    pub features: Vec<u8>,
+}
+
+impl IpxeFeatures {
+    pub fn has(&self, feature: IpxeFeature) -> bool {
+        self.features.contains(&(feature as u8))
+    }
+}
+
+impl TryFrom<&[u8]> for IpxeFeatures {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let mut res = IpxeFeatures::default();
+
+        let mut rest = value;
+        while !rest.is_empty() {
+            let (kind, len) = match rest {
+                [0, ..] => {
+                    rest = &rest[1..];
+                    continue;
+                }
+                [255, ..] => break,
+                [kind, len, ..] => (*kind, *len as usize),
+                _ => return Err(Error::Malformed("iPXE option is truncated".to_string())),
+            };
+            let data = rest
+                .get(2..2 + len)
+                .ok_or_else(|| Error::Malformed("iPXE option is truncated".to_string()))?;
+
+            match (kind, data) {
+                (IPXE_VERSION, [major, minor, patch]) => {
+                    res.version = Some((*major, *minor, *patch))
+                }
+                (IPXE_VERSION, _) => (),
+                // Feature indicators are a single byte, zero means disabled
+                (kind, [enabled]) if *enabled != 0 => res.features.push(kind),
+                _ => (),
+            }
+            rest = &rest[2 + len..];
+        }
+
+        Ok(res)
+    }
+}
+
 #[bitfield]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct PxeDiscoverControl {
@@ -547,3 +688,231 @@ impl From<&[VendorOption]> for DhcpOptionWrapper {
         .build()
     }
 }
+
+/// Relay Agent Information option (RFC 3046).
+/// Inserted by relay agents or DHCP snooping switches to identify the port a client is connected to.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RelayAgentInformation {
+    pub circuit_id: Option<Vec<u8>>,
+    pub remote_id: Option<Vec<u8>>,
+    /// Raw option data. Has to be echoed back unchanged in every reply.
+    pub data: Vec<u8>,
+}
+
+impl TryFrom<&[u8]> for RelayAgentInformation {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let mut circuit_id = None;
+        let mut remote_id = None;
+
+        let mut rest = value;
+        while !rest.is_empty() {
+            let (kind, len) = match rest {
+                [kind, len, ..] => (*kind, *len as usize),
+                _ => {
+                    return Err(Error::Malformed(
+                        "Relay agent sub-option is truncated".to_string(),
+                    ))
+                }
+            };
+            let data = rest.get(2..2 + len).ok_or_else(|| {
+                Error::Malformed("Relay agent sub-option is truncated".to_string())
+            })?;
+
+            match kind {
+                1 => circuit_id = Some(data.to_vec()),
+                2 => remote_id = Some(data.to_vec()),
+                _ => (),
+            }
+            rest = &rest[2 + len..];
+        }
+
+        Ok(RelayAgentInformation {
+            circuit_id,
+            remote_id,
+            data: value.to_vec(),
+        })
+    }
+}
+
+impl From<RelayAgentInformation> for DhcpOptionWrapper {
+    fn from(val: RelayAgentInformation) -> Self {
+        DhcpOptionWrapperBuilder {
+            mdata: val.data,
+            option_builder: |data| {
+                let kind = SubsetDhcpOption::RelayAgentInformation.into();
+                let data = data;
+                DhcpOption { kind, data }
+            },
+        }
+        .build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_relay_agent_information() {
+        // circuit-id "eth1/0/12", remote-id 00:11:22:33:44:55, unknown sub-option 9
+        let data = [
+            0x01, 0x09, b'e', b't', b'h', b'1', b'/', b'0', b'/', b'1', b'2', 0x02, 0x06, 0x00,
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x09, 0x01, 0xff,
+        ];
+        let info = RelayAgentInformation::try_from(data.as_slice()).unwrap();
+        assert_eq!(info.circuit_id.as_deref(), Some(b"eth1/0/12".as_slice()));
+        assert_eq!(
+            info.remote_id.as_deref(),
+            Some([0x00, 0x11, 0x22, 0x33, 0x44, 0x55].as_slice())
+        );
+
+        let wrapper: DhcpOptionWrapper = info.into();
+        let option: DhcpOption = (&wrapper).into();
+        assert_eq!(option.kind, 82);
+        assert_eq!(option.data, data.as_slice());
+
+        assert!(RelayAgentInformation::try_from([0x01, 0x05, 0x00].as_slice()).is_err());
+        assert!(RelayAgentInformation::try_from([0x01].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_pxe_vendor_class() {
+        let vendor_id = VendorClassIdentifier {
+            data: "PXEClient:Arch:00007:UNDI:003016".to_string(),
+        };
+        let class = vendor_id.pxe_class().unwrap();
+        assert!(!class.http);
+        assert_eq!(class.arch, Some(ClientArchType::X64Uefi));
+        assert_eq!(class.undi_major, Some(3));
+        assert_eq!(class.undi_minor, Some(16));
+
+        let vendor_id = VendorClassIdentifier {
+            data: "HTTPClient:Arch:00016:UNDI:003000".to_string(),
+        };
+        let class = vendor_id.pxe_class().unwrap();
+        assert!(class.http);
+        assert_eq!(class.arch, Some(ClientArchType::X64UefiHttp));
+
+        let vendor_id = VendorClassIdentifier {
+            data: "U-Boot.armv8".to_string(),
+        };
+        assert_eq!(vendor_id.pxe_class(), None);
+    }
+
+    #[test]
+    fn test_ipxe_features() {
+        // Bus id, version 1.0.0, HTTP, PXE, disabled EFI
+        let data = [
+            0xb1, 0x05, 0x01, 0x80, 0x86, 0x10, 0x0e, 0xeb, 0x03, 0x01, 0x00, 0x00, 0x13, 0x01,
+            0x01, 0x21, 0x01, 0x01, 0x24, 0x01, 0x00,
+        ];
+        let features = IpxeFeatures::try_from(data.as_slice()).unwrap();
+        assert_eq!(features.version, Some((1, 0, 0)));
+        assert!(features.has(IpxeFeature::Http));
+        assert!(features.has(IpxeFeature::Pxe));
+        assert!(!features.has(IpxeFeature::Efi));
+        assert!(!features.has(IpxeFeature::Https));
+
+        assert!(IpxeFeatures::try_from([0x13, 0x02, 0x01].as_slice()).is_err());
+    }
+
+    fn option_of(wrapper: &DhcpOptionWrapper) -> (u8, Vec<u8>) {
+        let option: DhcpOption = wrapper.into();
+        (option.kind, option.data.to_vec())
+    }
+
+    fn client_arch_type() -> impl Strategy<Value = ClientArchType> {
+        (0u16..=41).prop_map(|value| ClientArchType::try_from(&value.to_be_bytes()[..]).unwrap())
+    }
+
+    fn network_interface_version() -> impl Strategy<Value = NetworkInterfaceVersion> {
+        any::<(u8, u8)>().prop_map(|(major, minor)| NetworkInterfaceVersion {
+            interface_type: NetworkInterfaceType::Undi,
+            major,
+            minor,
+        })
+    }
+
+    fn client_identifier() -> impl Strategy<Value = ClientIdentifier> {
+        prop_oneof![
+            any::<[u8; 6]>().prop_map(|mac| ClientIdentifier {
+                hardware_type: HardwareType::Ethernet,
+                hardware_address: mac.to_vec(),
+            }),
+            prop::collection::vec(any::<u8>(), 0..64).prop_map(|name| ClientIdentifier {
+                hardware_type: HardwareType::DomainName,
+                hardware_address: name,
+            }),
+        ]
+    }
+
+    fn pxe_discover_control() -> impl Strategy<Value = PxeDiscoverControl> {
+        any::<[bool; 4]>().prop_map(|[broadcast, multicast, only_servers, direct]| {
+            PxeDiscoverControl::new()
+                .with_disable_broadcast(broadcast)
+                .with_disable_multicast(multicast)
+                .with_only_pxe_boot_servers(only_servers)
+                .with_direct_boot_file_download(direct)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_client_arch_type(arch in client_arch_type()) {
+            let (kind, data) = option_of(&arch.into());
+            prop_assert_eq!(kind, u8::from(SubsetDhcpOption::ClientSystemArchitecture));
+            prop_assert_eq!(data.clone(), u16::from(arch).to_be_bytes().to_vec());
+            prop_assert_eq!(ClientArchType::try_from(data.as_slice()).unwrap(), arch);
+        }
+
+        #[test]
+        fn prop_unknown_client_arch_type(value in 42u16..) {
+            prop_assert!(ClientArchType::try_from(&value.to_be_bytes()[..]).is_err());
+        }
+
+        #[test]
+        fn prop_network_interface_version(version in network_interface_version()) {
+            let (kind, data) = option_of(&version.into());
+            prop_assert_eq!(kind, u8::from(SubsetDhcpOption::ClientNetworkInterfaceIdentifier));
+            prop_assert_eq!(data.len(), 3);
+            prop_assert_eq!(NetworkInterfaceVersion::try_from(data.as_slice()).unwrap(), version);
+        }
+
+        #[test]
+        fn prop_client_identifier(id in client_identifier()) {
+            let (kind, data) = option_of(&id.clone().into());
+            prop_assert_eq!(kind, u8::from(SubsetDhcpOption::ClientIdentifier));
+            prop_assert_eq!(data.len(), 1 + id.hardware_address.len());
+            prop_assert_eq!(&data, &Vec::<u8>::from(id.clone()));
+            prop_assert_eq!(ClientIdentifier::try_from(data.as_slice()).unwrap(), id);
+        }
+
+        #[test]
+        fn prop_pxe_uuid(bytes in any::<[u8; 16]>()) {
+            let uuid = PxeUuid {
+                uuid: Uuid::from_bytes(bytes),
+            };
+            let (kind, data) = option_of(&uuid.clone().into());
+            prop_assert_eq!(kind, u8::from(SubsetDhcpOption::ClientUuid));
+            prop_assert_eq!(data.len(), 17);
+            prop_assert_eq!(PxeUuid::try_from(data.as_slice()).unwrap(), uuid);
+        }
+
+        #[test]
+        fn prop_pxe_discover_control(control in pxe_discover_control()) {
+            let vendor_option = VendorOption::from(control);
+            prop_assert_eq!(vendor_option.kind, 6);
+            prop_assert_eq!(vendor_option.data.len(), 1);
+            let parsed = PxeDiscoverControl::try_from(vendor_option.data.as_slice()).unwrap();
+            prop_assert_eq!(parsed, control);
+
+            // Kind, length, value and the end of the vendor options
+            let (kind, data) = option_of(&DhcpOptionWrapper::from(&[vendor_option][..]));
+            prop_assert_eq!(kind, u8::from(SubsetDhcpOption::VendorOptions));
+            prop_assert_eq!(data, vec![6, 1, control.into_bytes()[0], 255]);
+        }
+    }
+}
@@ -2,16 +2,62 @@ use log::*;
 
 use smoltcp::wire::DhcpMessageType;
 use smoltcp::wire::DhcpPacket;
+use smoltcp::wire::Ipv4Address;
 
 use super::error::*;
 use crate::dhcp::options::*;
 
 use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// MAC prefixes of the Raspberry Pi Foundation
+const RASPBERRY_PI_OUIS: [[u8; 3]; 6] = [
+    [0xb8, 0x27, 0xeb],
+    [0xdc, 0xa6, 0x32],
+    [0xe4, 0x5f, 0x01],
+    [0xd8, 0x3a, 0xdd],
+    [0x28, 0xcd, 0xc1],
+    [0x2c, 0xcf, 0x67],
+];
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FirmwareType {
+    /// PXE ROM following the Intel PXE specification
     Intel,
     IPxe,
+    UBoot,
+    Grub,
+    RaspberryPi,
+    Petitboot,
+    /// UEFI HTTP boot client
+    HttpClient,
+}
+
+impl FromStr for FirmwareType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "intel" | "pxe" => Ok(FirmwareType::Intel),
+            "ipxe" => Ok(FirmwareType::IPxe),
+            "u-boot" | "uboot" => Ok(FirmwareType::UBoot),
+            "grub" => Ok(FirmwareType::Grub),
+            "rpi" | "raspberrypi" => Ok(FirmwareType::RaspberryPi),
+            "petitboot" => Ok(FirmwareType::Petitboot),
+            "http" => Ok(FirmwareType::HttpClient),
+            _ => Err(Error::Malformed(f!(
+                "Unknown firmware {}. Expected one of intel, ipxe, u-boot, grub, rpi, petitboot, http",
+                s
+            ))),
+        }
+    }
+}
+
+impl FirmwareType {
+    /// Only these firmwares are answered, the others can not boot the served images
+    pub fn is_served(&self) -> bool {
+        matches!(self, FirmwareType::Intel | FirmwareType::IPxe)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -19,13 +65,21 @@ pub struct PxeClientInfo {
     pub client_arch: ClientArchType,
     /// Optionally identify the vendor type and configuration of a DHCP client
     pub vendor_id: Option<VendorClassIdentifier>,
-    pub client_uuid: PxeUuid,
+    /// Architecture and UNDI version parsed from the vendor class
+    pub vendor_class: Option<PxeVendorClass>,
+    pub client_uuid: Option<PxeUuid>,
     pub msg_type: DhcpMessageType,
-    pub network_interface_version: NetworkInterfaceVersion,
+    pub network_interface_version: Option<NetworkInterfaceVersion>,
     pub client_identifier: ClientIdentifier,
     pub transaction_id: u32,
     pub secs: u16,
     pub firmware_type: FirmwareType,
+    /// Features announced by iPXE in option 175
+    pub ipxe_features: Option<IpxeFeatures>,
+    /// Option 82 added by a relay agent or DHCP snooping switch
+    pub relay_agent_info: Option<RelayAgentInformation>,
+    /// The 'giaddr' field, set if the request was forwarded by a relay agent
+    pub relay_agent_ip: Ipv4Address,
 }
 
 pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
@@ -35,7 +89,9 @@ pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
     let mut network_interface_version: Option<NetworkInterfaceVersion> = None;
     let mut client_uuid: Option<PxeUuid> = None;
     let mut client_identifier: Option<ClientIdentifier> = None;
-    let mut firmware_type: FirmwareType = FirmwareType::Intel;
+    let mut user_class: Option<String> = None;
+    let mut ipxe_features: Option<IpxeFeatures> = None;
+    let mut relay_agent_info: Option<RelayAgentInformation> = None;
 
     if dhcp.opcode() != DhcpMessageType::Request.opcode() {
         return Err(Error::Ignore("Not a dhcp request".to_string()));
@@ -46,13 +102,15 @@ pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
             match opt_kind {
                 SubsetDhcpOption::MessageType => {
                     // Message Type
-                    let mtype = DhcpMessageType::try_from(option.data[0])
-                        .map_err(|e| Error::Malformed(f!("Invalid message type: {}", e)))
-                        .unwrap();
+                    let mtype = option
+                        .data
+                        .first()
+                        .map(|t| DhcpMessageType::from(*t))
+                        .ok_or_else(|| Error::Malformed("Empty message type".to_string()))?;
                     msg_type = Some(mtype);
                 }
                 SubsetDhcpOption::ClientSystemArchitecture => {
-                    let t = ClientArchType::try_from(option.data).unwrap();
+                    let t = ClientArchType::try_from(option.data)?;
                     client_arch = Some(t);
                 }
                 SubsetDhcpOption::ClientNetworkInterfaceIdentifier => {
@@ -63,18 +121,20 @@ pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
                     network_interface_version = Some(t);
                 }
                 SubsetDhcpOption::ClientUuid => {
-                    let t = PxeUuid::try_from(option.data).unwrap();
+                    let t = PxeUuid::try_from(option.data)?;
                     client_uuid = Some(t);
                 }
                 SubsetDhcpOption::VendorClassIdentifier => {
-                    let s = VendorClassIdentifier::try_from(option.data).unwrap();
+                    let s = VendorClassIdentifier::try_from(option.data)?;
                     vendor_id = Some(s);
                 }
                 SubsetDhcpOption::ClientIdentifier => {
                     let t = ClientIdentifier::try_from(option.data)
-                        .map_err(|e| Error::Malformed(f!("Invalid client identifier: {}", e)))
-                        .unwrap();
-                    client_identifier = Some(t);
+                        .map_err(|e| Error::Malformed(f!("Invalid client identifier: {}", e)))?;
+                    // Replies are addressed by MAC, so fall back to chaddr for other identifiers
+                    if t.hardware_type == HardwareType::Ethernet {
+                        client_identifier = Some(t);
+                    }
                 }
                 SubsetDhcpOption::ParameterRequestList | SubsetDhcpOption::MaximumMessageSize => {
                     // Ignore
@@ -82,18 +142,21 @@ pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
                 SubsetDhcpOption::UserClassInformation => {
                     // iPXE implements this options not adhering to the specification
                     // But we need it to detect iPXE clients
-
                     match core::str::from_utf8(option.data) {
-                        Ok(i) => {
-                            if i == "iPXE" {
-                                firmware_type = FirmwareType::IPxe;
-                            } else {
-                                warn!("Unknown firmware type: {}", i);
-                            }
-                        }
+                        Ok(i) => user_class = Some(i.to_string()),
                         Err(_) => warn!("UserClassInformation is not valid utf8"),
                     };
                 }
+                SubsetDhcpOption::IpxeEncapsulated => match IpxeFeatures::try_from(option.data) {
+                    Ok(t) => ipxe_features = Some(t),
+                    Err(e) => warn!("Invalid iPXE option: {}", e),
+                },
+                SubsetDhcpOption::RelayAgentInformation => {
+                    let t = RelayAgentInformation::try_from(option.data).map_err(|e| {
+                        Error::Malformed(f!("Invalid relay agent information: {}", e))
+                    })?;
+                    relay_agent_info = Some(t);
+                }
                 _ => {
                     warn!("Unhandled PXE option: {:?}", opt_kind)
                 }
@@ -110,21 +173,76 @@ pub fn pxe_discover(dhcp: DhcpPacket<&[u8]>) -> Result<PxeClientInfo> {
         client_identifier = Some(id);
     }
 
+    let vendor_class = vendor_id.as_ref().and_then(|v| v.pxe_class());
+    // Clients that are not PXE ROMs often only announce their architecture in the vendor class
+    let client_arch = client_arch
+        .or_else(|| vendor_class.and_then(|c| c.arch))
+        .ok_or(Error::MissingDhcpOption("Client Architecture"))?;
+    let client_identifier =
+        client_identifier.ok_or(Error::MissingDhcpOption("Client Identifier"))?;
+
+    let firmware_type = detect_firmware(
+        user_class.as_deref(),
+        vendor_id.as_ref(),
+        ipxe_features.is_some(),
+        client_arch,
+        &client_identifier,
+    );
+
     Ok(PxeClientInfo {
         firmware_type,
-        client_arch: client_arch.ok_or(Error::MissingDhcpOption("Client Architecture"))?,
+        client_arch,
         vendor_id,
-        client_identifier: client_identifier
-            .ok_or(Error::MissingDhcpOption("Client Identifier"))?,
-        client_uuid: client_uuid.ok_or(Error::MissingDhcpOption("Client UUID"))?,
+        vendor_class,
+        client_identifier,
+        client_uuid,
         msg_type: msg_type.ok_or(Error::MissingDhcpOption("Message Type"))?,
-        network_interface_version: network_interface_version
-            .ok_or(Error::MissingDhcpOption("Network Interface Version"))?,
+        network_interface_version,
         transaction_id: dhcp.transaction_id(),
         secs: dhcp.secs(),
+        ipxe_features,
+        relay_agent_info,
+        relay_agent_ip: dhcp.relay_agent_ip(),
     })
 }
 
+fn detect_firmware(
+    user_class: Option<&str>,
+    vendor_id: Option<&VendorClassIdentifier>,
+    has_ipxe_features: bool,
+    client_arch: ClientArchType,
+    client_identifier: &ClientIdentifier,
+) -> FirmwareType {
+    if user_class == Some("iPXE") || has_ipxe_features {
+        return FirmwareType::IPxe;
+    }
+    if let Some(user_class) = user_class {
+        warn!("Unknown firmware type: {}", user_class);
+    }
+
+    let vendor_id = vendor_id.map(|v| v.data.as_str()).unwrap_or_default();
+    if vendor_id.starts_with("U-Boot") {
+        FirmwareType::UBoot
+    } else if vendor_id.starts_with("GRUB") {
+        FirmwareType::Grub
+    } else if vendor_id.starts_with("petitboot") {
+        FirmwareType::Petitboot
+    } else if vendor_id.starts_with("HTTPClient") {
+        FirmwareType::HttpClient
+    } else if client_arch == ClientArchType::ArmRpiBoot
+        // The Raspberry Pi bootloader claims to be a x86 BIOS
+        || (client_arch == ClientArchType::X86Bios
+            && client_identifier.hardware_type == HardwareType::Ethernet
+            && RASPBERRY_PI_OUIS
+                .iter()
+                .any(|oui| client_identifier.hardware_address.starts_with(oui)))
+    {
+        FirmwareType::RaspberryPi
+    } else {
+        FirmwareType::Intel
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -185,8 +303,57 @@ mod test {
         assert_eq!(info.client_identifier.hardware_type, HardwareType::Ethernet);
         assert_eq!(
             info.client_uuid,
-            PxeUuid::try_from([0x00; 17].as_slice()).expect("Failed to create PxeUuid")
+            Some(PxeUuid::try_from([0x00; 17].as_slice()).expect("Failed to create PxeUuid"))
+        );
+
+        assert_eq!(info.firmware_type, FirmwareType::IPxe);
+        let class = info.vendor_class.unwrap();
+        assert_eq!(class.arch, Some(ClientArchType::X86Bios));
+        assert_eq!((class.undi_major, class.undi_minor), (Some(2), Some(1)));
+        let features = info.ipxe_features.unwrap();
+        assert_eq!(features.version, Some((1, 0, 0)));
+        assert!(features.has(IpxeFeature::Pxe));
+    }
+
+    #[test]
+    fn test_detect_firmware() {
+        fn detect(vendor_id: &str, arch: ClientArchType, mac: [u8; 6]) -> FirmwareType {
+            let vendor_id = VendorClassIdentifier {
+                data: vendor_id.to_string(),
+            };
+            let client_identifier = ClientIdentifier {
+                hardware_type: HardwareType::Ethernet,
+                hardware_address: mac.to_vec(),
+            };
+            detect_firmware(None, Some(&vendor_id), false, arch, &client_identifier)
+        }
+        let pxe = "PXEClient:Arch:00000:UNDI:002001";
+        let bios = ClientArchType::X86Bios;
+        let qemu = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let rpi = [0xdc, 0xa6, 0x32, 0x01, 0x02, 0x03];
+
+        assert_eq!(detect(pxe, bios, qemu), FirmwareType::Intel);
+        assert_eq!(
+            detect("U-Boot.armv8", ClientArchType::Arm64Uboot, qemu),
+            FirmwareType::UBoot
+        );
+        assert_eq!(detect("GRUBClient", bios, qemu), FirmwareType::Grub);
+        assert_eq!(detect("petitboot", bios, qemu), FirmwareType::Petitboot);
+        assert_eq!(
+            detect("HTTPClient:Arch:00016:UNDI:003000", bios, qemu),
+            FirmwareType::HttpClient
+        );
+        assert_eq!(detect(pxe, bios, rpi), FirmwareType::RaspberryPi);
+        assert_eq!(
+            detect(pxe, ClientArchType::ArmRpiBoot, qemu),
+            FirmwareType::RaspberryPi
+        );
+
+        assert_eq!(
+            "u-boot".parse::<FirmwareType>().unwrap(),
+            FirmwareType::UBoot
         );
+        assert!("coreboot".parse::<FirmwareType>().is_err());
     }
 
     static PXE_OFFER: &[u8] = &[
@@ -44,6 +44,7 @@ use std::str::FromStr;
 use uuid::Uuid;
 
 use crate::dhcp;
+use crate::dhcp::extra_options::ExtraDhcpOption;
 use crate::dhcp::utils::DhcpConnection;
 
 use super::parse::PxeClientInfo;
@@ -82,6 +83,12 @@ pub struct DhcpSocket {
     server_mac: EthernetAddress,
     server_ip: Ipv4Address,
     firmware_type: Option<dhcp::parse::FirmwareType>,
+    extra_options: Vec<ExtraDhcpOption>,
+    client_mac: Option<EthernetAddress>,
+    transaction_id: Option<u32>,
+    /// Replies sent to the current client, keyed by the transaction id,
+    /// message type and server port of the request they answered
+    replies: Vec<(u32, DhcpMessageType, u16, Vec<u8>)>,
 }
 
 impl DhcpSocket {
@@ -97,6 +104,16 @@ impl DhcpSocket {
     pub fn get_state(&self) -> &DhcpStates {
         &self._state
     }
+    /// Transaction id of the handshake with the current client
+    pub fn get_transaction_id(&self) -> Option<u32> {
+        self.transaction_id
+    }
+    pub fn get_extra_options(&self) -> &[ExtraDhcpOption] {
+        &self.extra_options
+    }
+    pub fn set_extra_options(&mut self, extra_options: Vec<ExtraDhcpOption>) {
+        self.extra_options = extra_options;
+    }
     fn set_state(&mut self, state: DhcpStates) {
         debug!("Changing state to {}", state);
         self._state = state;
@@ -131,10 +148,136 @@ impl DhcpSocket {
             server_ip,
             offer_file_name,
             firmware_type: None,
+            extra_options: Vec::new(),
+            client_mac: None,
+            transaction_id: None,
+            replies: Vec::new(),
+        }
+    }
+
+    /// Handles DHCP messages that are not part of the PXE handshake and can arrive
+    /// in any state: DHCPINFORM, DHCPRELEASE and DHCPDECLINE.
+    /// Returns `Error::IgnoreNoLog` for every other packet.
+    pub fn process_unsolicited(&mut self, rx_buffer: &[u8]) -> Result<Vec<u8>> {
+        let (dhcp, connection) =
+            utils::ether_to_dhcp_server(rx_buffer, &self.server_mac, &self.server_ip)?;
+
+        match utils::dhcp_message_type(&dhcp)? {
+            DhcpMessageType::Inform => {
+                /*
+                RFC 2131 4.3.5: The client already has an externally configured address and
+                only asks for local configuration parameters. The server responds with a
+                DHCPACK unicast to the address in 'ciaddr'.
+                */
+                let client_ip = dhcp.client_ip();
+                if client_ip.is_unspecified() {
+                    return Err(Error::Ignore(
+                        "DHCP inform without client ip address".to_string(),
+                    ));
+                }
+
+                let info = dhcp::parse::pxe_discover(dhcp)?;
+                log::info!("Parsed DHCP Inform from {}", client_ip);
+
+                let dhcp_repr = dhcp::construct::pxe_inform_ack(
+                    &info,
+                    self.server_ip,
+                    client_ip,
+                    &self.offer_file_name,
+                    &self.extra_options,
+                );
+
+                let connection = DhcpConnection {
+                    client_ip,
+                    client_port: 68,
+                    ..connection
+                };
+                let packet = utils::dhcp_to_ether_unicast(dhcp_repr.borrow_repr(), connection);
+
+                log::info!("Sent DHCP Ack for inform");
+                Ok(packet)
+            }
+            DhcpMessageType::Release => {
+                // We are a proxyDHCP server and do not own any leases
+                log::info!("Client {} released its address", connection.client_mac);
+                self.forget_client(connection.client_mac);
+                Err(Error::Ignore("Received DHCP release".to_string()))
+            }
+            DhcpMessageType::Decline => {
+                let declined_ip = dhcp
+                    .options()
+                    .find(|opt| opt.kind == 50 && opt.data.len() == 4)
+                    .map(|opt| Ipv4Address::from_bytes(opt.data));
+
+                match declined_ip {
+                    Some(ip) => warn!(
+                        "Client {} declined address {}. The address is already in use on this network",
+                        connection.client_mac, ip
+                    ),
+                    None => warn!("Client {} declined its address", connection.client_mac),
+                }
+                self.forget_client(connection.client_mac);
+                Err(Error::Ignore("Received DHCP decline".to_string()))
+            }
+            _ => Err(Error::IgnoreNoLog(
+                "Not an inform, release or decline packet".to_string(),
+            )),
+        }
+    }
+
+    /// Restarts the handshake if the client we are currently serving gave up its address
+    fn forget_client(&mut self, client_mac: EthernetAddress) {
+        if self.client_mac == Some(client_mac) {
+            self.client_mac = None;
+            self.firmware_type = None;
+            self.transaction_id = None;
+            self.replies.clear();
+            self.set_state(DhcpStates::Discover);
         }
     }
 
+    /// Re-sends our reply if the current client retransmits a request we already
+    /// answered, Wi-Fi links lose and duplicate frames. A discover with a new
+    /// transaction id restarts the handshake.
+    /// Returns `Error::IgnoreNoLog` for every other packet.
+    fn process_retransmission(&mut self, rx_buffer: &[u8]) -> Result<Vec<u8>> {
+        let client_mac = match self.client_mac {
+            Some(mac) => mac,
+            None => return Err(Error::IgnoreNoLog("No client yet".to_string())),
+        };
+        let (dhcp, connection) =
+            utils::ether_to_dhcp_server(rx_buffer, &self.server_mac, &self.server_ip)?;
+        if connection.client_mac != client_mac {
+            return Err(Error::IgnoreNoLog("Not our current client".to_string()));
+        }
+
+        let msg_type = utils::dhcp_message_type(&dhcp)?;
+        let xid = dhcp.transaction_id();
+        let reply = self.replies.iter().find(|(id, t, port, _)| {
+            *id == xid && *t == msg_type && *port == connection.server_port
+        });
+        if let Some((_, _, _, packet)) = reply {
+            log::info!("Re-sending reply to retransmitted DHCP {:?}", msg_type);
+            return Ok(packet.clone());
+        }
+
+        if msg_type == DhcpMessageType::Discover {
+            log::info!("Client {} restarted with a new discover", client_mac);
+            self.forget_client(client_mac);
+        }
+        Err(Error::IgnoreNoLog("Not a retransmission".to_string()))
+    }
+
     pub fn process(&mut self, rx_buffer: &[u8]) -> Result<Vec<u8>> {
+        match self.process_unsolicited(rx_buffer) {
+            Err(Error::IgnoreNoLog(_)) => (),
+            res => return res,
+        }
+        match self.process_retransmission(rx_buffer) {
+            Err(Error::IgnoreNoLog(_)) => (),
+            res => return res,
+        }
+
         match self.get_state() {
             DhcpStates::Discover => {
                 /* ================== Parse PXE Discover ================== */
@@ -146,17 +289,42 @@ impl DhcpSocket {
                    - A tag for the client system architecture.
                    - A DHCP option 60, Class ID, set to “PXEClient:Arch:xxxxx:UNDI:yyyzzz”.
                 */
-                let info = {
-                    let dhcp = super::utils::broadcast_ether_to_dhcp(rx_buffer)?;
+                let (info, relay) = {
+                    // A discover forwarded by a relay agent arrives unicast from the relay
+                    let (dhcp, relay) = match super::utils::broadcast_ether_to_dhcp(rx_buffer) {
+                        Ok(dhcp) => (dhcp, None),
+                        Err(e) => match utils::ether_to_dhcp_server(
+                            rx_buffer,
+                            &self.server_mac,
+                            &self.server_ip,
+                        ) {
+                            Ok((dhcp, connection)) if !dhcp.relay_agent_ip().is_unspecified() => {
+                                let relay = DhcpConnection {
+                                    client_ip: dhcp.relay_agent_ip(),
+                                    client_port: 67,
+                                    ..connection
+                                };
+                                (dhcp, Some(relay))
+                            }
+                            _ => return Err(e),
+                        },
+                    };
                     let info = crate::dhcp::parse::pxe_discover(dhcp)?;
 
                     if info.msg_type != DhcpMessageType::Discover {
                         Err(Error::Ignore("Not a dhcp discover packet".to_string()))
                     } else {
-                        Ok(info)
+                        Ok((info, relay))
                     }
                 }?;
 
+                if !info.firmware_type.is_served() {
+                    return Err(Error::Ignore(f!(
+                        "{:?} firmware detected, not answering",
+                        info.firmware_type
+                    )));
+                }
+
                 log::info!("Parsed PXE Discover");
                 log::info!("Sending PXE Offer");
 
@@ -167,13 +335,20 @@ impl DhcpSocket {
                 address field is null (0.0.0.0). If this is a DHCP Service, then the returned client IP address
                 field is valid.
                 */
-                let dhcp_repr =
-                    dhcp::construct::pxe_offer(&info, &self.server_ip, &self.offer_file_name);
-                let packet = utils::dhcp_to_ether_brdcast(
-                    dhcp_repr.borrow_repr(),
+                let dhcp_repr = dhcp::construct::pxe_offer(
+                    &info,
                     &self.server_ip,
-                    &self.server_mac,
+                    &self.offer_file_name,
+                    &self.extra_options,
                 );
+                let packet = match relay {
+                    Some(relay) => utils::dhcp_to_ether_unicast(dhcp_repr.borrow_repr(), relay),
+                    None => utils::dhcp_to_ether_brdcast(
+                        dhcp_repr.borrow_repr(),
+                        &self.server_ip,
+                        &self.server_mac,
+                    ),
+                };
 
                 log::info!("Sent PXE Offer");
 
@@ -193,12 +368,20 @@ impl DhcpSocket {
                     dhcp::parse::FirmwareType::Intel => {
                         self.set_state(DhcpStates::Request);
                     }
-                    dhcp::parse::FirmwareType::IPxe => {
+                    _ => {
                         info!("iPXE firmware detected. Jumping to TFTP phase");
                         self.set_state(DhcpStates::Done);
                     }
                 }
                 self.firmware_type = Some(info.firmware_type);
+                self.client_mac = Some(EthernetFrame::new_unchecked(rx_buffer).src_addr());
+                self.transaction_id = Some(info.transaction_id);
+                self.replies = vec![(
+                    info.transaction_id,
+                    DhcpMessageType::Discover,
+                    67,
+                    packet.clone(),
+                )];
 
                 Ok(packet)
             }
@@ -236,7 +419,9 @@ impl DhcpSocket {
                             self.set_state(DhcpStates::WaitForDhcpAck(info));
                             return Err(Error::WaitForDhcpAck);
                         }
-                        utils::TargetingScope::Multicast => todo!("Multicast is not supported"),
+                        utils::TargetingScope::Multicast => {
+                            return Err(Error::Ignore("Multicast is not supported".to_string()));
+                        }
                     }
 
                     Ok::<_, Error>((info, connection))
@@ -258,8 +443,12 @@ impl DhcpSocket {
                     - Any other options the NBP requires before it can be successfully executed.
                 */
 
-                let dhcp_repr =
-                    dhcp::construct::pxe_ack(&info, self.server_ip, &self.offer_file_name);
+                let dhcp_repr = dhcp::construct::pxe_ack(
+                    &info,
+                    self.server_ip,
+                    &self.offer_file_name,
+                    &self.extra_options,
+                );
                 let packet = utils::dhcp_to_ether_unicast(dhcp_repr.borrow_repr(), connection);
 
                 log::info!("Sent PXE ACK");
@@ -271,15 +460,36 @@ impl DhcpSocket {
                 */
 
                 self.set_state(DhcpStates::Done);
+                self.replies.push((
+                    info.transaction_id,
+                    DhcpMessageType::Request,
+                    connection.server_port,
+                    packet.clone(),
+                ));
 
                 Ok(packet)
             }
             DhcpStates::WaitForDhcpAck(info) => {
-                let (_, connection) =
+                let (dhcp, connection) =
                     utils::handle_dhcp_ack(rx_buffer, &self.server_mac, &self.server_ip)?;
 
-                let dhcp_repr =
-                    dhcp::construct::pxe_ack(info, self.server_ip, &self.offer_file_name);
+                // Other clients may be booting at the same time, only take the ack
+                // that belongs to our transaction
+                if dhcp.transaction_id() != info.transaction_id {
+                    return Err(Error::IgnoreNoLog(
+                        "DHCP ack of a different transaction".to_string(),
+                    ));
+                }
+                if utils::dhcp_message_type(&dhcp)? != DhcpMessageType::Ack {
+                    return Err(Error::Ignore("Not a dhcp ack packet".to_string()));
+                }
+
+                let dhcp_repr = dhcp::construct::pxe_ack(
+                    info,
+                    self.server_ip,
+                    &self.offer_file_name,
+                    &self.extra_options,
+                );
 
                 let packet = utils::dhcp_to_ether_unicast(dhcp_repr.borrow_repr(), connection);
 
@@ -290,7 +500,11 @@ impl DhcpSocket {
                 (port assigned in Boot Server Ack packet). The file downloaded and the placement of the
                 downloaded code in memory is dependent on the client’s CPU architecture.
                 */
+                // The client broadcast its request to the server port
+                let xid = info.transaction_id;
                 self.set_state(DhcpStates::Done);
+                self.replies
+                    .push((xid, DhcpMessageType::Request, 67, packet.clone()));
                 Ok(packet)
             }
             DhcpStates::Done => Err(Error::DhcpProtocolFinished),
@@ -19,6 +19,7 @@ use smoltcp::phy::RawSocket;
 use smoltcp::socket::dhcpv4;
 use smoltcp::time::Instant;
 use smoltcp::wire::ArpRepr;
+use smoltcp::wire::DhcpMessageType;
 use smoltcp::wire::DhcpPacket;
 use smoltcp::wire::DhcpRepr;
 use smoltcp::wire::EthernetAddress;
@@ -39,7 +40,13 @@ use smoltcp::wire::UdpRepr;
 use super::error::*;
 
 pub fn broadcast_ether_to_dhcp(buffer: &[u8]) -> Result<DhcpPacket<&[u8]>> {
-    let ether = EthernetFrame::new_checked(buffer).unwrap();
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
     if ether.dst_addr() != EthernetAddress::BROADCAST {
         return Err(Error::IgnoreNoLog("Not a broadcast packet".to_string()));
     }
@@ -117,7 +124,13 @@ pub fn arp_reply(repr: ArpRepr) -> Vec<u8> {
 }
 
 pub fn ether_to_arp(buffer: &[u8]) -> Result<ArpRepr> {
-    let ether = EthernetFrame::new_checked(buffer).unwrap();
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
     if ether.dst_addr() != EthernetAddress::BROADCAST {
         return Err(Error::IgnoreNoLog("Not a broadcast packet".to_string()));
     }
@@ -181,7 +194,7 @@ pub fn arp_respond(
 
             Ok(packet)
         }
-        _ => todo!(),
+        _ => Err(Error::Ignore("Unsupported arp packet".to_string())),
     }
 }
 
@@ -190,7 +203,13 @@ pub fn handle_dhcp_ack<'a>(
     server_mac: &'a EthernetAddress,
     server_ip: &'a Ipv4Address,
 ) -> Result<(DhcpPacket<&'a [u8]>, DhcpConnection)> {
-    let ether = EthernetFrame::new_checked(buffer).unwrap();
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
 
     if !ether.dst_addr().is_broadcast() {
         let err: String = format!("Mac address {} isn't broadcast", ether.dst_addr());
@@ -249,7 +268,13 @@ pub fn uni_broad_ether_to_dhcp<'a>(
     server_mac: &'a EthernetAddress,
     server_ip: &'a Ipv4Address,
 ) -> Result<(DhcpPacket<&'a [u8]>, TargetingScope, DhcpConnection)> {
-    let ether = EthernetFrame::new_checked(buffer).unwrap();
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
     if ether.dst_addr() != *server_mac {
         // && !ether.dst_addr().is_broadcast()
         let err: String = format!(
@@ -326,6 +351,93 @@ pub fn uni_broad_ether_to_dhcp<'a>(
     Ok((dhcp, target_scope, connection))
 }
 
+/// Parses a DHCP client message sent to the server port, either broadcast or
+/// unicast to our address. Used for messages that can arrive outside of the PXE
+/// handshake, like DHCPINFORM and DHCPRELEASE.
+pub fn ether_to_dhcp_server<'a>(
+    buffer: &'a [u8],
+    server_mac: &'a EthernetAddress,
+    server_ip: &'a Ipv4Address,
+) -> Result<(DhcpPacket<&'a [u8]>, DhcpConnection)> {
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
+    if ether.dst_addr() != *server_mac && !ether.dst_addr().is_broadcast() {
+        return Err(Error::IgnoreNoLog(
+            "Mac address does not match with ours. And isn't broadcast".to_string(),
+        ));
+    }
+
+    if ether.ethertype() != EthernetProtocol::Ipv4 {
+        return Err(Error::IgnoreNoLog("Not an ipv4 packet".to_string()));
+    }
+
+    let ipv4 = match Ipv4Packet::new_checked(ether.payload()) {
+        Ok(i) => i,
+        Err(e) => {
+            let err = format!("Parsing ipv4 packet failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
+
+    if ipv4.dst_addr() != *server_ip && !ipv4.dst_addr().is_broadcast() {
+        return Err(Error::IgnoreNoLog(
+            "IP destination does not match our server ip".to_string(),
+        ));
+    }
+
+    if ipv4.next_header() != IpProtocol::Udp {
+        return Err(Error::IgnoreNoLog("Not an udp packet".to_string()));
+    }
+
+    let udp = match UdpPacket::new_checked(ipv4.payload()) {
+        Ok(u) => u,
+        Err(e) => {
+            let err = format!("Parsing udp packet failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
+
+    if udp.dst_port() != 67 && udp.dst_port() != 4011 {
+        return Err(Error::IgnoreNoLog(format!(
+            "Not a dhcp packet. Port does not match ({} != 67 or 4011)",
+            udp.dst_port()
+        )));
+    }
+
+    let dhcp = match DhcpPacket::new_checked(udp.payload()) {
+        Ok(d) => d,
+        Err(e) => {
+            let err = format!("Parsing dhcp packet failed: {}", e);
+            return Err(Error::Ignore(err));
+        }
+    };
+
+    let connection = DhcpConnection {
+        server_ip: *server_ip,
+        server_mac: *server_mac,
+        client_ip: dhcp.client_ip(),
+        client_mac: ether.src_addr(),
+        server_port: udp.dst_port(),
+        client_port: udp.src_port(),
+    };
+
+    Ok((dhcp, connection))
+}
+
+/// Returns the value of the DHCP message type option (53)
+pub fn dhcp_message_type(dhcp: &DhcpPacket<&[u8]>) -> Result<DhcpMessageType> {
+    dhcp.options()
+        .find(|opt| opt.kind == 53)
+        .and_then(|opt| opt.data.first().copied())
+        .map(DhcpMessageType::from)
+        .ok_or(Error::MissingDhcpOption("Message Type"))
+}
+
 pub fn dhcp_to_ether_brdcast<'a>(
     dhcp: &'a DhcpRepr<'a>,
     server_ip: &'a Ipv4Address,
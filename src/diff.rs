@@ -0,0 +1,590 @@
+//! Field by field comparison of the frames we sent with captured ones.
+//!
+//! Byte for byte comparisons only tell that two frames differ. The diff walks the
+//! Ethernet, IPv4 and UDP headers and decodes DHCP and TFTP payloads, then reports
+//! every field that differs. Other payloads are compared in runs of differing bytes.
+//!
+//! DHCP options are compared by code instead of through `DhcpRepr`, which drops the
+//! PXE options we care about most.
+//!
+//! Replies are paired by what they answer, see [`align`], so one missing or extra
+//! reply does not make every following one differ.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address,
+    Ipv4Packet, UdpPacket,
+};
+
+use crate::dhcp::options::{ClientArchType, SubsetDhcpOption};
+use crate::prelude::*;
+use crate::tftp::parse::{Packet as TftpPacket, Repr as TftpRepr, TftpOptsReader};
+
+/// Runs of differing payload bytes that are reported per frame
+const MAX_BYTE_RUNS: usize = 8;
+
+pub(crate) const DHCP_PORTS: [u16; 3] = [67, 68, 4011];
+
+/// Fixed size DHCP fields without a typed accessor
+const DHCP_FLAGS: Range<usize> = 10..12;
+const DHCP_SERVER_NAME: Range<usize> = 44..108;
+const DHCP_BOOT_FILE: Range<usize> = 108..236;
+
+/// Fields that change between runs of the same session and can be left out of a diff
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ignore {
+    /// The IPv4 identification
+    pub ip_ident: bool,
+    /// The IPv4 and UDP checksums
+    pub checksums: bool,
+    /// The seconds elapsed since the client began the DHCP transaction
+    pub secs: bool,
+}
+
+impl Ignore {
+    /// Ignores all volatile fields
+    pub const VOLATILE: Ignore = Ignore {
+        ip_ident: true,
+        checksums: true,
+        secs: true,
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: String,
+    pub wanted: String,
+    pub got: String,
+}
+
+impl Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: wanted {}, got {}",
+            self.field, self.wanted, self.got
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Diffs(Vec<FieldDiff>);
+
+impl Diffs {
+    fn cmp<T: PartialEq + Display>(&mut self, field: &str, wanted: T, got: T) {
+        if wanted != got {
+            self.0.push(FieldDiff {
+                field: field.to_string(),
+                wanted: wanted.to_string(),
+                got: got.to_string(),
+            });
+        }
+    }
+
+    fn cmp_bytes(&mut self, field: &str, wanted: &[u8], got: &[u8]) {
+        self.cmp(&f!("{} length", field), wanted.len(), got.len());
+
+        let mut runs = 0;
+        let mut idx = 0;
+        let len = wanted.len().min(got.len());
+        while idx < len && runs < MAX_BYTE_RUNS {
+            if wanted[idx] == got[idx] {
+                idx += 1;
+                continue;
+            }
+            let start = idx;
+            while idx < len && wanted[idx] != got[idx] {
+                idx += 1;
+            }
+            self.cmp(
+                &f!("{}[{}..{}]", field, start, idx),
+                hex(&wanted[start..idx]),
+                hex(&got[start..idx]),
+            );
+            runs += 1;
+        }
+    }
+
+    /// Compares values by key, keys only one side has are reported as missing
+    fn cmp_maps<K: Ord + Display>(
+        &mut self,
+        prefix: &str,
+        wanted: &BTreeMap<K, String>,
+        got: &BTreeMap<K, String>,
+    ) {
+        let missing = "missing".to_string();
+        let got_only = got.keys().filter(|key| !wanted.contains_key(*key));
+        for key in wanted.keys().chain(got_only) {
+            self.cmp(
+                &f!("{} {}", prefix, key),
+                wanted.get(key).unwrap_or(&missing),
+                got.get(key).unwrap_or(&missing),
+            );
+        }
+    }
+}
+
+/// What a reply answers. Replies of both servers with the same key are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplyKey {
+    Dhcp {
+        xid: u32,
+        msg_type: Option<DhcpMessageType>,
+    },
+    Tftp {
+        client_port: u16,
+        opcode: u16,
+        block: Option<u16>,
+    },
+    Other(EthernetProtocol, Option<IpProtocol>),
+}
+
+fn reply_key(frame: &[u8]) -> Option<ReplyKey> {
+    let ether = EthernetFrame::new_checked(frame).ok()?;
+    if ether.ethertype() != EthernetProtocol::Ipv4 {
+        return Some(ReplyKey::Other(ether.ethertype(), None));
+    }
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).ok()?;
+    if ipv4.next_header() != IpProtocol::Udp {
+        return Some(ReplyKey::Other(ether.ethertype(), Some(ipv4.next_header())));
+    }
+    let udp = UdpPacket::new_checked(ipv4.payload()).ok()?;
+    if DHCP_PORTS.contains(&udp.src_port()) {
+        let dhcp = DhcpPacket::new_checked(udp.payload()).ok()?;
+        return Some(ReplyKey::Dhcp {
+            xid: dhcp.transaction_id(),
+            msg_type: crate::dhcp::utils::dhcp_message_type(&dhcp).ok(),
+        });
+    }
+
+    let payload = udp.payload();
+    let opcode = u16::from_be_bytes(payload.get(..2)?.try_into().ok()?);
+    // Data and ack carry the block number
+    let block = match opcode {
+        3 | 4 => Some(u16::from_be_bytes(payload.get(2..4)?.try_into().ok()?)),
+        _ => None,
+    };
+    Some(ReplyKey::Tftp {
+        client_port: udp.dst_port(),
+        opcode,
+        block,
+    })
+}
+
+/// Pairs every reply in `wanted` with the first reply in `got` that answers the same
+/// DHCP transaction or TFTP block. Returns the pairs in the order of `wanted`, followed
+/// by the replies only `got` contains. `None` marks a reply that only one side sent.
+pub fn align(wanted: &[Vec<u8>], got: &[Vec<u8>]) -> Vec<(Option<usize>, Option<usize>)> {
+    let got_keys: Vec<Option<ReplyKey>> = got.iter().map(|frame| reply_key(frame)).collect();
+    let mut paired = vec![false; got.len()];
+
+    let mut pairs: Vec<(Option<usize>, Option<usize>)> = wanted
+        .iter()
+        .enumerate()
+        .map(|(wanted_idx, frame)| {
+            let key = reply_key(frame);
+            let got_idx = (0..got.len()).find(|idx| !paired[*idx] && got_keys[*idx] == key);
+            if let Some(idx) = got_idx {
+                paired[idx] = true;
+            }
+            (Some(wanted_idx), got_idx)
+        })
+        .collect();
+    pairs.extend(
+        paired
+            .iter()
+            .enumerate()
+            .filter(|(_, paired)| !**paired)
+            .map(|(idx, _)| (None, Some(idx))),
+    );
+    pairs
+}
+
+/// Returns the fields of `got` that differ from `wanted`, empty if the frames are equal
+pub fn diff_frames(wanted: &[u8], got: &[u8], ignore: Ignore) -> Vec<FieldDiff> {
+    let mut diffs = Diffs::default();
+
+    let (wanted, got) = match (
+        EthernetFrame::new_checked(wanted),
+        EthernetFrame::new_checked(got),
+    ) {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        _ => {
+            diffs.cmp_bytes("frame", wanted, got);
+            return diffs.0;
+        }
+    };
+    diffs.cmp("ethernet dst", wanted.dst_addr(), got.dst_addr());
+    diffs.cmp("ethernet src", wanted.src_addr(), got.src_addr());
+    diffs.cmp("ethernet type", wanted.ethertype(), got.ethertype());
+    if wanted.ethertype() != EthernetProtocol::Ipv4 || got.ethertype() != EthernetProtocol::Ipv4 {
+        diffs.cmp_bytes("ethernet payload", wanted.payload(), got.payload());
+        return diffs.0;
+    }
+
+    let (wanted, got) = match (
+        Ipv4Packet::new_checked(wanted.payload()),
+        Ipv4Packet::new_checked(got.payload()),
+    ) {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        (wanted_ip, got_ip) => {
+            diffs.cmp("ipv4 valid", wanted_ip.is_ok(), got_ip.is_ok());
+            diffs.cmp_bytes("ethernet payload", wanted.payload(), got.payload());
+            return diffs.0;
+        }
+    };
+    diffs.cmp("ipv4 src", wanted.src_addr(), got.src_addr());
+    diffs.cmp("ipv4 dst", wanted.dst_addr(), got.dst_addr());
+    diffs.cmp("ipv4 total length", wanted.total_len(), got.total_len());
+    if !ignore.ip_ident {
+        diffs.cmp("ipv4 ident", wanted.ident(), got.ident());
+    }
+    diffs.cmp("ipv4 ttl", wanted.hop_limit(), got.hop_limit());
+    diffs.cmp("ipv4 protocol", wanted.next_header(), got.next_header());
+    if !ignore.checksums {
+        diffs.cmp("ipv4 checksum", wanted.checksum(), got.checksum());
+    }
+    if wanted.next_header() != IpProtocol::Udp || got.next_header() != IpProtocol::Udp {
+        diffs.cmp_bytes("ipv4 payload", wanted.payload(), got.payload());
+        return diffs.0;
+    }
+
+    let (wanted, got) = match (
+        UdpPacket::new_checked(wanted.payload()),
+        UdpPacket::new_checked(got.payload()),
+    ) {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        (wanted_udp, got_udp) => {
+            diffs.cmp("udp valid", wanted_udp.is_ok(), got_udp.is_ok());
+            diffs.cmp_bytes("ipv4 payload", wanted.payload(), got.payload());
+            return diffs.0;
+        }
+    };
+    diffs.cmp("udp src port", wanted.src_port(), got.src_port());
+    diffs.cmp("udp dst port", wanted.dst_port(), got.dst_port());
+    diffs.cmp("udp length", wanted.len(), got.len());
+    if !ignore.checksums {
+        diffs.cmp("udp checksum", wanted.checksum(), got.checksum());
+    }
+
+    let is_dhcp = |udp: &UdpPacket<&[u8]>| {
+        DHCP_PORTS.contains(&udp.src_port()) || DHCP_PORTS.contains(&udp.dst_port())
+    };
+    if is_dhcp(&wanted) && is_dhcp(&got) {
+        diff_dhcp(&mut diffs, wanted.payload(), got.payload(), ignore);
+    } else {
+        diff_tftp(&mut diffs, wanted.payload(), got.payload());
+    }
+    diffs.0
+}
+
+fn diff_dhcp(diffs: &mut Diffs, wanted_bytes: &[u8], got_bytes: &[u8], ignore: Ignore) {
+    let (wanted, got) = match (
+        DhcpPacket::new_checked(wanted_bytes),
+        DhcpPacket::new_checked(got_bytes),
+    ) {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        (wanted_dhcp, got_dhcp) => {
+            diffs.cmp("dhcp valid", wanted_dhcp.is_ok(), got_dhcp.is_ok());
+            diffs.cmp_bytes("udp payload", wanted_bytes, got_bytes);
+            return;
+        }
+    };
+
+    diffs.cmp(
+        "dhcp op",
+        f!("{:?}", wanted.opcode()),
+        f!("{:?}", got.opcode()),
+    );
+    diffs.cmp(
+        "dhcp xid",
+        f!("{:#010x}", wanted.transaction_id()),
+        f!("{:#010x}", got.transaction_id()),
+    );
+    if !ignore.secs {
+        diffs.cmp("dhcp secs", wanted.secs(), got.secs());
+    }
+    diffs.cmp(
+        "dhcp flags",
+        hex(&wanted_bytes[DHCP_FLAGS]),
+        hex(&got_bytes[DHCP_FLAGS]),
+    );
+    diffs.cmp("dhcp ciaddr", wanted.client_ip(), got.client_ip());
+    diffs.cmp("dhcp yiaddr", wanted.your_ip(), got.your_ip());
+    diffs.cmp("dhcp siaddr", wanted.server_ip(), got.server_ip());
+    diffs.cmp("dhcp giaddr", wanted.relay_agent_ip(), got.relay_agent_ip());
+    diffs.cmp(
+        "dhcp chaddr",
+        wanted.client_hardware_address(),
+        got.client_hardware_address(),
+    );
+    diffs.cmp(
+        "dhcp sname",
+        c_str(&wanted_bytes[DHCP_SERVER_NAME]),
+        c_str(&got_bytes[DHCP_SERVER_NAME]),
+    );
+    diffs.cmp(
+        "dhcp file",
+        c_str(&wanted_bytes[DHCP_BOOT_FILE]),
+        c_str(&got_bytes[DHCP_BOOT_FILE]),
+    );
+
+    let options = |dhcp: &DhcpPacket<&[u8]>| -> Vec<(u8, String)> {
+        dhcp.options()
+            .map(|opt| (opt.kind, dhcp_option_value(opt.kind, opt.data)))
+            .collect()
+    };
+    let (wanted, got) = (options(&wanted), options(&got));
+    let by_name = |options: &[(u8, String)]| -> BTreeMap<DhcpOptionName, String> {
+        options
+            .iter()
+            .map(|(kind, value)| (DhcpOptionName(*kind), value.clone()))
+            .collect()
+    };
+    let (wanted_map, got_map) = (by_name(&wanted), by_name(&got));
+    diffs.cmp_maps("dhcp option", &wanted_map, &got_map);
+
+    // Some firmwares are picky about the order of the options
+    if wanted_map == got_map {
+        let order = |options: &[(u8, String)]| {
+            let kinds: Vec<String> = options.iter().map(|(kind, _)| kind.to_string()).collect();
+            kinds.join(",")
+        };
+        diffs.cmp("dhcp option order", order(&wanted), order(&got));
+    }
+}
+
+/// Prints the option code with the name of the options we know
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct DhcpOptionName(u8);
+
+impl Display for DhcpOptionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SubsetDhcpOption::try_from(self.0) {
+            Ok(opt) => write!(f, "{} ({:?})", self.0, opt),
+            Err(_) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Decodes the options we know the type of, others are printed as text or hex
+fn dhcp_option_value(kind: u8, data: &[u8]) -> String {
+    match SubsetDhcpOption::try_from(kind) {
+        Ok(SubsetDhcpOption::MessageType) if data.len() == 1 => {
+            return f!("{:?}", DhcpMessageType::from(data[0]));
+        }
+        Ok(SubsetDhcpOption::ServerIdentifier) if data.len() == 4 => {
+            return Ipv4Address::from_bytes(data).to_string();
+        }
+        Ok(SubsetDhcpOption::ClientSystemArchitecture) => {
+            if let Ok(arch) = ClientArchType::try_from(data) {
+                return f!("{:?}", arch);
+            }
+        }
+        _ => (),
+    }
+
+    let is_text = !data.is_empty() && data.iter().all(|b| b.is_ascii_graphic() || *b == b' ');
+    if is_text {
+        f!("{:?}", String::from_utf8_lossy(data))
+    } else {
+        hex(data)
+    }
+}
+
+fn diff_tftp(diffs: &mut Diffs, wanted_bytes: &[u8], got_bytes: &[u8]) {
+    let packets = (
+        TftpPacket::new_checked(wanted_bytes),
+        TftpPacket::new_checked(got_bytes),
+    );
+    let (wanted_packet, got_packet) = match packets {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        _ => {
+            diffs.cmp_bytes("udp payload", wanted_bytes, got_bytes);
+            return;
+        }
+    };
+    let (wanted, got) = match (
+        TftpRepr::parse(&wanted_packet),
+        TftpRepr::parse(&got_packet),
+    ) {
+        (Ok(wanted), Ok(got)) => (wanted, got),
+        _ => {
+            diffs.cmp_bytes("udp payload", wanted_bytes, got_bytes);
+            return;
+        }
+    };
+
+    match (wanted, got) {
+        (
+            TftpRepr::ReadRequest {
+                filename: wanted_name,
+                mode: wanted_mode,
+                opts: wanted_opts,
+            },
+            TftpRepr::ReadRequest {
+                filename: got_name,
+                mode: got_mode,
+                opts: got_opts,
+            },
+        )
+        | (
+            TftpRepr::WriteRequest {
+                filename: wanted_name,
+                mode: wanted_mode,
+                opts: wanted_opts,
+            },
+            TftpRepr::WriteRequest {
+                filename: got_name,
+                mode: got_mode,
+                opts: got_opts,
+            },
+        ) => {
+            diffs.cmp("tftp filename", wanted_name, got_name);
+            diffs.cmp("tftp mode", wanted_mode.as_str(), got_mode.as_str());
+            diff_tftp_options(diffs, wanted_opts, got_opts);
+        }
+        (
+            TftpRepr::Data {
+                block_num: wanted_block,
+                data: wanted_data,
+            },
+            TftpRepr::Data {
+                block_num: got_block,
+                data: got_data,
+            },
+        ) => {
+            diffs.cmp("tftp block", wanted_block, got_block);
+            diffs.cmp_bytes("tftp data", wanted_data, got_data);
+        }
+        (
+            TftpRepr::Ack {
+                block_num: wanted_block,
+            },
+            TftpRepr::Ack {
+                block_num: got_block,
+            },
+        ) => diffs.cmp("tftp block", wanted_block, got_block),
+        (TftpRepr::OptionAck { opts: wanted_opts }, TftpRepr::OptionAck { opts: got_opts }) => {
+            diff_tftp_options(diffs, wanted_opts, got_opts);
+        }
+        (
+            TftpRepr::Error {
+                code: wanted_code,
+                msg: wanted_msg,
+            },
+            TftpRepr::Error {
+                code: got_code,
+                msg: got_msg,
+            },
+        ) => {
+            diffs.cmp(
+                "tftp error code",
+                f!("{:?}", wanted_code),
+                f!("{:?}", got_code),
+            );
+            diffs.cmp("tftp error message", wanted_msg, got_msg);
+        }
+        _ => diffs.cmp(
+            "tftp opcode",
+            f!("{:?}", wanted_packet.opcode()),
+            f!("{:?}", got_packet.opcode()),
+        ),
+    }
+}
+
+fn diff_tftp_options(diffs: &mut Diffs, wanted: TftpOptsReader<'_>, got: TftpOptsReader<'_>) {
+    // Option names are case insensitive
+    let options = |opts: &TftpOptsReader<'_>| -> BTreeMap<String, String> {
+        opts.options()
+            .map(|opt| (opt.name.to_lowercase(), opt.value.to_string()))
+            .collect()
+    };
+    diffs.cmp_maps("tftp option", &options(&wanted), &options(&got));
+}
+
+/// Returns the text of a zero terminated field
+fn c_str(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    f!("{:?}", String::from_utf8_lossy(&bytes[..len]))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| f!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::{read_frames, set_dhcp_message_type, set_dhcp_transaction_id};
+    use smoltcp::wire::IpAddress;
+    use std::path::Path;
+
+    fn fields(diffs: &[FieldDiff]) -> Vec<&str> {
+        diffs.iter().map(|d| d.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_diff_frames() {
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        assert!(diff_frames(&frames[1], &frames[1], Ignore::default()).is_empty());
+
+        let mut got = frames[1].clone();
+        got[0] ^= 0xff;
+        let diffs = diff_frames(&frames[1], &got, Ignore::default());
+        assert_eq!(fields(&diffs), ["ethernet dst"]);
+
+        assert_eq!(
+            diff_frames(&frames[1], &frames[1][..10], Ignore::default()),
+            vec![FieldDiff {
+                field: "frame length".to_string(),
+                wanted: frames[1].len().to_string(),
+                got: "10".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_dhcp() {
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        let mut got = frames[1].clone();
+        set_dhcp_transaction_id(&mut got, 0x1234_5678);
+
+        let diffs = diff_frames(&frames[1], &got, Ignore::default());
+        assert_eq!(fields(&diffs), ["udp checksum", "dhcp xid"]);
+        assert_eq!(diffs[1].got, "0x12345678");
+        let diffs = diff_frames(&frames[1], &got, Ignore::VOLATILE);
+        assert_eq!(fields(&diffs), ["dhcp xid"]);
+
+        let mut got = frames[1].clone();
+        set_dhcp_message_type(&mut got, DhcpMessageType::Nak);
+        let diffs = diff_frames(&frames[1], &got, Ignore::VOLATILE);
+        assert_eq!(fields(&diffs), ["dhcp option 53 (MessageType)"]);
+        assert_eq!(diffs[0].got, "Nak");
+    }
+
+    #[test]
+    fn test_diff_tftp() {
+        let frames = read_frames(Path::new("./assets/intel_bios_tftp.pcapng"));
+        let oack = frames
+            .iter()
+            .find(|f| f.len() > 44 && f[42..44] == [0, 6])
+            .expect("No oack in capture");
+
+        // Change a digit of an option value and fix up the udp checksum
+        let mut got = oack.clone();
+        let idx = got[44..].iter().position(|b| b.is_ascii_digit()).unwrap() + 44;
+        got[idx] = if got[idx] == b'9' { b'1' } else { got[idx] + 1 };
+        let mut ipv4 = Ipv4Packet::new_unchecked(&mut got[14..]);
+        let (src_addr, dst_addr) = (ipv4.src_addr(), ipv4.dst_addr());
+        UdpPacket::new_unchecked(ipv4.payload_mut())
+            .fill_checksum(&IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr));
+
+        let diffs = diff_frames(oack, &got, Ignore::VOLATILE);
+        assert_eq!(diffs.len(), 1);
+        assert!(diffs[0].field.starts_with("tftp option "));
+    }
+}
@@ -0,0 +1,719 @@
+//! Simulated PXE client for end-to-end tests without hardware.
+//!
+//! The client speaks DHCP and TFTP like the firmware it emulates and is wired to a
+//! [`PxeSocket`] in memory. The link between them can lose and reorder frames. Time is
+//! virtual and only advances while both sides wait for a timeout.
+
+use std::collections::VecDeque;
+
+use log::*;
+use rand::prelude::*;
+use smoltcp::phy::{Checksum, ChecksumCapabilities};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    DhcpMessageType, DhcpOption, DhcpPacket, DhcpRepr, EthernetAddress, EthernetFrame,
+    EthernetProtocol, EthernetRepr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, UdpPacket,
+    UdpRepr,
+};
+use uuid::Uuid;
+
+use crate::dhcp::options::*;
+use crate::dhcp::parse::FirmwareType;
+use crate::prelude::*;
+use crate::tftp::parse::{self, ErrorCode, Mode, TftpOptsReader};
+use crate::{PxeSocket, PxeStates};
+
+/// Time that passes while both sides wait for a timeout
+const IDLE_STEP: Duration = Duration::from_millis(50);
+
+/// How long the emulator waits for the server to finish after the client has the file
+const LINGER: Duration = Duration::from_secs(5);
+
+/// The 'file' field of the BOOTP header
+const BOOT_FILE: std::ops::Range<usize> = 108..236;
+
+/// Source port of the first TFTP request, the Intel firmware requests the file
+/// again from the next port after asking for its size
+const TFTP_CLIENT_PORT: u16 = 2070;
+
+const DEFAULT_BLKSIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub mac: EthernetAddress,
+    /// Address the DHCP server of the network assigned, it is not simulated
+    pub ip: Ipv4Address,
+    /// Decides the DHCP options and the handshake. The server only detects a
+    /// Raspberry Pi by its `arch` or a `mac` of the Raspberry Pi Foundation.
+    pub firmware: FirmwareType,
+    pub arch: ClientArchType,
+    pub transaction_id: u32,
+    /// Requested TFTP block size, the server uses 512 bytes without
+    pub blksize: Option<usize>,
+    /// Requested TFTP window size, the client acks every block without
+    pub windowsize: Option<usize>,
+    /// Ask for the file size. The Intel firmware does so in a separate read request.
+    pub tsize: bool,
+    /// Retransmission timeout of the client
+    pub timeout: Duration,
+    /// The boot fails after this many retransmissions of the same frame
+    pub max_retries: u8,
+}
+
+impl Default for ClientConfig {
+    /// An Intel PXE ROM of a x86 BIOS
+    fn default() -> Self {
+        Self {
+            mac: EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            ip: Ipv4Address::new(192, 168, 178, 75),
+            firmware: FirmwareType::Intel,
+            arch: ClientArchType::X86Bios,
+            transaction_id: 0x4331af13,
+            blksize: Some(1456),
+            windowsize: None,
+            tsize: true,
+            timeout: Duration::from_secs(1),
+            max_retries: 5,
+        }
+    }
+}
+
+/// Behaviour of the simulated link. Probabilities are between 0 and 1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConfig {
+    /// Probability that a frame is lost
+    pub loss: f64,
+    /// Probability that a frame is delivered after the next one
+    pub reorder: f64,
+    /// Seed of the random decisions, the same seed replays the same boot
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootResult {
+    /// Boot file name of the DHCP offer
+    pub boot_file: String,
+    /// File size announced in the option ack
+    pub tsize: Option<usize>,
+    /// The downloaded file
+    pub data: Vec<u8>,
+    /// Virtual time from the discover to the last data block
+    pub elapsed: Duration,
+}
+
+/// One direction of the simulated link
+struct Link {
+    config: LinkConfig,
+    rng: StdRng,
+    queue: VecDeque<Vec<u8>>,
+    /// Frame that waits to be overtaken by the next one
+    delayed: Option<Vec<u8>>,
+}
+
+impl Link {
+    fn new(config: LinkConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            queue: VecDeque::new(),
+            delayed: None,
+        }
+    }
+
+    fn send(&mut self, frame: Vec<u8>) {
+        if self.rng.gen_bool(self.config.loss) {
+            trace!("Link lost a frame");
+            return;
+        }
+        if self.delayed.is_none() && self.rng.gen_bool(self.config.reorder) {
+            trace!("Link delays a frame");
+            self.delayed = Some(frame);
+            return;
+        }
+        self.queue.push_back(frame);
+        if let Some(delayed) = self.delayed.take() {
+            self.queue.push_back(delayed);
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop_front()
+    }
+
+    /// Delivers a delayed frame that nothing overtook
+    fn flush(&mut self) {
+        if let Some(delayed) = self.delayed.take() {
+            self.queue.push_back(delayed);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.delayed.is_none()
+    }
+}
+
+/// Boots simulated clients from a [`PxeSocket`]
+pub struct Emulator<'a> {
+    socket: &'a mut PxeSocket,
+    to_server: Link,
+    to_client: Link,
+    /// Loses the frames it returns true for, in both directions
+    filter: Option<Box<dyn FnMut(&[u8]) -> bool + 'a>>,
+    now: Instant,
+}
+
+impl<'a> Emulator<'a> {
+    pub fn new(socket: &'a mut PxeSocket, link: LinkConfig) -> Self {
+        Self {
+            socket,
+            to_server: Link::new(link, link.seed),
+            to_client: Link::new(link, link.seed.wrapping_add(1)),
+            filter: None,
+            now: Instant::from_secs(0),
+        }
+    }
+
+    /// Loses every frame `filter` returns true for, on top of the random losses of the link
+    pub fn set_filter(&mut self, filter: impl FnMut(&[u8]) -> bool + 'a) {
+        self.filter = Some(Box::new(filter));
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    pub fn socket(&self) -> &PxeSocket {
+        self.socket
+    }
+
+    fn is_lost(&mut self, frame: &[u8]) -> bool {
+        self.filter.as_mut().is_some_and(|filter| filter(frame))
+    }
+
+    fn send_to_server(&mut self, frame: Vec<u8>) {
+        if !self.is_lost(&frame) {
+            self.to_server.send(frame);
+        }
+    }
+
+    fn send_to_client(&mut self, frame: Vec<u8>) {
+        if !self.is_lost(&frame) {
+            self.to_client.send(frame);
+        }
+    }
+
+    /// Boots one client until it downloaded its boot file and the server finished the transfer
+    pub fn boot(&mut self, config: ClientConfig) -> Result<BootResult> {
+        let start = self.now;
+        let mut client = PxeClient::new(config);
+        let mut result: Option<BootResult> = None;
+
+        let discover = client.start(self.now)?;
+        self.send_to_server(discover);
+
+        loop {
+            if let Some(frame) = self.to_server.recv() {
+                match self.socket.process(&frame, self.now) {
+                    Ok(reply) => self.send_to_client(reply),
+                    Err(e) => trace!("Server dropped frame: {}", e),
+                }
+                // The rest of a TFTP window is due right away, like in `PxeServer::poll`
+                while let Ok(frame) = self.socket.process_timeout(self.now) {
+                    self.send_to_client(frame);
+                }
+                continue;
+            }
+
+            if let Some(frame) = self.to_client.recv() {
+                for reply in client.process(&frame, self.now)? {
+                    self.send_to_server(reply);
+                }
+                if result.is_none() {
+                    result = client.result(self.now - start);
+                }
+                continue;
+            }
+
+            if let Some(res) = result.take() {
+                let finished = *self.socket.get_state() == PxeStates::Dhcp
+                    && self.to_server.is_idle()
+                    && self.to_client.is_idle();
+                if finished || self.now - start > res.elapsed + LINGER {
+                    return Ok(res);
+                }
+                result = Some(res);
+            }
+
+            // Nothing in flight, let time pass until someone retransmits
+            self.now += IDLE_STEP;
+            self.to_server.flush();
+            self.to_client.flush();
+            while let Ok(frame) = self.socket.process_timeout(self.now) {
+                self.send_to_client(frame);
+            }
+            if let Some(frame) = client.process_timeout(self.now)? {
+                self.send_to_server(frame);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    Discover,
+    /// Intel firmware asks the boot server on port 4011 after the offer
+    Request,
+    /// Intel firmware asks for the file size and aborts that read request
+    SizeProbe,
+    Transfer,
+    Done,
+}
+
+/// DHCP and TFTP state machine of a simulated firmware
+#[derive(Debug)]
+pub struct PxeClient {
+    config: ClientConfig,
+    state: ClientState,
+    server_mac: EthernetAddress,
+    server_ip: Ipv4Address,
+    boot_file: String,
+    tftp_port: u16,
+    blksize: usize,
+    windowsize: usize,
+    tsize: Option<usize>,
+    data: Vec<u8>,
+    next_block: u16,
+    /// Blocks received since the last ack
+    window_received: usize,
+    last_sent: Vec<u8>,
+    timeout: Instant,
+    retries: u8,
+}
+
+impl PxeClient {
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            config,
+            state: ClientState::Discover,
+            server_mac: EthernetAddress::BROADCAST,
+            server_ip: Ipv4Address::BROADCAST,
+            boot_file: String::new(),
+            tftp_port: TFTP_CLIENT_PORT,
+            blksize: DEFAULT_BLKSIZE,
+            windowsize: 1,
+            tsize: None,
+            data: Vec::new(),
+            next_block: 1,
+            window_received: 0,
+            last_sent: Vec::new(),
+            timeout: Instant::from_secs(0),
+            retries: 0,
+        }
+    }
+
+    /// Returns the broadcast DHCP discover that starts the boot
+    pub fn start(&mut self, now: Instant) -> Result<Vec<u8>> {
+        self.state = ClientState::Discover;
+        let discover = self.dhcp_frame(DhcpMessageType::Discover)?;
+        Ok(self.sent(discover, now))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ClientState::Done
+    }
+
+    /// Returns the downloaded file once the transfer is complete
+    pub fn result(&self, elapsed: Duration) -> Option<BootResult> {
+        if !self.is_done() {
+            return None;
+        }
+        Some(BootResult {
+            boot_file: self.boot_file.clone(),
+            tsize: self.tsize,
+            data: self.data.clone(),
+            elapsed,
+        })
+    }
+
+    /// Returns the last frame again once its timeout expired
+    pub fn process_timeout(&mut self, now: Instant) -> Result<Option<Vec<u8>>> {
+        if self.is_done() || self.timeout > now {
+            return Ok(None);
+        }
+        if self.retries >= self.config.max_retries {
+            return Err(Error::Emulator(f!(
+                "client gave up in state {:?} after {} retries",
+                self.state,
+                self.retries
+            )));
+        }
+        debug!("Client retransmits in state {:?}", self.state);
+        self.retries += 1;
+        self.timeout = now + self.config.timeout;
+        Ok(Some(self.last_sent.clone()))
+    }
+
+    /// Processes a frame of the server and returns the frames the client answers with
+    pub fn process(&mut self, frame: &[u8], now: Instant) -> Result<Vec<Vec<u8>>> {
+        let ether = match EthernetFrame::new_checked(frame) {
+            Ok(ether) => ether,
+            Err(_) => return Ok(vec![]),
+        };
+        if ether.dst_addr() != self.config.mac && !ether.dst_addr().is_broadcast() {
+            return Ok(vec![]);
+        }
+        if ether.ethertype() != EthernetProtocol::Ipv4 {
+            return Ok(vec![]);
+        }
+        let ipv4 = match Ipv4Packet::new_checked(ether.payload()) {
+            Ok(ipv4) if ipv4.next_header() == IpProtocol::Udp => ipv4,
+            _ => return Ok(vec![]),
+        };
+        let udp = match UdpPacket::new_checked(ipv4.payload()) {
+            Ok(udp) => udp,
+            Err(_) => return Ok(vec![]),
+        };
+
+        let replies = match (udp.dst_port(), self.state) {
+            (68, ClientState::Discover | ClientState::Request) => {
+                self.process_dhcp(ether.src_addr(), ipv4.src_addr(), udp.payload())?
+            }
+            (68, _) => vec![],
+            (port, _) if port == self.tftp_port => self.process_tftp(udp.payload())?,
+            _ => vec![],
+        };
+
+        // Retransmissions repeat the frame that asks for the next step
+        if let Some(last) = replies.last() {
+            self.sent(last.clone(), now);
+        }
+        Ok(replies)
+    }
+
+    fn process_dhcp(
+        &mut self,
+        server_mac: EthernetAddress,
+        server_ip: Ipv4Address,
+        payload: &[u8],
+    ) -> Result<Vec<Vec<u8>>> {
+        let dhcp = match DhcpPacket::new_checked(payload) {
+            Ok(dhcp) => dhcp,
+            Err(_) => return Ok(vec![]),
+        };
+        if dhcp.transaction_id() != self.config.transaction_id {
+            return Ok(vec![]);
+        }
+
+        match (self.state, crate::dhcp::utils::dhcp_message_type(&dhcp)?) {
+            (ClientState::Discover, DhcpMessageType::Offer) => {
+                self.server_mac = server_mac;
+                self.server_ip = server_ip;
+                let file = &payload[BOOT_FILE];
+                let len = file.iter().position(|b| *b == 0).unwrap_or(file.len());
+                self.boot_file = String::from_utf8_lossy(&file[..len]).to_string();
+                info!(
+                    "Client got an offer of {} from {}",
+                    self.boot_file, server_ip
+                );
+
+                match self.config.firmware {
+                    FirmwareType::Intel => {
+                        self.state = ClientState::Request;
+                        Ok(vec![self.dhcp_frame(DhcpMessageType::Request)?])
+                    }
+                    _ => self.start_transfer(),
+                }
+            }
+            (ClientState::Request, DhcpMessageType::Ack) => {
+                info!("Client got the ack of the boot server");
+                self.start_transfer()
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Returns the read request of the boot file
+    fn start_transfer(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut opts = vec![];
+        if self.config.firmware == FirmwareType::Intel && self.config.tsize {
+            self.state = ClientState::SizeProbe;
+            push_option(&mut opts, "tsize", "0");
+        } else {
+            self.state = ClientState::Transfer;
+            if let Some(blksize) = self.config.blksize {
+                push_option(&mut opts, "blksize", &blksize.to_string());
+            }
+            if let Some(windowsize) = self.config.windowsize {
+                push_option(&mut opts, "windowsize", &windowsize.to_string());
+            }
+            if self.config.tsize {
+                push_option(&mut opts, "tsize", "0");
+            }
+        }
+
+        let request = parse::Repr::ReadRequest {
+            filename: &self.boot_file,
+            mode: Mode::Octet,
+            opts: TftpOptsReader::new(&opts),
+        };
+        Ok(vec![self.tftp_frame(&request)?])
+    }
+
+    fn process_tftp(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let packet = match parse::Packet::new_checked(payload) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(vec![]),
+        };
+        let repr = match parse::Repr::parse(&packet) {
+            Ok(repr) => repr,
+            Err(_) => return Ok(vec![]),
+        };
+
+        match (self.state, repr) {
+            (ClientState::SizeProbe | ClientState::Transfer, parse::Repr::Error { code, msg }) => {
+                Err(Error::Emulator(f!(
+                    "server sent tftp error {:?}: {}",
+                    code,
+                    msg
+                )))
+            }
+            (ClientState::SizeProbe, parse::Repr::OptionAck { opts }) => {
+                self.tsize = option_value(&opts, "tsize");
+                info!("Client got the file size {:?}", self.tsize);
+
+                // Abort like the Intel firmware and request the file from a new port
+                let abort = parse::Repr::Error {
+                    code: ErrorCode::Undefined,
+                    msg: "TFTP Aborted",
+                };
+                let abort = self.tftp_frame(&abort)?;
+                self.tftp_port += 1;
+                let mut frames = vec![abort];
+                frames.extend(self.start_transfer()?);
+                Ok(frames)
+            }
+            // A retransmitted option ack means our ack of it got lost
+            (ClientState::Transfer, parse::Repr::OptionAck { opts }) if self.next_block == 1 => {
+                self.blksize = option_value(&opts, "blksize").unwrap_or(DEFAULT_BLKSIZE);
+                self.windowsize = option_value(&opts, "windowsize").unwrap_or(1);
+                if let Some(tsize) = option_value(&opts, "tsize") {
+                    self.tsize = Some(tsize);
+                }
+                Ok(vec![self.ack_frame(0)?])
+            }
+            (ClientState::Transfer, parse::Repr::Data { block_num, data })
+                if block_num == self.next_block =>
+            {
+                self.data.extend_from_slice(data);
+                self.next_block = self.next_block.wrapping_add(1);
+                self.window_received += 1;
+                if data.len() < self.blksize {
+                    info!("Client downloaded {} bytes", self.data.len());
+                    self.state = ClientState::Done;
+                } else if self.window_received < self.windowsize {
+                    return Ok(vec![]);
+                }
+                self.window_received = 0;
+                Ok(vec![self.ack_frame(block_num)?])
+            }
+            // The server did not get our ack, also after the last block
+            (ClientState::Transfer | ClientState::Done, parse::Repr::Data { block_num, .. })
+                if block_num == self.next_block.wrapping_sub(1) =>
+            {
+                self.window_received = 0;
+                Ok(vec![self.ack_frame(block_num)?])
+            }
+            // A block of the window got lost, the server continues after our ack
+            (ClientState::Transfer, parse::Repr::Data { .. }) if self.windowsize > 1 => {
+                self.window_received = 0;
+                Ok(vec![self.ack_frame(self.next_block.wrapping_sub(1))?])
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Remembers the frame for retransmissions
+    fn sent(&mut self, frame: Vec<u8>, now: Instant) -> Vec<u8> {
+        self.last_sent = frame.clone();
+        self.timeout = now + self.config.timeout;
+        self.retries = 0;
+        frame
+    }
+
+    fn dhcp_options(&self) -> Vec<DhcpOptionWrapper> {
+        let arch = u16::from(self.config.arch);
+        let vendor_class = match self.config.firmware {
+            FirmwareType::Intel | FirmwareType::IPxe | FirmwareType::RaspberryPi => {
+                f!("PXEClient:Arch:{:05}:UNDI:002001", arch)
+            }
+            FirmwareType::HttpClient => f!("HTTPClient:Arch:{:05}:UNDI:003001", arch),
+            FirmwareType::UBoot => "U-Boot.armv8".to_string(),
+            FirmwareType::Grub => "GRUBClient".to_string(),
+            FirmwareType::Petitboot => "petitboot".to_string(),
+        };
+        let mut uuid = [0; 16];
+        uuid[10..].copy_from_slice(self.config.mac.as_bytes());
+
+        vec![
+            self.config.arch.into(),
+            NetworkInterfaceVersion {
+                interface_type: NetworkInterfaceType::Undi,
+                major: 2,
+                minor: 1,
+            }
+            .into(),
+            PxeUuid {
+                uuid: Uuid::from_bytes(uuid),
+            }
+            .into(),
+            VendorClassIdentifier { data: vendor_class }.into(),
+            ClientIdentifier {
+                hardware_type: HardwareType::Ethernet,
+                hardware_address: self.config.mac.as_bytes().to_vec(),
+            }
+            .into(),
+        ]
+    }
+
+    /// Builds the discover broadcast to port 67 or the request unicast to the boot server
+    fn dhcp_frame(&self, message_type: DhcpMessageType) -> Result<Vec<u8>> {
+        let wrappers = self.dhcp_options();
+        let mut options: Vec<DhcpOption> = wrappers.iter().map(|x| x.into()).collect();
+        if self.config.firmware == FirmwareType::IPxe {
+            let kind = SubsetDhcpOption::UserClassInformation.into();
+            options.push(DhcpOption {
+                kind,
+                data: b"iPXE",
+            });
+        }
+
+        let (client_ip, server, broadcast) = match message_type {
+            DhcpMessageType::Discover => (
+                Ipv4Address::UNSPECIFIED,
+                (EthernetAddress::BROADCAST, Ipv4Address::BROADCAST, 67),
+                true,
+            ),
+            _ => (
+                self.config.ip,
+                (self.server_mac, self.server_ip, 4011),
+                false,
+            ),
+        };
+
+        let repr = DhcpRepr {
+            sname: None,
+            boot_file: None,
+            message_type,
+            transaction_id: self.config.transaction_id,
+            client_hardware_address: self.config.mac,
+            secs: 0,
+            client_ip,
+            your_ip: Ipv4Address::UNSPECIFIED,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            broadcast,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+
+            // unimportant
+            router: None,
+            subnet_mask: None,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: None,
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &options,
+        };
+
+        let client = (self.config.mac, client_ip, 68);
+        let mut res = Ok(());
+        let frame = udp_frame(client, server, repr.buffer_len(), |buf| {
+            res = repr.emit(&mut DhcpPacket::new_unchecked(buf));
+        });
+        res.map_err(|e| Error::Emulator(f!("emitting dhcp packet failed: {}", e)))?;
+        Ok(frame)
+    }
+
+    fn ack_frame(&self, block_num: u16) -> Result<Vec<u8>> {
+        self.tftp_frame(&parse::Repr::Ack { block_num })
+    }
+
+    /// Builds a TFTP frame from the current client port to port 69 of the server
+    fn tftp_frame(&self, repr: &parse::Repr) -> Result<Vec<u8>> {
+        let client = (self.config.mac, self.config.ip, self.tftp_port);
+        let server = (self.server_mac, self.server_ip, 69);
+        let mut res = Ok(());
+        let frame = udp_frame(client, server, repr.buffer_len(), |buf| {
+            res = repr.emit(&mut parse::Packet::new_unchecked(buf));
+        });
+        res.map_err(|e| Error::Emulator(f!("emitting tftp packet failed: {}", e)))?;
+        Ok(frame)
+    }
+}
+
+/// Appends a TFTP option to the options of a read request
+fn push_option(opts: &mut Vec<u8>, name: &str, value: &str) {
+    opts.extend_from_slice(name.as_bytes());
+    opts.push(0);
+    opts.extend_from_slice(value.as_bytes());
+    opts.push(0);
+}
+
+fn option_value(opts: &TftpOptsReader, name: &str) -> Option<usize> {
+    opts.options()
+        .find(|opt| opt.name == name)
+        .and_then(|opt| opt.value.parse().ok())
+}
+
+/// Builds an ethernet frame with a UDP packet of `payload_len` bytes written by `emit_payload`
+fn udp_frame(
+    src: (EthernetAddress, Ipv4Address, u16),
+    dst: (EthernetAddress, Ipv4Address, u16),
+    payload_len: usize,
+    emit_payload: impl FnOnce(&mut [u8]),
+) -> Vec<u8> {
+    let mut checksum = ChecksumCapabilities::ignored();
+    checksum.ipv4 = Checksum::Both;
+    checksum.udp = Checksum::Both;
+
+    let udp_packet = UdpRepr {
+        src_port: src.2,
+        dst_port: dst.2,
+    };
+    let ip_packet = Ipv4Repr {
+        src_addr: src.1,
+        dst_addr: dst.1,
+        hop_limit: 64,
+        payload_len: payload_len + udp_packet.header_len(),
+        next_header: IpProtocol::Udp,
+    };
+    let eth_packet = EthernetRepr {
+        dst_addr: dst.0,
+        src_addr: src.0,
+        ethertype: EthernetProtocol::Ipv4,
+    };
+
+    let packet_size =
+        eth_packet.buffer_len() + ip_packet.buffer_len() + udp_packet.header_len() + payload_len;
+    let mut buffer = vec![0; packet_size];
+
+    let mut packet = EthernetFrame::new_unchecked(&mut buffer[..]);
+    eth_packet.emit(&mut packet);
+
+    let mut packet = Ipv4Packet::new_unchecked(packet.payload_mut());
+    ip_packet.emit(&mut packet, &checksum);
+
+    let mut packet = UdpPacket::new_unchecked(packet.payload_mut());
+    udp_packet.emit(
+        &mut packet,
+        &ip_packet.src_addr.into_address(),
+        &ip_packet.dst_addr.into_address(),
+        payload_len,
+        emit_payload,
+        &checksum,
+    );
+    buffer
+}
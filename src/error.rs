@@ -8,14 +8,28 @@ pub enum Error {
     #[error(transparent)]
     TryFromSlice(#[from] std::array::TryFromSliceError),
 
-    // #[error(transparent)]
-    // TftpError(#[from] crate::tftp::error::Error),
+    #[error(transparent)]
+    Pcap(#[from] pcap_file::PcapError),
+
+    #[error(transparent)]
+    TftpError(#[from] crate::tftp::error::Error),
+
+    #[error(transparent)]
+    DhcpError(#[from] crate::dhcp::error::Error),
 
-    // #[error(transparent)]
-    // DhcpError(#[from] crate::dhcp::error::Error),
     #[error("Kill current tftp connection")]
     StopTftpConnection(Vec<u8>),
 
+    #[error("Network device error: {0}")]
+    Device(String),
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[cfg(any(test, feature = "emulator"))]
+    #[error("Emulated client failed: {0}")]
+    Emulator(String),
+
     #[error("Ignore")]
     Ignore(String),
 
@@ -0,0 +1,268 @@
+//! Minimal ICMP for the raw stack: echo replies for the server ip and
+//! port unreachable errors for UDP ports we do not serve.
+
+use log::*;
+use smoltcp::phy::{Checksum, ChecksumCapabilities};
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Icmpv4DstUnreachable,
+    Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, UdpPacket,
+};
+
+use crate::prelude::*;
+
+/// UDP ports handled by the DHCP and TFTP state machines
+pub const SERVED_UDP_PORTS: [u16; 3] = [67, 69, 4011];
+
+/// ICMP errors are kept within the minimum IPv4 MTU, like smoltcp does
+const ICMP_ERROR_MAX_LEN: usize = 576;
+
+/// Answers pings to `server_ip` and UDP datagrams to ports that are not in `SERVED_UDP_PORTS`.
+/// Returns `Error::IgnoreNoLog` for everything the DHCP and TFTP state machines should see.
+pub fn icmp_respond(
+    rx_buffer: &[u8],
+    server_ip: &Ipv4Address,
+    server_mac: &EthernetAddress,
+) -> Result<Vec<u8>> {
+    let ether = match EthernetFrame::new_checked(rx_buffer) {
+        Ok(e) if e.ethertype() == EthernetProtocol::Ipv4 => e,
+        _ => return Err(Error::IgnoreNoLog("Not an ipv4 packet".to_string())),
+    };
+    // RFC 1122: No ICMP errors for link layer broadcasts
+    if ether.dst_addr() != *server_mac || ether.src_addr() == *server_mac {
+        return Err(Error::IgnoreNoLog("Not unicast to us".to_string()));
+    }
+
+    let ipv4 = match Ipv4Packet::new_checked(ether.payload()) {
+        Ok(i) if i.dst_addr() == *server_ip => i,
+        _ => return Err(Error::IgnoreNoLog("Not addressed to our ip".to_string())),
+    };
+
+    match ipv4.next_header() {
+        IpProtocol::Icmp => {
+            let checksum = ChecksumCapabilities::default();
+            let icmp = Icmpv4Packet::new_checked(ipv4.payload())
+                .and_then(|packet| Icmpv4Repr::parse(&packet, &checksum))
+                .map_err(|e| Error::Ignore(f!("Parsing icmp packet failed: {}", e)))?;
+
+            match icmp {
+                Icmpv4Repr::EchoRequest {
+                    ident,
+                    seq_no,
+                    data,
+                } => {
+                    debug!("Answering ping of {}", ipv4.src_addr());
+                    let reply = Icmpv4Repr::EchoReply {
+                        ident,
+                        seq_no,
+                        data,
+                    };
+                    Ok(icmp_to_ether_unicast(
+                        &reply,
+                        server_ip,
+                        server_mac,
+                        &ipv4.src_addr(),
+                        &ether.src_addr(),
+                    ))
+                }
+                _ => Err(Error::Ignore("Unhandled icmp packet".to_string())),
+            }
+        }
+        IpProtocol::Udp => {
+            let udp = UdpPacket::new_checked(ipv4.payload())
+                .map_err(|e| Error::IgnoreNoLog(f!("Parsing udp packet failed: {}", e)))?;
+            if SERVED_UDP_PORTS.contains(&udp.dst_port()) {
+                return Err(Error::IgnoreNoLog("Served udp port".to_string()));
+            }
+            // RFC 1122: No ICMP errors for datagrams from an invalid source
+            if !ipv4.src_addr().is_unicast() {
+                return Err(Error::Ignore("Udp packet from invalid source".to_string()));
+            }
+
+            debug!(
+                "Port {} is closed. Sending port unreachable to {}",
+                udp.dst_port(),
+                ipv4.src_addr()
+            );
+            let header = Ipv4Repr::parse(&ipv4, &ChecksumCapabilities::ignored())
+                .map_err(|e| Error::Ignore(f!("Parsing ipv4 packet failed: {}", e)))?;
+            // Quote as much of the offending datagram as fits, at least the udp header
+            let quote_len = ipv4
+                .payload()
+                .len()
+                .min(ICMP_ERROR_MAX_LEN - 2 * header.buffer_len() - 8);
+            let unreachable = Icmpv4Repr::DstUnreachable {
+                reason: Icmpv4DstUnreachable::PortUnreachable,
+                header,
+                data: &ipv4.payload()[..quote_len],
+            };
+            Ok(icmp_to_ether_unicast(
+                &unreachable,
+                server_ip,
+                server_mac,
+                &ipv4.src_addr(),
+                &ether.src_addr(),
+            ))
+        }
+        _ => Err(Error::IgnoreNoLog("Not an icmp or udp packet".to_string())),
+    }
+}
+
+pub fn icmp_to_ether_unicast(
+    icmp: &Icmpv4Repr,
+    server_ip: &Ipv4Address,
+    server_mac: &EthernetAddress,
+    client_ip: &Ipv4Address,
+    client_mac: &EthernetAddress,
+) -> Vec<u8> {
+    let mut checksum = ChecksumCapabilities::ignored();
+    checksum.ipv4 = Checksum::Both;
+    checksum.icmpv4 = Checksum::Both;
+
+    let ip_packet = Ipv4Repr {
+        src_addr: *server_ip,
+        dst_addr: *client_ip,
+        hop_limit: 128,
+        payload_len: icmp.buffer_len(),
+        next_header: IpProtocol::Icmp,
+    };
+
+    let eth_packet = EthernetRepr {
+        dst_addr: *client_mac,
+        src_addr: *server_mac,
+        ethertype: EthernetProtocol::Ipv4,
+    };
+
+    let packet_size = eth_packet.buffer_len() + ip_packet.buffer_len() + icmp.buffer_len();
+
+    let mut buffer = vec![0; packet_size];
+
+    let mut packet = EthernetFrame::new_unchecked(&mut buffer[..]);
+    eth_packet.emit(&mut packet);
+
+    let mut packet = Ipv4Packet::new_unchecked(packet.payload_mut());
+    ip_packet.emit(&mut packet, &checksum);
+
+    let mut packet = Icmpv4Packet::new_unchecked(packet.payload_mut());
+    icmp.emit(&mut packet, &checksum);
+
+    buffer
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::read_frames;
+    use crate::PxeSocket;
+    use smoltcp::time::Instant;
+    use std::path::Path;
+
+    fn addresses() -> (Ipv4Address, EthernetAddress, Ipv4Address, EthernetAddress) {
+        (
+            Ipv4Address::new(192, 168, 178, 97),
+            EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]),
+            Ipv4Address::new(192, 168, 178, 75),
+            EthernetAddress::from_bytes(&[0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe]),
+        )
+    }
+
+    #[test]
+    fn test_echo_reply() {
+        let (server_ip, server_mac, client_ip, client_mac) = addresses();
+
+        let request = Icmpv4Repr::EchoRequest {
+            ident: 0x1337,
+            seq_no: 1,
+            data: b"rs_pxe",
+        };
+        let frame =
+            icmp_to_ether_unicast(&request, &client_ip, &client_mac, &server_ip, &server_mac);
+
+        let reply = icmp_respond(&frame, &server_ip, &server_mac).unwrap();
+        let ether = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(ether.dst_addr(), client_mac);
+        let ipv4 = Ipv4Packet::new_checked(ether.payload()).unwrap();
+        assert!(ipv4.verify_checksum());
+        assert_eq!(ipv4.dst_addr(), client_ip);
+        let icmp = Icmpv4Packet::new_checked(ipv4.payload()).unwrap();
+        assert_eq!(
+            Icmpv4Repr::parse(&icmp, &ChecksumCapabilities::default()).unwrap(),
+            Icmpv4Repr::EchoReply {
+                ident: 0x1337,
+                seq_no: 1,
+                data: b"rs_pxe",
+            }
+        );
+
+        // Pings for other hosts are not ours to answer
+        let frame =
+            icmp_to_ether_unicast(&request, &client_ip, &client_mac, &client_ip, &server_mac);
+        assert!(matches!(
+            icmp_respond(&frame, &server_ip, &server_mac),
+            Err(Error::IgnoreNoLog(_))
+        ));
+    }
+
+    #[test]
+    fn test_port_unreachable() {
+        let (server_ip, server_mac, client_ip, client_mac) = addresses();
+
+        // The tftp read request is for a served port
+        let mut frame = read_frames(Path::new("./assets/intel_bios_tftp.pcapng")).remove(0);
+        assert!(matches!(
+            icmp_respond(&frame, &server_ip, &server_mac),
+            Err(Error::IgnoreNoLog(_))
+        ));
+
+        // Redirect it to a closed port
+        let udp_offset = 14 + 20;
+        frame[udp_offset + 2..udp_offset + 4].copy_from_slice(&5000u16.to_be_bytes());
+
+        let reply = icmp_respond(&frame, &server_ip, &server_mac).unwrap();
+        let ether = EthernetFrame::new_checked(&reply[..]).unwrap();
+        assert_eq!(ether.dst_addr(), client_mac);
+        let ipv4 = Ipv4Packet::new_checked(ether.payload()).unwrap();
+        assert_eq!(ipv4.dst_addr(), client_ip);
+        let icmp = Icmpv4Packet::new_checked(ipv4.payload()).unwrap();
+        match Icmpv4Repr::parse(&icmp, &ChecksumCapabilities::default()).unwrap() {
+            Icmpv4Repr::DstUnreachable {
+                reason,
+                header,
+                data,
+            } => {
+                assert_eq!(reason, Icmpv4DstUnreachable::PortUnreachable);
+                assert_eq!(header.src_addr, client_ip);
+                assert_eq!(header.dst_addr, server_ip);
+                assert_eq!(&data[2..4], &5000u16.to_be_bytes());
+            }
+            repr => panic!("Expected port unreachable, got {:?}", repr),
+        }
+    }
+
+    #[test]
+    fn test_icmp_opt_in() {
+        let (server_ip, server_mac, client_ip, client_mac) = addresses();
+        let mut pxe_socket = PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        );
+        let request = Icmpv4Repr::EchoRequest {
+            ident: 0x1337,
+            seq_no: 1,
+            data: b"rs_pxe",
+        };
+        let frame =
+            icmp_to_ether_unicast(&request, &client_ip, &client_mac, &server_ip, &server_mac);
+
+        // The host owns the address by default and answers itself
+        assert!(pxe_socket.process(&frame, Instant::from_secs(0)).is_err());
+
+        pxe_socket.set_icmp(true);
+        let reply = pxe_socket.process(&frame, Instant::from_secs(0)).unwrap();
+        assert_eq!(
+            reply,
+            icmp_respond(&frame, &server_ip, &server_mac).unwrap()
+        );
+    }
+}
@@ -1,19 +1,34 @@
 #![allow(unused_imports)]
 
+pub mod arp;
+pub mod bpf;
 pub mod dhcp;
+pub mod diff;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
 pub mod error;
+pub mod icmp;
 pub mod prelude;
+pub mod record;
+pub mod replay;
+pub mod server;
 
 pub mod tftp;
 pub mod udp_port_check;
 mod utils;
+pub mod vlan;
 
 #[cfg(test)]
 mod tests;
 
+use arp::ArpCache;
+use dhcp::extra_options::ExtraDhcpOption;
+use dhcp::lease::Leases;
+use dhcp::monitor::ServerMonitor;
 use dhcp::parse::FirmwareType;
 use prelude::*;
 use smoltcp::wire::ArpRepr;
+use tftp::cache::FileCache;
 use tftp::construct::TftpError;
 use tftp::construct::TftpOptionEnum;
 use tftp::parse::Repr;
@@ -24,9 +39,8 @@ use tftp::construct::Handle;
 use tftp::construct::TestTftp;
 use tftp::construct::TftpConnection;
 use tftp::construct::Transfer;
-use utils::build_arp_announce;
+use utils::{build_arp_announce, copy_frame};
 
-use core::panic;
 use ouroboros::self_referencing;
 use rand::prelude::*;
 use tftp::parse::TftpOption;
@@ -49,6 +63,7 @@ use smoltcp::wire::DhcpMessageType;
 use smoltcp::wire::DhcpPacket;
 use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::EthernetFrame;
+use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::HardwareAddress;
 use smoltcp::wire::IpAddress;
 use smoltcp::wire::IpCidr;
@@ -73,10 +88,14 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use uuid::Uuid;
 
+use crate::dhcp::socket::DhcpStates;
 use crate::tftp::socket::TftpStates;
 
 static ARP_TIMEOUT: Duration = Duration::from_secs(120);
 
+/// Largest frame a [`PxeSocket`] writes, a TFTP data frame with the maximum blksize fits
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
 pub enum PxeStates {
     Dhcp,
@@ -102,6 +121,17 @@ pub struct PxeSocket {
     dhcp_socket: dhcp::socket::DhcpSocket,
     tftp_socket: Option<TftpSocket>,
     timeout: Instant,
+    extra_dhcp_options: Vec<ExtraDhcpOption>,
+    server_monitor: ServerMonitor,
+    back_off: bool,
+    vlan: Option<u16>,
+    arp: bool,
+    arp_cache: ArpCache,
+    icmp: bool,
+    files: FileCache,
+    leases: Leases,
+    /// Reused by `process` and `process_timeout`, empty until the first call
+    tx_buffer: Vec<u8>,
 }
 
 impl PxeSocket {
@@ -120,38 +150,124 @@ impl PxeSocket {
     pub fn get_stage_one(&self) -> &PathBuf {
         &self.stage_one
     }
+    pub fn get_extra_dhcp_options(&self) -> &[ExtraDhcpOption] {
+        &self.extra_dhcp_options
+    }
+    /// Sets user defined DHCP options that are appended to every PXE offer and ack
+    pub fn set_extra_dhcp_options(&mut self, options: Vec<ExtraDhcpOption>) {
+        self.dhcp_socket.set_extra_options(options.clone());
+        self.extra_dhcp_options = options;
+    }
+    pub fn get_server_monitor(&self) -> &ServerMonitor {
+        &self.server_monitor
+    }
+    pub fn get_vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+    /// Serves the 802.1Q VLAN `vlan` instead of untagged frames.
+    /// `process` still takes untagged frames, tags are handled by [`server::PxeServer`].
+    pub fn set_vlan(&mut self, vlan: Option<u16>) {
+        self.vlan = vlan;
+    }
+    /// Do not answer new DHCP discovers while another PXE server is active on the network
+    pub fn set_back_off(&mut self, back_off: bool) {
+        self.back_off = back_off;
+    }
+    pub fn get_arp_cache(&self) -> &ArpCache {
+        &self.arp_cache
+    }
+    pub fn get_arp(&self) -> bool {
+        self.arp
+    }
+    /// Answers ARP requests for the server ip and announces it, enabled by default.
+    /// Disable it if the host owns the address, the host stack answers otherwise.
+    pub fn set_arp(&mut self, arp: bool) {
+        self.arp = arp;
+    }
+    pub fn get_icmp(&self) -> bool {
+        self.icmp
+    }
+    /// Answers pings and sends port unreachable errors for the server ip.
+    /// Only enable it if the host does not own the address, the host stack answers otherwise.
+    pub fn set_icmp(&mut self, icmp: bool) {
+        self.icmp = icmp;
+    }
+    pub fn get_file_cache(&self) -> &FileCache {
+        &self.files
+    }
+    /// Serves the boot files from `files`. Give every socket a clone of the same
+    /// cache to read each boot file once.
+    pub fn set_file_cache(&mut self, files: FileCache) {
+        self.files = files;
+    }
+    pub fn get_leases(&self) -> &Leases {
+        &self.leases
+    }
+    /// Shares the table of served clients. A client answered by another socket
+    /// sharing `leases` is ignored until its lease expires or it releases it.
+    pub fn set_leases(&mut self, leases: Leases) {
+        self.leases = leases;
+    }
     fn set_state(&mut self, state: PxeStates) {
         debug!("Changing state to {}", state);
         self._state = state;
     }
+    fn new_dhcp_socket(&self) -> dhcp::socket::DhcpSocket {
+        let mut dhcp_socket =
+            dhcp::socket::DhcpSocket::new(self.server_ip, self.server_mac, self.get_stage_one());
+        dhcp_socket.set_extra_options(self.extra_dhcp_options.clone());
+        dhcp_socket
+    }
     fn reset_state(&mut self) {
         self.tftp_socket = None;
-        self.dhcp_socket =
-            dhcp::socket::DhcpSocket::new(self.server_ip, self.server_mac, self.get_stage_one());
+        self.dhcp_socket = self.new_dhcp_socket();
         self.set_state(PxeStates::Dhcp);
     }
 
-    pub fn process_timeout(&mut self) -> Result<Vec<u8>> {
-        if self.timeout < Instant::now() {
-            self.timeout = Instant::now() + ARP_TIMEOUT;
-            return Ok(build_arp_announce(self.server_mac, self.server_ip));
+    /// Writes the next frame that is due because of a timeout to `tx_buffer`.
+    /// Returns the frame length.
+    pub fn process_timeout_into(&mut self, tx_buffer: &mut [u8], now: Instant) -> Result<usize> {
+        if self.arp && self.timeout < now {
+            self.timeout = now + ARP_TIMEOUT;
+            return copy_frame(
+                &build_arp_announce(self.server_mac, self.server_ip),
+                tx_buffer,
+            );
         }
 
         if let Some(tftp_socket) = &mut self.tftp_socket {
-            return match tftp_socket.process_timeout() {
-                Ok(packet) => Ok(packet),
+            return match tftp_socket.process_timeout(tx_buffer, now) {
+                Ok(len) => {
+                    self.arp_cache.address(&mut tx_buffer[..len], now);
+                    Ok(len)
+                }
                 Err(tftp::error::Error::StopTftpConnection(packet)) => {
                     self.reset_state();
-                    Ok(packet)
+                    copy_frame(&packet, tx_buffer)
                 }
                 Err(tftp::error::Error::Ignore(e)) => Err(Error::Ignore(e)),
                 Err(tftp::error::Error::IgnoreNoLog(e)) => Err(Error::IgnoreNoLog(e)),
-                Err(e) => panic!("{}", e),
+                Err(tftp::error::Error::Device(e)) => Err(Error::Device(e)),
+                Err(e) => {
+                    // The transfer can not continue, do not retry it on every poll
+                    self.reset_state();
+                    Err(e.into())
+                }
             };
         }
         Err(Error::IgnoreNoLog("Nothing todo".to_string()))
     }
 
+    /// Like `process_timeout_into` but returns the frame in a new buffer
+    pub fn process_timeout(&mut self, now: Instant) -> Result<Vec<u8>> {
+        let mut tx_buffer = self.take_tx_buffer();
+        let res = self
+            .process_timeout_into(&mut tx_buffer, now)
+            .map(|len| tx_buffer[..len].to_vec());
+        self.tx_buffer = tx_buffer;
+        res
+    }
+
     pub fn new(
         server_ip: Ipv4Address,
         server_mac: EthernetAddress,
@@ -173,25 +289,158 @@ impl PxeSocket {
 
         Self {
             _state: state,
-            timeout: Instant::now(),
+            // The first call to process_timeout announces the server
+            timeout: Instant::from_secs(0),
             tftp_socket: None,
             server_mac,
             server_ip,
             stage_two: stage_two.to_path_buf(),
             stage_one: stage_one.to_path_buf(),
             dhcp_socket,
+            extra_dhcp_options: Vec::new(),
+            server_monitor: ServerMonitor::new(),
+            back_off: false,
+            vlan: None,
+            arp: true,
+            arp_cache: ArpCache::new(),
+            icmp: false,
+            files: FileCache::new(),
+            leases: Leases::new(),
+            tx_buffer: Vec::new(),
         }
     }
 
-    pub fn process(&mut self, rx_buffer: &[u8]) -> Result<Vec<u8>> {
+    /// Like `process_into` but returns the reply in a new buffer
+    pub fn process(&mut self, rx_buffer: &[u8], now: Instant) -> Result<Vec<u8>> {
+        let mut tx_buffer = self.take_tx_buffer();
+        let res = self
+            .process_into(rx_buffer, &mut tx_buffer, now)
+            .map(|len| tx_buffer[..len].to_vec());
+        self.tx_buffer = tx_buffer;
+        res
+    }
+
+    /// The buffer `process` and `process_timeout` build replies in, allocated on first use.
+    /// Put it back into `self.tx_buffer` afterwards.
+    fn take_tx_buffer(&mut self) -> Vec<u8> {
+        let tx_buffer = std::mem::take(&mut self.tx_buffer);
+        if tx_buffer.is_empty() {
+            return vec![0; MAX_FRAME_LEN];
+        }
+        tx_buffer
+    }
+
+    /// Processes a received untagged frame and writes the reply to `tx_buffer`.
+    /// Returns the length of the reply. TFTP data is copied from the file cache
+    /// into `tx_buffer`, so it should hold at least `MAX_FRAME_LEN` bytes.
+    pub fn process_into(
+        &mut self,
+        rx_buffer: &[u8],
+        tx_buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        let client = dhcp::lease::dhcp_client(rx_buffer);
+        if let Some((mac, _)) = client {
+            if let Some(lease) = self.leases.get(&mac, now) {
+                if (lease.server_ip, lease.vlan) != (self.server_ip, self.vlan) {
+                    return Err(Error::Ignore(f!(
+                        "Client {} is served by {}",
+                        mac,
+                        lease.server_ip
+                    )));
+                }
+            }
+        }
+
+        let res = self.process_frame_into(rx_buffer, tx_buffer, now);
+        match client {
+            Some((mac, DhcpMessageType::Release | DhcpMessageType::Decline)) => {
+                self.leases.release(&mac)
+            }
+            Some((mac, _)) if res.is_ok() => self.leases.claim(mac, self.server_ip, self.vlan, now),
+            _ => (),
+        }
+
+        let len = res?;
+        self.arp_cache.address(&mut tx_buffer[..len], now);
+        Ok(len)
+    }
+
+    fn process_frame_into(
+        &mut self,
+        rx_buffer: &[u8],
+        tx_buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        self.server_monitor
+            .observe(rx_buffer, &self.server_mac, now);
+
+        let is_arp = EthernetFrame::new_checked(rx_buffer)
+            .map_or(false, |ether| ether.ethertype() == EthernetProtocol::Arp);
+        if is_arp {
+            self.arp_cache.learn(rx_buffer, &self.server_mac, now);
+            if !self.arp {
+                return Err(Error::IgnoreNoLog(
+                    "The host answers arp for our address".to_string(),
+                ));
+            }
+            let packet = arp::arp_respond(rx_buffer, &self.server_ip, &self.server_mac)?;
+            return copy_frame(&packet, tx_buffer);
+        }
+
+        if self.icmp {
+            match icmp::icmp_respond(rx_buffer, &self.server_ip, &self.server_mac) {
+                Ok(packet) => return copy_frame(&packet, tx_buffer),
+                Err(Error::IgnoreNoLog(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Clients that already have an address may ask for the boot configuration
+        // with a DHCPINFORM at any time. Our PXE ack may also have been lost, or
+        // the client gave up on the transfer and starts over with a new discover.
+        // A client that releases or declines its address ends its transfer.
+        if let PxeStates::Tftp(_) = self.get_state() {
+            let transaction_id = self.dhcp_socket.get_transaction_id();
+            let res = self.dhcp_socket.process(rx_buffer);
+            if self.dhcp_socket.get_transaction_id() != transaction_id {
+                self.tftp_socket = None;
+                match (
+                    self.dhcp_socket.get_state(),
+                    self.dhcp_socket.get_firmware_type(),
+                ) {
+                    (DhcpStates::Done, Some(firmware)) => self.set_state(PxeStates::Tftp(firmware)),
+                    _ => self.set_state(PxeStates::Dhcp),
+                }
+            }
+            match res {
+                Ok(packet) => return copy_frame(&packet, tx_buffer),
+                Err(dhcp::error::Error::Ignore(e)) => return Err(Error::Ignore(e)),
+                Err(_) => (),
+            }
+        }
+
+        if self.back_off
+            && self.get_state() == &PxeStates::Dhcp
+            && matches!(self.dhcp_socket.get_state(), DhcpStates::Discover)
+        {
+            if let Some(server) = self.server_monitor.active_pxe_server(now) {
+                return Err(Error::Ignore(f!(
+                    "Backing off, PXE server {} is active on the network",
+                    server.ip
+                )));
+            }
+        }
+
         match self.get_state() {
             PxeStates::Dhcp => match self.dhcp_socket.process(rx_buffer) {
-                Ok(packet) => Ok(packet),
+                Ok(packet) => copy_frame(&packet, tx_buffer),
                 Err(dhcp::error::Error::DhcpProtocolFinished) => {
-                    self.set_state(PxeStates::Tftp(
-                        self.dhcp_socket.get_firmware_type().unwrap(),
-                    ));
-                    self.process(rx_buffer)
+                    let firmware = self.dhcp_socket.get_firmware_type().ok_or_else(|| {
+                        Error::Ignore("DHCP finished without a firmware type".to_string())
+                    })?;
+                    self.set_state(PxeStates::Tftp(firmware));
+                    self.process_frame_into(rx_buffer, tx_buffer, now)
                 }
 
                 Err(dhcp::error::Error::IgnoreNoLog(e)) => Err(Error::IgnoreNoLog(e)),
@@ -202,39 +451,34 @@ impl PxeSocket {
                 Err(dhcp::error::Error::WaitForDhcpAck) => Err(Error::Ignore(
                     "Waiting for DHCP Ack packet of router".to_string(),
                 )),
-                Err(e) => panic!("{}", e),
+                Err(e) => Err(e.into()),
             },
             PxeStates::Tftp(firmware_type) => {
-                if self.tftp_socket.is_none() {
-                    match self.dhcp_socket.get_firmware_type().unwrap() {
-                        dhcp::parse::FirmwareType::Intel => {
-                            self.tftp_socket = Some(TftpSocket::new(
-                                self.server_mac,
-                                self.server_ip,
-                                self.get_stage_one(),
-                                *firmware_type,
-                            ));
-                        }
-                        dhcp::parse::FirmwareType::IPxe => {
-                            self.tftp_socket = Some(TftpSocket::new(
-                                self.server_mac,
-                                self.server_ip,
-                                self.get_stage_two(),
-                                *firmware_type,
-                            ));
-                        }
-                    }
-                }
+                let firmware_type = *firmware_type;
+                let (server_mac, server_ip) = (self.server_mac, self.server_ip);
+                // iPXE chainloads the kernel, every other firmware gets the first stage
+                let file_path = match firmware_type {
+                    dhcp::parse::FirmwareType::IPxe => &self.stage_two,
+                    _ => &self.stage_one,
+                };
+                let files = self.files.clone();
+                let tftp_socket = self.tftp_socket.get_or_insert_with(|| {
+                    let mut tftp_socket =
+                        TftpSocket::new(server_mac, server_ip, file_path, firmware_type);
+                    tftp_socket.set_file_cache(files);
+                    tftp_socket
+                });
 
-                match self.tftp_socket.as_mut().unwrap().process(rx_buffer) {
+                match tftp_socket.process(rx_buffer, tx_buffer, now) {
                     Err(tftp::error::Error::TftpEndOfFile) => {
                         self.reset_state();
-                        self.process(rx_buffer)
+                        self.process_frame_into(rx_buffer, tx_buffer, now)
                     }
                     Err(tftp::error::Error::Ignore(e)) => Err(Error::Ignore(e)),
                     Err(tftp::error::Error::IgnoreNoLog(e)) => Err(Error::IgnoreNoLog(e)),
-                    Ok(packet) => Ok(packet),
-                    Err(e) => panic!("{}", e),
+                    Err(tftp::error::Error::Device(e)) => Err(Error::Device(e)),
+                    Ok(len) => Ok(len),
+                    Err(e) => Err(e.into()),
                 }
             }
         }
@@ -23,6 +23,7 @@ use smoltcp::phy::Device;
 use smoltcp::phy::Medium;
 use smoltcp::phy::RawSocket;
 use smoltcp::phy::RxToken;
+use smoltcp::phy::TunTapInterface;
 use smoltcp::phy::TxToken;
 use smoltcp::socket::dhcpv4;
 use smoltcp::time::Duration;
@@ -48,15 +49,27 @@ use smoltcp::{iface::Interface, phy::ChecksumCapabilities};
 use std::borrow::BorrowMut;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::str::FromStr;
 use uuid::Uuid;
 
+use rs_pxe::bpf;
+use rs_pxe::dhcp::extra_options::ExtraDhcpOption;
+use rs_pxe::dhcp::lease::Leases;
+use rs_pxe::diff::Ignore;
+use rs_pxe::record::Recording;
+use rs_pxe::replay;
+use rs_pxe::server::{run_all, PxeServer};
 use rs_pxe::tftp;
+use rs_pxe::tftp::cache::FileCache;
+use rs_pxe::vlan::VlanConfig;
 
+use crate::cli_opts::InterfaceConfig;
 use crate::dhcp::options::*;
 use prelude::*;
 use rs_pxe::*;
@@ -64,11 +77,14 @@ use std::net::Ipv4Addr;
 
 //RFC: https://datatracker.ietf.org/doc/html/rfc2132
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        run_replay();
+    }
+
     let (mut opts, mut _free) = cli_opts::create_options();
 
     let mut matches = cli_opts::parse_options(&opts, _free);
 
-    //TODO: Detect that interface has not set a static ip address
     let static_ip = matches
         .opt_str("ip")
         .map(|ip| Ipv4Cidr::from_str(&ip).expect("Invalid ipv4 cidr"));
@@ -78,169 +94,166 @@ fn main() {
         None => "INFO".to_owned(),
     };
 
-    let pxe_image = match matches.opt_str("ipxe") {
-        Some(image) => std::path::PathBuf::from_str(&image).expect("Invalid path to ipxe image"),
-        None => {
-            let path = std::env::var("IPXE_IMAGE")
-                .expect("IPXE_IMAGE env var not set. Or use --ipxe flag.");
-
-            std::path::PathBuf::from_str(&path).expect("Invalid path to ipxe image")
-        }
-    };
-
-    let kernel_image = match matches.opt_str("kernel") {
-        Some(image) => std::path::PathBuf::from_str(&image).expect("Invalid path to kernel image"),
-        None => {
-            let path = std::env::var("KERNEL_IMAGE")
-                .expect("KERNEL_IMAGE env var not set. Or use --kernel flag.");
-
-            std::path::PathBuf::from_str(&path).expect("Invalid path to kernel image")
+    let pxe_image = boot_image(&matches, "ipxe", "IPXE_IMAGE");
+    let kernel_image = boot_image(&matches, "kernel", "KERNEL_IMAGE");
+
+    let extra_dhcp_options: Vec<ExtraDhcpOption> = matches
+        .opt_strs("dhcp-option")
+        .iter()
+        .map(|opt| ExtraDhcpOption::from_str(opt).expect("Invalid dhcp option"))
+        .collect();
+
+    let vlans: Vec<VlanConfig> = matches
+        .opt_strs("vlan")
+        .iter()
+        .map(|vlan| VlanConfig::from_str(vlan).expect("Invalid vlan"))
+        .collect();
+
+    let mac = matches
+        .opt_str("mac")
+        .map(|mac_str| EthernetAddress::from_str(&mac_str).expect("Invalid MAC address"));
+
+    let back_off = matches.opt_present("back-off");
+
+    let record_path = matches.opt_str("record").map(std::path::PathBuf::from);
+
+    let interfaces: Vec<InterfaceConfig> = matches
+        .opt_strs("interface")
+        .iter()
+        .map(|interface| {
+            let mut config = InterfaceConfig::from_str(interface).expect("Invalid interface");
+            config.ip = config.ip.or(static_ip);
+            config.mac = config.mac.or(mac);
+            if config.vlans.is_empty() {
+                config.vlans = vlans.clone();
+            }
+            config.back_off |= back_off;
+            config
+        })
+        .collect();
+    if interfaces.is_empty() {
+        panic!("Interface not specified");
+    }
+    // The same server ip on several segments, e.g. from a global --ip, is ambiguous
+    let mut static_ips = HashSet::new();
+    for config in interfaces.iter() {
+        if let Some(ip) = config.ip {
+            if !static_ips.insert(ip.address()) {
+                panic!(
+                    "Ip {} is used on multiple interfaces. Use -i <name>,ip=<ip>/<prefix>",
+                    ip.address()
+                );
+            }
         }
-    };
+    }
+
+    // All interfaces read each boot file once and answer each client once
+    let files = FileCache::new();
+    let leases = Leases::new();
+    let new_socket =
+        |server_ip: Ipv4Address, server_mac: EthernetAddress, config: &InterfaceConfig| {
+            let mut pxe_socket = PxeSocket::new(
+                server_ip,
+                server_mac,
+                config.ipxe.as_deref().unwrap_or(pxe_image.as_path()),
+                config.kernel.as_deref().unwrap_or(kernel_image.as_path()),
+            );
+            pxe_socket.set_extra_dhcp_options(extra_dhcp_options.clone());
+            pxe_socket.set_back_off(config.back_off);
+            pxe_socket.set_file_cache(files.clone());
+            pxe_socket.set_leases(leases.clone());
+            pxe_socket
+        };
 
     let level_filter = LevelFilter::from_str(&v).unwrap();
     cli_opts::setup_logging(level_filter);
     info!("Starting pxe....");
 
-    let interface = matches
-        .opt_str("interface")
-        .expect("Interface not specified");
-
-    let hardware_addr = {
-        match matches.opt_str("mac") {
-            Some(mac_str) => EthernetAddress::from_str(&mac_str).expect("Invalid MAC address"),
-            None => {
-                let mac = mac_address::mac_address_by_name(&interface)
-                    .unwrap()
-                    .unwrap();
-                EthernetAddress::from_bytes(&mac.bytes())
-            }
-        }
-    };
-
     if matches.opt_present("raw") {
-        let mut device = match RawSocket::new(&interface, Medium::Ethernet) {
-            Ok(device) => device,
-            Err(e) => {
-                panic!("Failed to create raw socket: {}", e);
-            }
-        };
-
-        // Create interface
-        let mut config = match device.capabilities().medium {
-            Medium::Ethernet => Config::new(Into::into(hardware_addr)),
-            Medium::Ip => Config::new(wire::HardwareAddress::Ip),
-            Medium::Ieee802154 => todo!(),
-        };
-        config.random_seed = rand::random();
-
-        let mut iface = Interface::new(config, &mut device);
-
-        if let Some(ip) = static_ip {
-            iface.update_ip_addrs(|ip_addr| {
-                ip_addr.push(wire::IpCidr::Ipv4(ip)).unwrap();
-            });
-        } else {
-            utils::get_ip(&mut device, &mut iface);
-        }
-
-        // Get interface mac and ip
-        let server_mac = match iface.hardware_addr() {
-            HardwareAddress::Ethernet(addr) => addr,
-            _ => panic!("Currently we only support ethernet"),
-        };
-        let server_ip: Ipv4Address = iface.ipv4_addr().unwrap();
-
-        let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
-        let fd: i32 = device.as_raw_fd();
-        let mut last_time: Instant = Instant::now();
-
-        loop {
-            let timeout = Some(Duration::from_millis(250));
-            // Process timeout events in all state machines
-            match pxe_socket.process_timeout() {
-                Ok(packet) => {
-                    let mut tx = device.transmit(Instant::now()).unwrap();
-                    tx.consume(packet.len(), |buffer| {
-                        buffer.copy_from_slice(&packet);
-                        Ok::<(), Error>(())
-                    })
-                    .unwrap();
-                    continue;
+        let interfaces: Vec<InterfaceConfig> = interfaces
+            .iter()
+            .flat_map(|config| config.raw_interfaces())
+            .collect();
+        let mut servers: Vec<(PxeServer, RawSocket)> = interfaces
+            .iter()
+            .map(|config| {
+                let hardware_addr = config.mac.unwrap_or_else(|| {
+                    let mac = mac_address::mac_address_by_name(&config.name)
+                        .unwrap()
+                        .unwrap();
+                    EthernetAddress::from_bytes(&mac.bytes())
+                });
+
+                let mut device = match RawSocket::new(&config.name, Medium::Ethernet) {
+                    Ok(device) => device,
+                    Err(e) => {
+                        panic!("Failed to create raw socket on {}: {}", config.name, e);
+                    }
+                };
+
+                let host_ips = host_ipv4_addrs(&config.name);
+                let (server_ip, server_mac) =
+                    configure_interface(&mut device, &config.name, hardware_addr, config.ip);
+                let server = new_pxe_server(server_ip, server_mac, config, &host_ips, &new_socket);
+
+                // Only an optimization, everything still works without the filter
+                let server_ips: Vec<Ipv4Address> = server
+                    .get_sockets()
+                    .iter()
+                    .map(|socket| socket.get_server_ip())
+                    .collect();
+                let icmp = server.get_sockets().iter().any(|socket| socket.get_icmp());
+                match bpf::pxe_filter(&server_ips, icmp)
+                    .and_then(|filter| bpf::attach_filter(&device, &filter))
+                {
+                    Ok(()) => debug!("Attached bpf filter to {}", config.name),
+                    Err(e) => warn!("Failed to attach bpf filter to {}: {}", config.name, e),
                 }
-                Err(Error::StopTftpConnection(packet)) => {
-                    let mut tx = device.transmit(Instant::now()).unwrap();
-                    debug!("Sending Tftp Error");
-                    tx.consume(packet.len(), |buffer| {
-                        buffer.copy_from_slice(&packet);
-                        Ok::<(), Error>(())
-                    })
-                    .unwrap();
-                    continue;
-                }
-                Err(Error::Ignore(_) | Error::IgnoreNoLog(_)) => (),
-                Err(e) => panic!("{}", e),
-            }
+                (server, device)
+            })
+            .collect();
 
-            // Wait for socket to be ready to be read or timeout and continue
-            phy_wait(fd, timeout).unwrap();
-
-            // Receive packet and create two tokens for reading (rx) and writing (tx)
-            let (rx, tx) = match device.receive(Instant::now()) {
-                Some(res) => res,
-                None => {
-                    let diff = Instant::now() - last_time;
-                    last_time = Instant::now();
-                    continue;
-                }
-            };
-
-            // Consume the received packet and process it with the meta state machine
-            let packet = rx.consume(|buffer| pxe_socket.process(buffer));
-
-            // If the meta state machine returns a packet, send it
-            // else ignore the packet or panic if an error occurs
-            match packet {
-                Ok(packet) => {
-                    tx.consume(packet.len(), |buffer| {
-                        buffer.copy_from_slice(&packet);
-                        Ok::<(), Error>(())
-                    })
-                    .unwrap();
-                }
-                Err(Error::Ignore(e)) => {
-                    debug!("Ignoring Packet. Reason: {:?}", e);
-                }
-                Err(Error::IgnoreNoLog(e)) => {
-                    trace!("Ignoring Packet. Reason: {:?}", e);
-                }
-                Err(e) => {
-                    panic!("{:?}", e);
-                }
-            }
+        if let Some(path) = &record_path {
+            record(&mut servers, &interfaces, path);
         }
+        serve(&mut servers);
     } else if matches.opt_present("tap") {
-        let mut device = smoltcp::phy::TunTapInterface::new(&interface, Medium::Ethernet).unwrap();
-
-        // Create interface
-        let mut config = match device.capabilities().medium {
-            Medium::Ethernet => Config::new(Into::into(hardware_addr)),
-            Medium::Ip => panic!("Tap interface does not support IP"),
-            Medium::Ieee802154 => todo!(),
-        };
-        config.random_seed = rand::random();
-        let mut iface = Interface::new(config, &mut device);
-
-        utils::get_ip(&mut device, &mut iface);
-
-        // Get interface mac and ip
-        let server_mac = match iface.hardware_addr() {
-            HardwareAddress::Ethernet(addr) => addr,
-            _ => panic!("Currently we only support ethernet"),
-        };
-        let server_ip: Ipv4Address = iface.ipv4_addr().unwrap();
-
-        todo!();
+        let mut servers: Vec<(PxeServer, TunTapInterface)> = interfaces
+            .iter()
+            .map(|config| {
+                // The address of the tap interface belongs to the host side, taking it
+                // would answer ARP for the host with our own mac
+                let ip = config.ip.unwrap_or_else(|| {
+                    panic!("--tap requires --ip for interface {}", config.name);
+                });
+
+                let mut device = match TunTapInterface::new(&config.name, Medium::Ethernet) {
+                    Ok(device) => device,
+                    Err(e) => {
+                        panic!("Failed to open tap interface {}: {}", config.name, e);
+                    }
+                };
+
+                // The host side of the tap owns the interface mac. Use our own
+                // locally administered address unless one was given.
+                let hardware_addr = config.mac.unwrap_or_else(|| {
+                    let mut mac: [u8; 6] = rand::random();
+                    mac[0] = (mac[0] & 0xfe) | 0x02;
+                    EthernetAddress(mac)
+                });
+
+                let host_ips = host_ipv4_addrs(&config.name);
+                let (server_ip, server_mac) =
+                    configure_interface(&mut device, &config.name, hardware_addr, Some(ip));
+                let server = new_pxe_server(server_ip, server_mac, config, &host_ips, &new_socket);
+                (server, device)
+            })
+            .collect();
+
+        if let Some(path) = &record_path {
+            record(&mut servers, &interfaces, path);
+        }
+        serve(&mut servers);
     } else if matches.opt_present("tun") {
         // let mut device = smoltcp::phy::TunTapInterface::new(&interface, Medium::Ip).unwrap();
 
@@ -264,3 +277,203 @@ fn main() {
         panic!("{}", brief);
     };
 }
+
+/// Returns the path of the `--ipxe` or `--kernel` image, falling back to the `env_var`
+fn boot_image(matches: &getopts::Matches, opt: &str, env_var: &str) -> std::path::PathBuf {
+    let path = match matches.opt_str(opt) {
+        Some(path) => path,
+        None => std::env::var(env_var)
+            .unwrap_or_else(|_| panic!("{} env var not set. Or use --{} flag.", env_var, opt)),
+    };
+    std::path::PathBuf::from_str(&path).unwrap_or_else(|_| panic!("Invalid path to {} image", opt))
+}
+
+/// Replays the client frames of a capture and compares our replies with the captured ones.
+/// Exits with 1 if they differ.
+fn run_replay() -> ! {
+    let (opts, free) = cli_opts::create_replay_options();
+    let matches = cli_opts::parse_options(&opts, free);
+
+    let level = matches
+        .opt_str("level")
+        .unwrap_or_else(|| "INFO".to_owned());
+    cli_opts::setup_logging(LevelFilter::from_str(&level).expect("Invalid debug level"));
+
+    let capture = match matches.free.get(1) {
+        Some(capture) => std::path::PathBuf::from(capture),
+        None => {
+            print!(
+                "{}",
+                opts.usage("Usage: rs_pxe replay [OPTION]... <capture.pcapng>")
+            );
+            std::process::exit(1);
+        }
+    };
+    let server_mac = matches
+        .opt_str("server-mac")
+        .map(|mac| EthernetAddress::from_str(&mac).expect("Invalid MAC address"))
+        .expect("--server-mac not specified");
+    let server_ip = matches
+        .opt_str("server-ip")
+        .map(|ip| Ipv4Address::from_str(&ip).expect("Invalid ip address"))
+        .expect("--server-ip not specified");
+    let out = matches
+        .opt_str("out")
+        .unwrap_or_else(|| "./target".to_owned());
+
+    let pxe_image = boot_image(&matches, "ipxe", "IPXE_IMAGE");
+    let kernel_image = boot_image(&matches, "kernel", "KERNEL_IMAGE");
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    pxe_socket.set_extra_dhcp_options(
+        matches
+            .opt_strs("dhcp-option")
+            .iter()
+            .map(|opt| ExtraDhcpOption::from_str(opt).expect("Invalid dhcp option"))
+            .collect(),
+    );
+    if let Some(vlan) = matches.opt_str("vlan") {
+        pxe_socket.set_vlan(Some(vlan.parse().expect("Invalid vlan id")));
+    }
+
+    let res = replay::cmp_impl_responses(&mut pxe_socket, &capture, |e| warn!("Error: {}", e))
+        .expect("Failed to replay capture");
+    let ignore = if matches.opt_present("ignore-volatile") {
+        Ignore::VOLATILE
+    } else {
+        Ignore::default()
+    };
+    let diff = res.diff(ignore);
+    for reply in diff.iter() {
+        warn!("{}:", reply);
+        for field in reply.fields.iter() {
+            warn!("    {}", field);
+        }
+    }
+
+    let out = Path::new(&out);
+    res.write_pcaps(out).expect("Failed to write pcaps");
+    info!(
+        "Replayed {}: {} of {} responses differ, we sent {}. See {} and {}",
+        capture.display(),
+        diff.len(),
+        res.wanted.len(),
+        res.got.len(),
+        out.join("got.pcapng").display(),
+        out.join("wanted.pcapng").display()
+    );
+    std::process::exit(if diff.is_empty() { 0 } else { 1 })
+}
+
+/// Returns the ip and mac address to serve `device` with.
+/// Without `static_ip` the address of the interface `name` or a DHCP lease is used.
+fn configure_interface<D>(
+    device: &mut D,
+    name: &str,
+    hardware_addr: EthernetAddress,
+    static_ip: Option<Ipv4Cidr>,
+) -> (Ipv4Address, EthernetAddress)
+where
+    D: Device + AsRawFd,
+{
+    // Create interface
+    let mut config = match device.capabilities().medium {
+        Medium::Ethernet => Config::new(Into::into(hardware_addr)),
+        Medium::Ip => panic!("Currently we only support ethernet"),
+        Medium::Ieee802154 => todo!(),
+    };
+    config.random_seed = rand::random();
+    let mut iface = Interface::new(config, device);
+
+    if let Some(ip) = static_ip {
+        iface.update_ip_addrs(|ip_addr| {
+            ip_addr.push(wire::IpCidr::Ipv4(ip)).unwrap();
+        });
+    } else {
+        utils::get_ip(device, &mut iface, name).expect("Failed to get ip address");
+    }
+
+    // Get interface mac and ip
+    let server_mac = match iface.hardware_addr() {
+        HardwareAddress::Ethernet(addr) => addr,
+        _ => panic!("Currently we only support ethernet"),
+    };
+    let server_ip: Ipv4Address = iface.ipv4_addr().unwrap();
+    (server_ip, server_mac)
+}
+
+/// Addresses the host stack owns on `interface`, it answers ICMP for them itself
+fn host_ipv4_addrs(interface: &str) -> Vec<Ipv4Address> {
+    utils::interface_ipv4_addrs(interface)
+        .unwrap_or_else(|e| panic!("Failed to read addresses of {}: {}", interface, e))
+        .iter()
+        .map(|cidr| cidr.address())
+        .collect()
+}
+
+/// Creates a socket per VLAN, or a single one for untagged frames without `--vlan`.
+/// ARP and ICMP are only answered for addresses that are not in `host_ips`.
+fn new_pxe_server(
+    server_ip: Ipv4Address,
+    server_mac: EthernetAddress,
+    config: &InterfaceConfig,
+    host_ips: &[Ipv4Address],
+    new_socket: impl Fn(Ipv4Address, EthernetAddress, &InterfaceConfig) -> PxeSocket,
+) -> PxeServer {
+    info!(
+        "Serving interface {} with ip {} and mac {}",
+        config.name, server_ip, server_mac
+    );
+    if config.vlans.is_empty() {
+        let mut pxe_socket = new_socket(server_ip, server_mac, config);
+        pxe_socket.set_arp(!host_ips.contains(&server_ip));
+        pxe_socket.set_icmp(!host_ips.contains(&server_ip));
+        return PxeServer::new(pxe_socket);
+    }
+
+    let mut sockets = config.vlans.iter().map(|vlan| {
+        let ip = vlan.ip.map_or(server_ip, |cidr| cidr.address());
+        info!("Serving vlan {} with ip {}", vlan.id, ip);
+        let mut pxe_socket = new_socket(ip, server_mac, &config.vlan_config(vlan));
+        pxe_socket.set_vlan(Some(vlan.id));
+        pxe_socket.set_arp(!host_ips.contains(&ip));
+        pxe_socket.set_icmp(!host_ips.contains(&ip));
+        pxe_socket
+    });
+
+    let mut server = PxeServer::new(sockets.next().unwrap());
+    for pxe_socket in sockets {
+        server.add_socket(pxe_socket).expect("Duplicate vlan");
+    }
+    server
+}
+
+/// Records the frames of all interfaces to the pcapng file at `path`
+fn record<D>(servers: &mut [(PxeServer, D)], interfaces: &[InterfaceConfig], path: &Path) {
+    let mut recording = Recording::create(path).expect("Failed to create recording");
+    for ((server, _), config) in servers.iter_mut().zip(interfaces) {
+        let socket = server.get_socket();
+        let recorder = recording
+            .add_interface(
+                &config.name,
+                socket.get_server_mac(),
+                socket.get_server_ip(),
+            )
+            .expect("Failed to write interface description");
+        server.set_recorder(recorder);
+    }
+    info!("Recording frames to {}", path.display());
+}
+
+fn serve<D>(servers: &mut [(PxeServer, D)])
+where
+    D: Device + AsRawFd,
+{
+    if let Err(e) = run_all(servers) {
+        error!("Stopping PXE server: {}", e);
+        // exit does not run destructors, write out the rest of the recording
+        for (server, _) in servers.iter_mut() {
+            server.flush_recorder();
+        }
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,201 @@
+//! Records live sessions to pcapng files.
+//!
+//! A recording holds every frame received and sent on the served interfaces, in
+//! the format `cmp_impl_responses` replays. A boot that fails on new hardware can
+//! be recorded in the field and turned into a regression test.
+//!
+//! Frames are written as they are on the wire, VLAN tags included. Received frames
+//! are flagged inbound and sent frames outbound. Frames the server sent because a
+//! timeout expired, like ARP announcements and TFTP retransmissions, carry the
+//! comment [`TIMEOUT_COMMENT`] as they are not the reply to a received frame.
+//!
+//! Frames are buffered, the server flushes the recording whenever it is idle.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::{
+    InterfaceDescriptionBlock, InterfaceDescriptionOption,
+};
+use pcap_file::pcapng::{PcapNgBlock, PcapNgWriter};
+use pcap_file::DataLink;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, EthernetFrame, Ipv4Address};
+
+use crate::prelude::*;
+use crate::MAX_FRAME_LEN;
+
+/// Comment of frames that were sent because a timeout expired
+pub const TIMEOUT_COMMENT: &str = "sent on timeout";
+
+/// Direction bits of the enhanced packet flags
+const FLAG_INBOUND: u32 = 0b01;
+const FLAG_OUTBOUND: u32 = 0b10;
+
+/// Timestamps of enhanced packet blocks are written in nanoseconds
+const TS_RESOLUTION_NANOS: u8 = 9;
+
+/// A pcapng file shared by the recorders of all interfaces
+pub struct Recording {
+    writer: Rc<RefCell<PcapNgWriter<BufWriter<File>>>>,
+    interfaces: u32,
+}
+
+impl Recording {
+    /// Creates the file at `path`, an existing file is overwritten
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)?;
+        let writer = PcapNgWriter::new(BufWriter::new(file))?;
+        Ok(Self {
+            writer: Rc::new(RefCell::new(writer)),
+            interfaces: 0,
+        })
+    }
+
+    /// Writes the description of an interface. Returns the recorder for its frames.
+    pub fn add_interface(
+        &mut self,
+        name: &str,
+        server_mac: EthernetAddress,
+        server_ip: Ipv4Address,
+    ) -> Result<Recorder> {
+        let interface = InterfaceDescriptionBlock {
+            linktype: DataLink::ETHERNET,
+            snaplen: MAX_FRAME_LEN as u32,
+            options: vec![
+                InterfaceDescriptionOption::IfName(name.into()),
+                InterfaceDescriptionOption::IfDescription(
+                    f!("rs_pxe serving {}", server_ip).into(),
+                ),
+                InterfaceDescriptionOption::IfMacAddr(server_mac.as_bytes().into()),
+                InterfaceDescriptionOption::IfTsResol(TS_RESOLUTION_NANOS),
+            ],
+        };
+        self.writer
+            .borrow_mut()
+            .write_block(&interface.into_block())?;
+
+        let recorder = Recorder {
+            writer: self.writer.clone(),
+            interface_id: self.interfaces,
+            server_mac,
+        };
+        self.interfaces += 1;
+        Ok(recorder)
+    }
+}
+
+/// Writes the frames of one interface to a [`Recording`]
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Rc<RefCell<PcapNgWriter<BufWriter<File>>>>,
+    interface_id: u32,
+    server_mac: EthernetAddress,
+}
+
+impl Recorder {
+    pub fn received(&self, frame: &[u8], now: Instant) -> Result<()> {
+        // Raw sockets also receive the frames we send, they are recorded when sent
+        let is_ours = EthernetFrame::new_checked(frame)
+            .map_or(false, |ether| ether.src_addr() == self.server_mac);
+        if is_ours {
+            return Ok(());
+        }
+        self.write(frame, now, FLAG_INBOUND, None)
+    }
+
+    pub fn sent(&self, frame: &[u8], now: Instant) -> Result<()> {
+        self.write(frame, now, FLAG_OUTBOUND, None)
+    }
+
+    pub fn sent_on_timeout(&self, frame: &[u8], now: Instant) -> Result<()> {
+        self.write(frame, now, FLAG_OUTBOUND, Some(TIMEOUT_COMMENT))
+    }
+
+    /// Writes the buffered frames of all interfaces to the file
+    pub fn flush(&self) -> Result<()> {
+        self.writer.borrow_mut().get_mut().flush()?;
+        Ok(())
+    }
+
+    fn write(&self, frame: &[u8], now: Instant, flags: u32, comment: Option<&str>) -> Result<()> {
+        let mut options = vec![EnhancedPacketOption::Flags(flags)];
+        if let Some(comment) = comment {
+            options.push(EnhancedPacketOption::Comment(comment.into()));
+        }
+
+        let packet = EnhancedPacketBlock {
+            interface_id: self.interface_id,
+            timestamp: std::time::Duration::from_micros(now.total_micros() as u64),
+            original_len: frame.len() as u32,
+            data: frame.into(),
+            options,
+        };
+        self.writer.borrow_mut().write_block(&packet.into_block())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::{cmp_impl_responses, read_frames, verify_responses};
+    use crate::utils::build_arp_announce;
+    use crate::PxeSocket;
+
+    #[test]
+    fn test_recording_replays() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let new_socket = || {
+            PxeSocket::new(
+                server_ip,
+                server_mac,
+                Path::new("./assets/ipxe.pxe"),
+                Path::new("./assets/kernel.elf"),
+            )
+        };
+        let path = Path::new("./target/test_recording_replays.pcapng");
+
+        // Record a DHCP handshake like a live session, our own frames are looped back
+        let mut recording = Recording::create(path).unwrap();
+        let recorder = recording
+            .add_interface("eth0", server_mac, server_ip)
+            .unwrap();
+        let now = Instant::from_secs(1);
+        let announce = build_arp_announce(server_mac, server_ip);
+        recorder.sent_on_timeout(&announce, now).unwrap();
+        recorder.received(&announce, now).unwrap();
+
+        let mut pxe_socket = new_socket();
+        let mut sent = vec![announce];
+        for frame in read_frames(Path::new("./assets/intel_bios_dhcp.pcapng")) {
+            if EthernetFrame::new_checked(&frame[..]).unwrap().src_addr() == server_mac {
+                continue;
+            }
+            recorder.received(&frame, now).unwrap();
+            if let Ok(reply) = pxe_socket.process(&frame, now) {
+                recorder.sent(&reply, now).unwrap();
+                recorder.received(&reply, now).unwrap();
+                sent.push(reply);
+            }
+        }
+        drop(recorder);
+        drop(recording);
+
+        // Looped back frames are not recorded twice
+        let frames = read_frames(path);
+        for frame in sent.iter() {
+            assert_eq!(frames.iter().filter(|f| *f == frame).count(), 1);
+        }
+
+        // The timeout frame is no reply, a fresh socket answers like the recorded one
+        let res = cmp_impl_responses(&mut new_socket(), path, |e| panic!("{}", e));
+        verify_responses(&res);
+        assert_eq!(res.got, sent[1..]);
+    }
+}
@@ -0,0 +1,276 @@
+//! Offline replay of captured sessions.
+//!
+//! The client frames of a capture are fed through a [`PxeSocket`] and our replies
+//! are compared with the frames the captured server sent. The capture can come from
+//! any PXE server, e.g. `dnsmasq` on the same network, or be a recording of our own.
+
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use log::*;
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+use pcap_file::pcapng::{PcapNgBlock, PcapNgReader, PcapNgWriter};
+use pcap_file::DataLink;
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetFrame;
+
+use crate::diff::{align, diff_frames, FieldDiff, Ignore};
+use crate::prelude::*;
+use crate::record::TIMEOUT_COMMENT;
+use crate::vlan::{self, VLAN_TAG_LEN};
+use crate::{PxeSocket, MAX_FRAME_LEN};
+
+/// The replies of the captured server and ours, in the order they were sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Responses {
+    pub wanted: Vec<Vec<u8>>,
+    pub got: Vec<Vec<u8>>,
+}
+
+/// A reply of the captured server and ours to the same request that are not equal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyDiff {
+    /// Index in `Responses::wanted`, `None` if only we sent the reply
+    pub wanted: Option<usize>,
+    /// Index in `Responses::got`, `None` if we did not send the reply
+    pub got: Option<usize>,
+    pub fields: Vec<FieldDiff>,
+}
+
+impl Display for ReplyDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.wanted, self.got) {
+            (Some(wanted), Some(got)) => write!(f, "Response {} differs from ours {}", wanted, got),
+            (Some(wanted), None) => write!(f, "Response {} is missing", wanted),
+            (None, Some(got)) => write!(f, "We sent the extra response {}", got),
+            (None, None) => write!(f, "No response"),
+        }
+    }
+}
+
+impl Responses {
+    /// Returns every reply that is not equal to the one answering the same request.
+    /// Replies only one side sent are compared with an empty frame.
+    pub fn diff(&self, ignore: Ignore) -> Vec<ReplyDiff> {
+        align(&self.wanted, &self.got)
+            .into_iter()
+            .filter_map(|(wanted, got)| {
+                let fields = diff_frames(
+                    frame_at(&self.wanted, wanted),
+                    frame_at(&self.got, got),
+                    ignore,
+                );
+                (!fields.is_empty()).then_some(ReplyDiff {
+                    wanted,
+                    got,
+                    fields,
+                })
+            })
+            .collect()
+    }
+
+    /// Writes `got.pcapng` and `wanted.pcapng` to `dir`
+    pub fn write_pcaps(&self, dir: &Path) -> Result<()> {
+        write_frames(&self.got, &dir.join("got.pcapng"))?;
+        write_frames(&self.wanted, &dir.join("wanted.pcapng"))
+    }
+}
+
+fn frame_at(frames: &[Vec<u8>], idx: Option<usize>) -> &[u8] {
+    idx.map_or(&[], |idx| &frames[idx])
+}
+
+/// Feeds the client frames of the capture at `pcap_path` through `pxe_socket`.
+/// Errors other than ignored frames are passed to `handle_error`.
+/// If `pxe_socket` serves a VLAN only frames tagged with it are replayed, untagged.
+pub fn cmp_impl_responses(
+    pxe_socket: &mut PxeSocket,
+    pcap_path: &Path,
+    handle_error: impl Fn(Error),
+) -> Result<Responses> {
+    let file_in = File::open(pcap_path)?;
+    let mut pcapng_reader = PcapNgReader::new(file_in)?;
+
+    let mut orig_send: Vec<Vec<u8>> = vec![];
+    let mut impl_send: Vec<Vec<u8>> = vec![];
+
+    while let Some(block) = pcapng_reader.next_block() {
+        let block = block?;
+
+        // Get the data from the block
+        let block = match block.clone().into_enhanced_packet() {
+            Some(block) => block,
+            None => {
+                trace!("Not an enhanced packet block: {:?}", block);
+                continue;
+            }
+        };
+
+        let mut data = block.data.to_vec();
+        let data = match (
+            vlan::strip_vlan_tag_in_place(&mut data),
+            pxe_socket.get_vlan(),
+        ) {
+            (Some(id), Some(vlan)) if id == vlan => &data[VLAN_TAG_LEN..],
+            // Priority tagged frames belong to the native VLAN
+            (Some(0), None) => &data[VLAN_TAG_LEN..],
+            (None, None) => &data[..],
+            (id, _) => {
+                trace!("Skipping frame of vlan {:?}", id);
+                continue;
+            }
+        };
+
+        let ether = match EthernetFrame::new_checked(data) {
+            Ok(ether) => ether,
+            Err(e) => {
+                debug!("Skipping frame that is not ethernet: {}", e);
+                continue;
+            }
+        };
+
+        // Check if the packet is from the server and ignore it
+        if ether.src_addr() == pxe_socket.get_server_mac() {
+            // Recorded announcements and retransmissions do not answer a frame
+            let on_timeout = block
+                .options
+                .iter()
+                .any(|opt| matches!(opt, EnhancedPacketOption::Comment(c) if c == TIMEOUT_COMMENT));
+            if !on_timeout {
+                orig_send.push(data.to_vec());
+            }
+            continue;
+        }
+
+        // Process the packet
+        let response = pxe_socket.process(data, Instant::from_secs(0));
+        match response {
+            Ok(resp) => impl_send.push(resp.to_vec()),
+            Err(Error::IgnoreNoLog(e)) => trace!("IgnoreNoLog: {}", e),
+            Err(Error::Ignore(e)) => debug!("Ignore: {}", e),
+            Err(e) => handle_error(e),
+        }
+    }
+
+    Ok(Responses {
+        wanted: orig_send,
+        got: impl_send,
+    })
+}
+
+/// Writes ethernet frames to a pcapng file
+pub fn write_frames(data: &[Vec<u8>], file_path: &Path) -> Result<()> {
+    let interface = InterfaceDescriptionBlock {
+        linktype: DataLink::ETHERNET,
+        snaplen: MAX_FRAME_LEN as u32,
+        options: vec![],
+    };
+
+    let file = File::create(file_path)?;
+    let mut pcap_ng_writer = PcapNgWriter::new(file)?;
+    pcap_ng_writer.write_block(&interface.into_block())?;
+    for data in data {
+        let packet = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp: Duration::from_secs(0),
+            original_len: data.len() as u32,
+            data: Cow::Borrowed(data),
+            options: vec![],
+        };
+        pcap_ng_writer.write_block(&packet.into_block())?;
+    }
+    Ok(())
+}
+
+/// Returns all ethernet frames of a pcapng file
+pub fn read_frames(pcap_path: &Path) -> Result<Vec<Vec<u8>>> {
+    let file_in = File::open(pcap_path)?;
+    let mut pcapng_reader = PcapNgReader::new(file_in)?;
+
+    let mut frames = vec![];
+    while let Some(block) = pcapng_reader.next_block() {
+        if let Some(block) = block?.into_enhanced_packet() {
+            frames.push(block.data.to_vec());
+        }
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::insert_vlan_tag;
+    use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+    fn pxe_socket() -> PxeSocket {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        )
+    }
+
+    #[test]
+    fn test_replay_diff() {
+        let capture = Path::new("./assets/intel_bios_dhcp.pcapng");
+        let mut res = cmp_impl_responses(&mut pxe_socket(), capture, |e| panic!("{}", e)).unwrap();
+        // Offer and ack
+        assert_eq!(res.wanted.len(), 2);
+        assert!(res.diff(Ignore::default()).is_empty());
+
+        // Without our offer the ack is still compared with the captured ack
+        let offer = res.got.remove(0);
+        let diff = res.diff(Ignore::default());
+        assert_eq!(diff.len(), 1);
+        assert_eq!((diff[0].wanted, diff[0].got), (Some(0), None));
+        assert_eq!(diff[0].fields[0].wanted, offer.len().to_string());
+
+        // A changed reply and one only we sent
+        res.got.insert(0, offer.clone());
+        res.got[1][0] ^= 0xff;
+        res.got.push(offer);
+        let diff = res.diff(Ignore::default());
+        assert_eq!(diff.len(), 2);
+        assert_eq!((diff[0].wanted, diff[0].got), (Some(1), Some(1)));
+        assert_eq!(diff[0].fields[0].field, "ethernet dst");
+        assert_eq!((diff[1].wanted, diff[1].got), (None, Some(2)));
+    }
+
+    #[test]
+    fn test_replay_vlan() {
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng")).unwrap();
+        let tagged: Vec<Vec<u8>> = frames
+            .iter()
+            .map(|frame| insert_vlan_tag(frame, 100))
+            .collect();
+        let capture = Path::new("./target/test_replay_vlan.pcapng");
+        write_frames(&tagged, capture).unwrap();
+
+        let mut socket = pxe_socket();
+        socket.set_vlan(Some(100));
+        let res = cmp_impl_responses(&mut socket, capture, |e| panic!("{}", e)).unwrap();
+        assert!(!res.wanted.is_empty());
+        assert!(res.diff(Ignore::default()).is_empty());
+
+        // Frames of other VLANs are not replayed
+        let mut socket = pxe_socket();
+        socket.set_vlan(Some(200));
+        let res = cmp_impl_responses(&mut socket, capture, |e| panic!("{}", e)).unwrap();
+        assert_eq!(res, Responses::default());
+    }
+
+    #[test]
+    fn test_write_frames() {
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng")).unwrap();
+        let path = Path::new("./target/test_write_frames.pcapng");
+        write_frames(&frames, path).unwrap();
+        assert_eq!(read_frames(path).unwrap(), frames);
+    }
+}
@@ -0,0 +1,592 @@
+//! Device agnostic driver for the [`PxeSocket`] state machine.
+//!
+//! The driver pulls frames out of any smoltcp [`Device`], feeds them to the
+//! state machine and transmits the replies. Raw sockets, tap devices and in
+//! memory devices like [`smoltcp::phy::Loopback`] can all be driven the same way.
+//!
+//! On a trunk port one server drives a socket per VLAN, see [`crate::vlan`].
+//! [`run_all`] multiplexes the servers of several interfaces in one loop.
+
+use std::fmt;
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use log::*;
+use smoltcp::phy::wait as phy_wait;
+use smoltcp::phy::{Device, RxToken, TxToken};
+use smoltcp::time::{Duration, Instant};
+
+use crate::prelude::*;
+use crate::record::Recorder;
+use crate::vlan::{self, VLAN_TAG_LEN};
+use crate::{PxeSocket, MAX_FRAME_LEN};
+
+/// How long `run` blocks waiting for a frame before it checks for timeouts again
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct PxeServer {
+    /// One socket per served VLAN, `None` serves untagged frames
+    sockets: Vec<PxeSocket>,
+    /// Replies are built here, with room to insert a VLAN tag in front
+    tx_buffer: Vec<u8>,
+    recorder: Option<Recorder>,
+}
+
+impl fmt::Debug for PxeServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PxeServer")
+            .field("sockets", &self.sockets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PxeServer {
+    pub fn new(socket: PxeSocket) -> Self {
+        Self {
+            sockets: vec![socket],
+            tx_buffer: vec![0; VLAN_TAG_LEN + MAX_FRAME_LEN],
+            recorder: None,
+        }
+    }
+
+    /// Writes every received and sent frame to `recorder`.
+    /// Recording stops at the first failed write, serving goes on.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Writes the buffered frames of the recording to its file
+    pub fn flush_recorder(&mut self) {
+        record(&mut self.recorder, |recorder| recorder.flush());
+    }
+
+    /// Serves another VLAN with its own address and boot files.
+    /// Fails if a socket for the same VLAN already exists.
+    pub fn add_socket(&mut self, socket: PxeSocket) -> Result<()> {
+        if self.get_socket_by_vlan(socket.get_vlan()).is_some() {
+            return Err(Error::Config(f!(
+                "Vlan {:?} is already served",
+                socket.get_vlan()
+            )));
+        }
+        self.sockets.push(socket);
+        Ok(())
+    }
+
+    /// Returns the socket passed to `new`
+    pub fn get_socket(&self) -> &PxeSocket {
+        &self.sockets[0]
+    }
+
+    pub fn get_socket_mut(&mut self) -> &mut PxeSocket {
+        &mut self.sockets[0]
+    }
+
+    pub fn get_sockets(&self) -> &[PxeSocket] {
+        &self.sockets
+    }
+
+    pub fn get_socket_by_vlan(&self, vlan: Option<u16>) -> Option<&PxeSocket> {
+        self.sockets.iter().find(|socket| socket.get_vlan() == vlan)
+    }
+
+    /// Handles expired timeouts and at most one received frame.
+    /// Returns `true` if a frame was received or sent, `false` if there was nothing to do.
+    pub fn poll<D>(&mut self, device: &mut D, now: Instant) -> Result<bool>
+    where
+        D: Device + ?Sized,
+    {
+        // Process timeout events in all state machines
+        for socket in self.sockets.iter_mut() {
+            match socket.process_timeout_into(&mut self.tx_buffer[VLAN_TAG_LEN..], now) {
+                Ok(len) => {
+                    let frame = tag(&mut self.tx_buffer, len, socket.get_vlan());
+                    let frame = &self.tx_buffer[frame];
+                    record(&mut self.recorder, |recorder| {
+                        recorder.sent_on_timeout(frame, now)
+                    });
+                    transmit(device, now, frame)?;
+                    return Ok(true);
+                }
+                Err(Error::Ignore(_) | Error::IgnoreNoLog(_)) => (),
+                Err(e @ (Error::DhcpError(_) | Error::TftpError(_))) => {
+                    warn!("Timeout handling failed: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Receive packet and create two tokens for reading (rx) and writing (tx)
+        let (rx, tx) = match device.receive(now) {
+            Some(res) => res,
+            None => return Ok(false),
+        };
+
+        // Consume the received packet and process it with the meta state machine
+        let res = rx.consume(|buffer| {
+            record(&mut self.recorder, |recorder| {
+                recorder.received(buffer, now)
+            });
+            process(&mut self.sockets, buffer, &mut self.tx_buffer, now)
+        });
+        match res {
+            Ok(frame) => {
+                let frame = &self.tx_buffer[frame];
+                record(&mut self.recorder, |recorder| recorder.sent(frame, now));
+                tx.consume(frame.len(), |buffer| buffer.copy_from_slice(frame));
+            }
+            Err(Error::Ignore(e)) => {
+                debug!("Ignoring Packet. Reason: {:?}", e);
+            }
+            Err(Error::IgnoreNoLog(e)) => {
+                trace!("Ignoring Packet. Reason: {:?}", e);
+            }
+            // A misbehaving client must not take down the server, drop its packet instead
+            Err(e @ (Error::DhcpError(_) | Error::TftpError(_))) => {
+                warn!("Dropping Packet. Reason: {}", e);
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(true)
+    }
+
+    /// Serves PXE clients on `device` until an unrecoverable error occurs
+    pub fn run<D>(&mut self, device: &mut D) -> Result<()>
+    where
+        D: Device + AsRawFd,
+    {
+        let fd = device.as_raw_fd();
+
+        loop {
+            if self.poll(device, Instant::now())? {
+                continue;
+            }
+            self.flush_recorder();
+
+            // Wait for socket to be ready to be read or timeout and continue
+            phy_wait(fd, Some(POLL_INTERVAL))?;
+        }
+    }
+}
+
+/// Serves PXE clients on several devices from one loop until every device failed.
+/// Every device is polled once per round, so a busy interface can not starve the others.
+/// An error stops only the interface it occurred on.
+///
+/// Each server keeps its own DHCP and TFTP state. Sockets that were given the same
+/// [`FileCache`](crate::tftp::cache::FileCache) and [`Leases`](crate::dhcp::lease::Leases)
+/// read each boot file once and do not answer a client another socket serves.
+pub fn run_all<D>(servers: &mut [(PxeServer, D)]) -> Result<()>
+where
+    D: Device + AsRawFd,
+{
+    let mut failed = vec![false; servers.len()];
+
+    loop {
+        let mut busy = false;
+        for ((server, device), failed) in servers.iter_mut().zip(failed.iter_mut()) {
+            if *failed {
+                continue;
+            }
+            match server.poll(device, Instant::now()) {
+                Ok(res) => busy |= res,
+                Err(e) => {
+                    error!(
+                        "Stopped serving {}: {}",
+                        server.get_socket().get_server_ip(),
+                        e
+                    );
+                    *failed = true;
+                }
+            }
+        }
+        if failed.iter().all(|failed| *failed) {
+            return Err(Error::Device(
+                "Serving failed on every interface".to_string(),
+            ));
+        }
+        if busy {
+            continue;
+        }
+        for (server, _) in servers.iter_mut() {
+            server.flush_recorder();
+        }
+
+        // Wait for any socket to be ready to be read or timeout and continue
+        let fds: Vec<RawFd> = servers
+            .iter()
+            .zip(failed.iter())
+            .filter(|(_, failed)| !**failed)
+            .map(|((_, device), _)| device.as_raw_fd())
+            .collect();
+        wait_any(&fds, POLL_INTERVAL)?;
+    }
+}
+
+/// Writes a frame with `write` if recording is enabled.
+/// A failed write disables recording instead of stopping the server.
+fn record(recorder: &mut Option<Recorder>, write: impl FnOnce(&Recorder) -> Result<()>) {
+    if let Some(rec) = recorder.as_ref() {
+        if let Err(e) = write(rec) {
+            error!("Stopped recording: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+/// Like [`smoltcp::phy::wait`] but returns as soon as one of `fds` is readable
+fn wait_any(fds: &[RawFd], timeout: Duration) -> Result<()> {
+    let mut pollfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    let res = unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout.total_millis() as libc::c_int,
+        )
+    };
+    if res == -1 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// Hands `frame` untagged to the socket serving its VLAN and builds the tagged reply
+/// in `tx_buffer`. Returns the position of the reply in `tx_buffer`.
+fn process(
+    sockets: &mut [PxeSocket],
+    frame: &mut [u8],
+    tx_buffer: &mut [u8],
+    now: Instant,
+) -> Result<Range<usize>> {
+    let (vlan, frame) = match vlan::strip_vlan_tag_in_place(frame) {
+        // Priority tagged frames belong to the native VLAN
+        Some(0) => (None, &frame[VLAN_TAG_LEN..]),
+        Some(id) => (Some(id), &frame[VLAN_TAG_LEN..]),
+        None => (None, &frame[..]),
+    };
+
+    let socket = sockets
+        .iter_mut()
+        .find(|socket| socket.get_vlan() == vlan)
+        .ok_or_else(|| Error::IgnoreNoLog(f!("Not serving vlan {:?}", vlan)))?;
+
+    let len = socket.process_into(frame, &mut tx_buffer[VLAN_TAG_LEN..], now)?;
+    Ok(tag(tx_buffer, len, vlan))
+}
+
+/// Tags the frame of `len` bytes at `tx_buffer[VLAN_TAG_LEN..]` in place.
+/// Returns the position of the frame to send.
+fn tag(tx_buffer: &mut [u8], len: usize, vlan: Option<u16>) -> Range<usize> {
+    match vlan {
+        Some(id) => {
+            vlan::insert_vlan_tag_in_place(tx_buffer, len, id);
+            0..VLAN_TAG_LEN + len
+        }
+        None => VLAN_TAG_LEN..VLAN_TAG_LEN + len,
+    }
+}
+
+fn transmit<D>(device: &mut D, now: Instant, packet: &[u8]) -> Result<()>
+where
+    D: Device + ?Sized,
+{
+    let tx = device
+        .transmit(now)
+        .ok_or_else(|| Error::Device("No transmit buffer available".to_string()))?;
+    tx.consume(packet.len(), |buffer| buffer.copy_from_slice(packet));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record::Recording;
+    use crate::tests::test_utils::{
+        dhcp_boot_file, dhcp_message_type, insert_vlan_tag, read_frames, strip_vlan_tag,
+    };
+    use smoltcp::phy::{DeviceCapabilities, Medium};
+    use smoltcp::wire::{
+        DhcpMessageType, EthernetAddress, EthernetFrame, EthernetProtocol, Ipv4Address,
+    };
+    use std::collections::VecDeque;
+    use std::path::Path;
+
+    /// In memory device, frames in `rx` are received and sent frames end up in `tx`
+    #[derive(Default)]
+    struct QueueDevice {
+        rx: VecDeque<Vec<u8>>,
+        tx: Vec<Vec<u8>>,
+    }
+
+    struct QueueRxToken(Vec<u8>);
+
+    struct QueueTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+    impl RxToken for QueueRxToken {
+        fn consume<R, F>(mut self, f: F) -> R
+        where
+            F: FnOnce(&mut [u8]) -> R,
+        {
+            f(&mut self.0)
+        }
+    }
+
+    impl<'a> TxToken for QueueTxToken<'a> {
+        fn consume<R, F>(self, len: usize, f: F) -> R
+        where
+            F: FnOnce(&mut [u8]) -> R,
+        {
+            let mut buffer = vec![0; len];
+            let res = f(&mut buffer);
+            self.0.push(buffer);
+            res
+        }
+    }
+
+    impl Device for QueueDevice {
+        type RxToken<'a>
+            = QueueRxToken
+        where
+            Self: 'a;
+        type TxToken<'a>
+            = QueueTxToken<'a>
+        where
+            Self: 'a;
+
+        fn receive(
+            &mut self,
+            _timestamp: Instant,
+        ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+            let frame = self.rx.pop_front()?;
+            Some((QueueRxToken(frame), QueueTxToken(&mut self.tx)))
+        }
+
+        fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+            Some(QueueTxToken(&mut self.tx))
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut caps = DeviceCapabilities::default();
+            caps.medium = Medium::Ethernet;
+            caps.max_transmission_unit = 1514;
+            caps
+        }
+    }
+
+    #[test]
+    fn test_poll_queue_device() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let socket = PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        );
+        let mut server = PxeServer::new(socket);
+
+        let mut device = QueueDevice::default();
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        device.rx.push_back(frames[0].clone());
+
+        let now = Instant::now();
+        while server.poll(&mut device, now).unwrap() {}
+        assert!(device.rx.is_empty());
+
+        // Skip the ARP announcement of our address
+        let replies: Vec<&Vec<u8>> = device
+            .tx
+            .iter()
+            .filter(|frame| {
+                let frame = EthernetFrame::new_checked(frame.as_slice()).unwrap();
+                frame.ethertype() == EthernetProtocol::Ipv4
+            })
+            .collect();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(dhcp_message_type(replies[0]), DhcpMessageType::Offer);
+    }
+
+    #[test]
+    fn test_poll_records_frames() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let socket = PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        );
+        let mut server = PxeServer::new(socket);
+
+        let path = Path::new("./target/test_poll_records_frames.pcapng");
+        let mut recording = Recording::create(path).unwrap();
+        server.set_recorder(
+            recording
+                .add_interface("eth0", server_mac, server_ip)
+                .unwrap(),
+        );
+
+        let mut device = QueueDevice::default();
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        device.rx.push_back(frames[0].clone());
+
+        let now = Instant::now();
+        while server.poll(&mut device, now).unwrap() {}
+        server.flush_recorder();
+
+        // The ARP announcement, the discover and our offer
+        let recorded = read_frames(path);
+        assert_eq!(recorded.len(), 3);
+        assert_eq!(recorded[0], device.tx[0]);
+        assert_eq!(recorded[1], frames[0]);
+        assert_eq!(recorded[2], device.tx[1]);
+    }
+
+    #[test]
+    fn test_poll_failed_recording() {
+        let server_ip = Ipv4Address::new(192, 168, 178, 97);
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let socket = PxeSocket::new(
+            server_ip,
+            server_mac,
+            Path::new("./assets/ipxe.pxe"),
+            Path::new("./assets/kernel.elf"),
+        );
+        let mut server = PxeServer::new(socket);
+
+        // Every write to /dev/full fails with ENOSPC
+        let mut recording = Recording::create(Path::new("/dev/full")).unwrap();
+        server.set_recorder(
+            recording
+                .add_interface("eth0", server_mac, server_ip)
+                .unwrap(),
+        );
+        server.flush_recorder();
+        assert!(server.recorder.is_none());
+
+        // Serving goes on without the recording
+        let mut device = QueueDevice::default();
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        device.rx.push_back(frames[0].clone());
+        while server.poll(&mut device, Instant::now()).unwrap() {}
+        assert_eq!(device.tx.len(), 2);
+        assert_eq!(dhcp_message_type(&device.tx[1]), DhcpMessageType::Offer);
+    }
+
+    #[test]
+    fn test_poll_vlans() {
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let new_socket = |server_ip: Ipv4Address, vlan: u16| {
+            let mut socket = PxeSocket::new(
+                server_ip,
+                server_mac,
+                Path::new("./assets/ipxe.pxe"),
+                Path::new("./assets/kernel.elf"),
+            );
+            socket.set_vlan(Some(vlan));
+            socket
+        };
+        let mut server = PxeServer::new(new_socket(Ipv4Address::new(192, 168, 178, 97), 100));
+        server
+            .add_socket(new_socket(Ipv4Address::new(10, 0, 200, 1), 200))
+            .unwrap();
+        assert!(server
+            .add_socket(new_socket(Ipv4Address::new(10, 0, 201, 1), 200))
+            .is_err());
+
+        let mut device = QueueDevice::default();
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        // Untagged and unknown VLANs are not served
+        device.rx.push_back(frames[0].clone());
+        device.rx.push_back(insert_vlan_tag(&frames[0], 300));
+        device.rx.push_back(insert_vlan_tag(&frames[0], 100));
+
+        let now = Instant::now();
+        while server.poll(&mut device, now).unwrap() {}
+        assert!(device.rx.is_empty());
+
+        let mut announced = Vec::new();
+        let mut replies = Vec::new();
+        for frame in device.tx.iter() {
+            let (vlan_id, untagged) = strip_vlan_tag(frame).expect("Sent an untagged frame");
+            let ether = EthernetFrame::new_checked(&untagged[..]).unwrap();
+            match ether.ethertype() {
+                EthernetProtocol::Arp => announced.push(vlan_id),
+                _ => replies.push((vlan_id, untagged)),
+            }
+        }
+        announced.sort();
+        assert_eq!(announced, vec![100, 200]);
+
+        assert_eq!(replies.len(), 1);
+        let (vlan_id, reply) = &replies[0];
+        assert_eq!(*vlan_id, 100);
+        assert_eq!(dhcp_message_type(reply), DhcpMessageType::Offer);
+    }
+
+    #[test]
+    fn test_poll_vlan_boot_files() {
+        let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+        let new_socket = |server_ip: Ipv4Address, vlan: u16, ipxe: &str| {
+            let mut socket = PxeSocket::new(
+                server_ip,
+                server_mac,
+                Path::new(ipxe),
+                Path::new("./assets/kernel.elf"),
+            );
+            socket.set_vlan(Some(vlan));
+            socket
+        };
+        let mut server = PxeServer::new(new_socket(
+            Ipv4Address::new(10, 0, 100, 1),
+            100,
+            "./assets/ipxe.pxe",
+        ));
+        server
+            .add_socket(new_socket(
+                Ipv4Address::new(10, 0, 200, 1),
+                200,
+                "./assets/ipxe.efi",
+            ))
+            .unwrap();
+
+        // The same client boots on both VLANs
+        let mut device = QueueDevice::default();
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        device.rx.push_back(insert_vlan_tag(&frames[0], 100));
+        device.rx.push_back(insert_vlan_tag(&frames[0], 200));
+        while server.poll(&mut device, Instant::now()).unwrap() {}
+
+        let mut offers: Vec<(u16, Option<String>)> = device
+            .tx
+            .iter()
+            .filter_map(|frame| strip_vlan_tag(frame))
+            .filter(|(_, untagged)| {
+                EthernetFrame::new_checked(&untagged[..])
+                    .unwrap()
+                    .ethertype()
+                    == EthernetProtocol::Ipv4
+            })
+            .map(|(vlan_id, untagged)| (vlan_id, dhcp_boot_file(&untagged)))
+            .collect();
+        offers.sort();
+        assert_eq!(
+            offers,
+            vec![
+                (100, Some("ipxe.pxe".to_string())),
+                (200, Some("ipxe.efi".to_string())),
+            ]
+        );
+    }
+}
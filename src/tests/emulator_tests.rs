@@ -0,0 +1,238 @@
+use crate::{
+    dhcp::{options::ClientArchType, parse::FirmwareType},
+    emulator::{ClientConfig, Emulator, LinkConfig},
+    prelude::Error,
+    PxeSocket, PxeStates,
+};
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+use std::{cell::Cell, fs, path::Path, rc::Rc};
+
+use super::test_utils::setup;
+
+const STAGE_ONE: &str = "./assets/ipxe.pxe";
+const STAGE_TWO: &str = "./assets/kernel.elf";
+
+fn pxe_socket(stage_one: &str, stage_two: &str) -> PxeSocket {
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    PxeSocket::new(
+        server_ip,
+        server_mac,
+        Path::new(stage_one),
+        Path::new(stage_two),
+    )
+}
+
+fn ipxe_client() -> ClientConfig {
+    ClientConfig {
+        firmware: FirmwareType::IPxe,
+        transaction_id: 0x1be0_3ad2,
+        ..Default::default()
+    }
+}
+
+#[test]
+pub fn emulated_intel_bios_boot() {
+    setup();
+    let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+    let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+
+    let res = emulator.boot(ClientConfig::default()).unwrap();
+    let expected = fs::read(STAGE_ONE).unwrap();
+    assert_eq!(res.boot_file, "ipxe.pxe");
+    assert_eq!(res.tsize, Some(expected.len()));
+    assert!(res.data == expected, "stage one differs from {}", STAGE_ONE);
+    assert_eq!(emulator.socket().get_state(), &PxeStates::Dhcp);
+}
+
+#[test]
+pub fn emulated_chainload() {
+    setup();
+    let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+    let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+
+    // The Intel ROM loads iPXE, which then loads the kernel with the default block size
+    let res = emulator.boot(ClientConfig::default()).unwrap();
+    assert!(res.data == fs::read(STAGE_ONE).unwrap());
+
+    let config = ClientConfig {
+        blksize: None,
+        ..ipxe_client()
+    };
+    let res = emulator.boot(config).unwrap();
+    let expected = fs::read(STAGE_TWO).unwrap();
+    assert_eq!(res.tsize, Some(expected.len()));
+    assert!(res.data == expected, "kernel differs from {}", STAGE_TWO);
+}
+
+#[test]
+pub fn emulated_unserved_firmwares() {
+    setup();
+    let firmwares = [
+        (FirmwareType::UBoot, ClientArchType::Arm64Uboot),
+        (FirmwareType::Grub, ClientArchType::X64Uefi),
+        (FirmwareType::Petitboot, ClientArchType::PowerPcOpenFimware),
+        (FirmwareType::HttpClient, ClientArchType::X64UefiHttp),
+        (FirmwareType::RaspberryPi, ClientArchType::ArmRpiBoot),
+    ];
+
+    // The served images are for PXE ROMs and iPXE, other firmwares are left alone
+    for (firmware, arch) in firmwares {
+        let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+        let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+        let config = ClientConfig {
+            firmware,
+            arch,
+            ..Default::default()
+        };
+
+        match emulator.boot(config) {
+            Err(Error::Emulator(_)) => (),
+            res => panic!(
+                "{:?} should not be answered, got {:?}",
+                firmware,
+                res.map(|r| r.boot_file)
+            ),
+        }
+        assert_eq!(emulator.socket().get_state(), &PxeStates::Dhcp);
+    }
+}
+
+#[test]
+pub fn emulated_lossy_link() {
+    setup();
+    let stage_one = fs::read(STAGE_ONE).unwrap();
+    let stage_two = fs::read(STAGE_TWO).unwrap();
+
+    for seed in 0..4 {
+        let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+        let link = LinkConfig {
+            loss: 0.1,
+            reorder: 0.1,
+            seed,
+        };
+        let mut emulator = Emulator::new(&mut pxe_socket, link);
+
+        let res = emulator.boot(ClientConfig::default()).unwrap();
+        assert!(res.data == stage_one, "seed {}: stage one differs", seed);
+        let res = emulator.boot(ipxe_client()).unwrap();
+        assert!(res.data == stage_two, "seed {}: kernel differs", seed);
+    }
+}
+
+/// Counts the TFTP acks the client sends
+fn count_acks(acks: &Rc<Cell<usize>>) -> impl FnMut(&[u8]) -> bool {
+    let acks = acks.clone();
+    move |frame| {
+        // Ethernet, ipv4 and udp headers in front of the tftp opcode
+        if frame.len() == 42 + 4 && frame[42..44] == [0, 4] {
+            acks.set(acks.get() + 1);
+        }
+        false
+    }
+}
+
+#[test]
+pub fn emulated_windowsize() {
+    setup();
+    let expected = fs::read(STAGE_TWO).unwrap();
+    let blocks = expected.len() / 1456 + 1;
+
+    for windowsize in [1, 8, 64] {
+        let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+        let acks = Rc::new(Cell::new(0));
+        let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+        emulator.set_filter(count_acks(&acks));
+
+        let config = ClientConfig {
+            windowsize: Some(windowsize),
+            ..ipxe_client()
+        };
+        let res = emulator.boot(config).unwrap();
+        assert!(res.data == expected, "kernel differs from {}", STAGE_TWO);
+
+        // One ack of the option ack, then one per window
+        assert_eq!(acks.get(), 1 + blocks.div_ceil(windowsize));
+    }
+}
+
+#[test]
+pub fn emulated_windowsize_lossy_link() {
+    setup();
+    let expected = fs::read(STAGE_TWO).unwrap();
+
+    for seed in 0..4 {
+        let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+        let link = LinkConfig {
+            loss: 0.05,
+            reorder: 0.05,
+            seed,
+        };
+        let mut emulator = Emulator::new(&mut pxe_socket, link);
+        let config = ClientConfig {
+            windowsize: Some(16),
+            max_retries: 10,
+            ..ipxe_client()
+        };
+        let res = emulator.boot(config).unwrap();
+        assert!(res.data == expected, "seed {}: kernel differs", seed);
+    }
+}
+
+#[test]
+pub fn emulated_lost_final_block() {
+    setup();
+    let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+    let expected = fs::read(STAGE_TWO).unwrap();
+
+    // Ethernet, ipv4, udp and tftp headers in front of the short last block
+    let final_len = 42 + 4 + expected.len() % 1456;
+    let mut lost = false;
+    let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+    emulator.set_filter(move |frame| {
+        let lose = !lost && frame.len() == final_len;
+        lost |= lose;
+        lose
+    });
+
+    let res = emulator.boot(ipxe_client()).unwrap();
+    assert!(res.data == expected, "kernel differs from {}", STAGE_TWO);
+    assert_eq!(emulator.socket().get_state(), &PxeStates::Dhcp);
+}
+
+#[test]
+pub fn emulated_lost_final_ack() {
+    setup();
+    let mut pxe_socket = pxe_socket(STAGE_ONE, STAGE_TWO);
+    let expected = fs::read(STAGE_TWO).unwrap();
+
+    // The server resends the last block until the client acks it again
+    let last_block = (expected.len() / 1456 + 1) as u16;
+    let mut lost = false;
+    let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+    emulator.set_filter(move |frame| {
+        let is_ack = frame.len() == 46 && frame[42..44] == [0, 4];
+        let lose = !lost && is_ack && frame[44..46] == last_block.to_be_bytes();
+        lost |= lose;
+        lose
+    });
+
+    let res = emulator.boot(ipxe_client()).unwrap();
+    assert!(res.data == expected, "kernel differs from {}", STAGE_TWO);
+    assert_eq!(emulator.socket().get_state(), &PxeStates::Dhcp);
+}
+
+#[test]
+pub fn emulated_missing_file() {
+    setup();
+    let mut pxe_socket = pxe_socket(STAGE_ONE, "./assets/missing.elf");
+    let mut emulator = Emulator::new(&mut pxe_socket, LinkConfig::default());
+
+    match emulator.boot(ipxe_client()) {
+        Err(Error::Emulator(_)) => (),
+        res => panic!(
+            "Expected the boot to fail, got {:?}",
+            res.map(|r| r.boot_file)
+        ),
+    }
+}
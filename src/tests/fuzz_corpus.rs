@@ -0,0 +1,113 @@
+//! Writes the seed corpus of the fuzz targets in `fuzz/` from the captures in `assets/`.
+//! Run `cargo test write_fuzz_corpus -- --ignored` after adding a capture.
+
+use crate::diff::DHCP_PORTS;
+use crate::tftp::parse::{Packet, Repr};
+use smoltcp::wire::{
+    DhcpPacket, EthernetAddress, EthernetFrame, EthernetProtocol, Ipv4Packet, UdpPacket,
+};
+use std::{collections::HashSet, fs, path::Path};
+
+use super::test_utils::read_frames;
+
+const SERVER_MAC: EthernetAddress = EthernetAddress([0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+
+// Boots that are replayed as a whole by the pxe_socket target
+const CONVERSATIONS: [(&str, &[&str]); 6] = [
+    ("ipxe", &["ipxe_dhcp", "ipxe_tftp"]),
+    ("intel_bios", &["intel_bios_dhcp", "intel_bios_tftp"]),
+    ("intel_efi", &["intel_efi_dhcp", "intel_efi_tftp"]),
+    ("amd_efi", &["amd_efi_dhcp"]),
+    ("crash_in_dhcp_request", &["crash_in_dhcp_request"]),
+    ("uefi_tftp_pixiecore", &["uefi_tftp_pixiecore"]),
+];
+
+// The pixiecore capture is a complete transfer, its beginning is enough
+const MAX_FRAMES: usize = 32;
+
+struct Corpus {
+    seen: HashSet<Vec<u8>>,
+}
+
+impl Corpus {
+    fn add(&mut self, target: &str, name: String, data: &[u8]) {
+        if !self.seen.insert(data.to_vec()) {
+            return;
+        }
+        let dir = Path::new("./fuzz/corpus").join(target);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), data).unwrap();
+    }
+}
+
+/// Returns the udp payload of an ethernet frame and whether it is DHCP.
+/// ProxyDHCP requests go from port 4011 to port 4011.
+fn udp_payload(frame: &[u8]) -> Option<(bool, &[u8])> {
+    let ether = EthernetFrame::new_checked(frame).ok()?;
+    if ether.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).ok()?;
+    let udp = UdpPacket::new_checked(ipv4.payload()).ok()?;
+    let is_dhcp = DHCP_PORTS.contains(&udp.src_port()) || DHCP_PORTS.contains(&udp.dst_port());
+    Some((is_dhcp, udp.payload()))
+}
+
+#[test]
+#[ignore]
+pub fn write_fuzz_corpus() {
+    let mut corpus = Corpus {
+        seen: HashSet::new(),
+    };
+
+    for (conversation, captures) in CONVERSATIONS {
+        let mut client_frames = vec![];
+
+        for capture in captures {
+            let path = format!("./assets/{}.pcapng", capture);
+            let frames = read_frames(Path::new(&path));
+
+            for (idx, frame) in frames.iter().take(MAX_FRAMES).enumerate() {
+                if EthernetFrame::new_unchecked(frame).src_addr() != SERVER_MAC {
+                    client_frames.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+                    client_frames.extend_from_slice(frame);
+                }
+
+                let (is_dhcp, payload) = match udp_payload(frame) {
+                    Some(res) => res,
+                    None => continue,
+                };
+                let name = format!("{}-{}", capture, idx);
+
+                if is_dhcp {
+                    corpus.add("dhcp_discover", name.clone(), payload);
+                    if let Ok(dhcp) = DhcpPacket::new_checked(payload) {
+                        for option in dhcp.options() {
+                            let mut data = vec![option.kind];
+                            data.extend_from_slice(option.data);
+                            let name = format!("{}-{}", name, option.kind);
+                            corpus.add("dhcp_options", name, &data);
+                        }
+                    }
+                    continue;
+                }
+
+                corpus.add("tftp_packet", name.clone(), payload);
+                let packet = match Packet::new_checked(payload) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                };
+                match Repr::parse(&packet) {
+                    Ok(Repr::ReadRequest { opts, .. } | Repr::OptionAck { opts }) => {
+                        if opts.size() > 0 {
+                            corpus.add("tftp_options", name, opts.as_bytes());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        corpus.add("pxe_socket", conversation.to_string(), &client_frames);
+    }
+}
@@ -1,2 +1,4 @@
+mod emulator_tests;
+mod fuzz_corpus;
 mod pxe_tests;
 pub mod test_utils;
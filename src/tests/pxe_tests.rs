@@ -1,5 +1,16 @@
 use crate::{
-    prelude::Error, tests::test_utils::cmp_impl_responses, utils, PxeSocket, PxeStates, TftpStates,
+    dhcp::{
+        lease::{Leases, LEASE_TIMEOUT},
+        monitor::PXE_SERVER_ACTIVE_TIMEOUT,
+        parse::FirmwareType,
+    },
+    diff::Ignore,
+    prelude::Error,
+    tests::test_utils::cmp_impl_responses,
+    tftp::cache::FileCache,
+    tftp::construct::{MAX_RETRIES, RETRY_TIMEOUT},
+    tftp::socket::MAX_CLOSED_TRANSFERS,
+    utils, PxeSocket, PxeStates, TftpStates,
 };
 use env_logger::fmt::Color;
 use log::*;
@@ -9,11 +20,17 @@ use pcap_file::pcapng::{
     },
     PcapNgBlock, PcapNgReader, PcapNgWriter,
 };
-use smoltcp::wire::{EthernetAddress, EthernetFrame, Ipv4Address};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    DhcpMessageType, EthernetAddress, EthernetFrame, EthernetProtocol, Ipv4Address, Ipv4Packet,
+};
 use std::{borrow::Cow, fs::File, path::Path, str::FromStr, time::Duration, vec};
 use std::{io::Write, sync::Once};
 
-use super::test_utils::{setup, verify_responses};
+use super::test_utils::{
+    dhcp_message_type, log_diff, read_frames, set_dhcp_message_type, set_dhcp_transaction_id,
+    setup, verify_responses,
+};
 
 #[test]
 pub fn intel_bios_pxe() {
@@ -108,12 +125,644 @@ pub fn amd_efi() {
     let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
 
     // Emulate the DHCP Discover phase
-    let _res = cmp_impl_responses(
+    let res = cmp_impl_responses(
         &mut pxe_socket,
         Path::new("./assets/amd_efi_dhcp.pcapng"),
         |e| panic!("{}", e),
     );
+    // Still differs from the captured server, show where
+    log_diff(&res, Ignore::VOLATILE);
     //verify_responses(&res);
 
     // assert_eq!(pxe_socket.get_state(), &PxeStates::Tftp);
 }
+
+#[test]
+pub fn dhcp_release_restarts_handshake() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 21);
+    let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+
+    // The client releases its address after our PXE ack and starts over with a new discover.
+    // It retries the discover with another transaction id, which restarts the handshake again.
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/crash_in_dhcp_request.pcapng"),
+        |e| panic!("{}", e),
+    );
+
+    // The router and the client ask for our address before talking to us
+    let (arp, dhcp): (Vec<_>, Vec<_>) = res.got.iter().partition(|f| {
+        EthernetFrame::new_checked(&f[..]).unwrap().ethertype() == EthernetProtocol::Arp
+    });
+    assert_eq!(arp.len(), 2);
+
+    let got: Vec<DhcpMessageType> = dhcp.iter().map(|f| dhcp_message_type(f)).collect();
+    assert_eq!(
+        got,
+        vec![
+            DhcpMessageType::Offer,
+            DhcpMessageType::Ack,
+            DhcpMessageType::Offer,
+            DhcpMessageType::Offer
+        ]
+    );
+    assert_eq!(pxe_socket.get_state(), &PxeStates::Dhcp);
+}
+
+#[test]
+pub fn dhcp_inform() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 21);
+    let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    // Turn the unicast PXE request of the capture into an inform
+    let mut frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+    let inform = &mut frames[10];
+    assert_eq!(dhcp_message_type(inform), DhcpMessageType::Request);
+    set_dhcp_message_type(inform, DhcpMessageType::Inform);
+
+    let resp = pxe_socket.process(inform, now).unwrap();
+    assert_eq!(dhcp_message_type(&resp), DhcpMessageType::Ack);
+
+    let ether = EthernetFrame::new_checked(&resp[..]).unwrap();
+    assert_eq!(
+        ether.dst_addr(),
+        EthernetAddress::from_bytes(&[0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe])
+    );
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).unwrap();
+    assert_eq!(ipv4.dst_addr(), Ipv4Address::new(192, 168, 178, 75));
+
+    // An inform does not advance the handshake
+    assert_eq!(pxe_socket.get_state(), &PxeStates::Dhcp);
+}
+
+#[test]
+pub fn dhcp_decline_is_ignored() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 21);
+    let server_mac = EthernetAddress::from_bytes(&[0xe8, 0xd0, 0xfc, 0xc8, 0x00, 0x97]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let mut frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+    let decline = &mut frames[11];
+    assert_eq!(dhcp_message_type(decline), DhcpMessageType::Release);
+    set_dhcp_message_type(decline, DhcpMessageType::Decline);
+
+    assert!(matches!(
+        pxe_socket.process(decline, now),
+        Err(Error::Ignore(_))
+    ));
+}
+
+#[test]
+pub fn dhcp_release_ends_transfer() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+    pxe_socket.process(&tftp[0], now).unwrap();
+    assert_eq!(pxe_socket.get_state(), &PxeStates::Tftp(FirmwareType::IPxe));
+
+    // The same client releases its address to us in the other capture
+    let mut frames = read_frames(Path::new("./assets/crash_in_dhcp_request.pcapng"));
+    let release = &mut frames[11];
+    assert_eq!(dhcp_message_type(release), DhcpMessageType::Release);
+    release[0..6].copy_from_slice(server_mac.as_bytes());
+    release[30..34].copy_from_slice(server_ip.as_bytes());
+
+    assert!(matches!(
+        pxe_socket.process(release, now),
+        Err(Error::Ignore(_))
+    ));
+    assert_eq!(pxe_socket.get_state(), &PxeStates::Dhcp);
+
+    // Its transfer is gone
+    assert!(pxe_socket.process(&tftp[2], now).is_err());
+}
+
+#[test]
+pub fn competing_pxe_server() {
+    setup();
+
+    // Pretend to be a second PXE server on the network of the intel bios capture
+    let server_ip = Ipv4Address::new(192, 168, 178, 98);
+    let server_mac = EthernetAddress::from_bytes(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+    pxe_socket.set_back_off(true);
+
+    let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+
+    // Offer of the original proxyDHCP server and the router
+    let _ = pxe_socket.process(&frames[1], now);
+    let _ = pxe_socket.process(&frames[2], now);
+
+    let monitor = pxe_socket.get_server_monitor();
+    let proxy = monitor
+        .servers()
+        .find(|s| s.ip == Ipv4Address::new(192, 168, 178, 97))
+        .unwrap();
+    assert!(proxy.is_pxe && proxy.is_proxy);
+    let router = monitor
+        .servers()
+        .find(|s| s.ip == Ipv4Address::new(192, 168, 178, 1))
+        .unwrap();
+    assert!(!router.is_pxe && !router.is_proxy);
+
+    // The discover is not answered because the other PXE server is active
+    assert!(matches!(
+        pxe_socket.process(&frames[0], now),
+        Err(Error::Ignore(_))
+    ));
+
+    pxe_socket.set_back_off(false);
+    assert!(pxe_socket.process(&frames[0], now).is_ok());
+
+    // Servers that stayed silent for the back-off window are forgotten
+    let later = now + PXE_SERVER_ACTIVE_TIMEOUT;
+    let _ = pxe_socket.process(&frames[2], later);
+    let monitor = pxe_socket.get_server_monitor();
+    assert_eq!(monitor.servers().count(), 1);
+    assert!(monitor.active_pxe_server(later).is_none());
+}
+
+#[test]
+pub fn shared_leases() {
+    setup();
+
+    // Two interfaces see the discovers of the intel bios capture
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut first = PxeSocket::new(
+        Ipv4Address::new(192, 168, 178, 97),
+        EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]),
+        &pxe_image,
+        &kernel_image,
+    );
+    let mut second = PxeSocket::new(
+        Ipv4Address::new(192, 168, 178, 98),
+        EthernetAddress::from_bytes(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+        &pxe_image,
+        &kernel_image,
+    );
+    let leases = Leases::new();
+    first.set_leases(leases.clone());
+    second.set_leases(leases.clone());
+    let now = Instant::from_secs(0);
+
+    let mut frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+    let discover = &mut frames[0];
+    assert_eq!(dhcp_message_type(discover), DhcpMessageType::Discover);
+
+    // The first socket that answers serves the client
+    assert!(first.process(discover, now).is_ok());
+    assert!(matches!(
+        second.process(discover, now),
+        Err(Error::Ignore(_))
+    ));
+    assert_eq!(leases.len(), 1);
+
+    // Until the client stays silent for too long
+    let later = now + LEASE_TIMEOUT;
+    assert!(second.process(discover, later).is_ok());
+    assert!(matches!(
+        first.process(discover, later),
+        Err(Error::Ignore(_))
+    ));
+
+    // Or releases its address
+    set_dhcp_message_type(discover, DhcpMessageType::Release);
+    let _ = second.process(discover, later);
+    assert!(leases.is_empty());
+}
+
+#[test]
+pub fn shared_file_cache() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let files = FileCache::new();
+    let now = Instant::from_secs(0);
+
+    // Sockets that share the cache read the kernel once
+    for _ in 0..2 {
+        let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+        pxe_socket.set_file_cache(files.clone());
+        let res = cmp_impl_responses(
+            &mut pxe_socket,
+            Path::new("./assets/ipxe_dhcp.pcapng"),
+            |e| panic!("{}", e),
+        );
+        verify_responses(&res);
+
+        let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+        pxe_socket.process(&tftp[0], now).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}
+
+#[test]
+pub fn dhcp_retransmissions() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    // Discover, offer, offer of the router, broadcast request, ack of the router,
+    // unicast request, ack
+    let dhcp = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+    let tftp = read_frames(Path::new("./assets/intel_bios_tftp.pcapng"));
+
+    // Our offer got lost and the client discovers again
+    assert_eq!(pxe_socket.process(&dhcp[0], now).unwrap(), dhcp[1]);
+    assert_eq!(pxe_socket.process(&dhcp[0], now).unwrap(), dhcp[1]);
+    assert!(pxe_socket.process(&dhcp[3], now).is_err());
+
+    // Our ack got lost and the client requests again
+    assert_eq!(pxe_socket.process(&dhcp[5], now).unwrap(), dhcp[6]);
+    assert_eq!(pxe_socket.process(&dhcp[5], now).unwrap(), dhcp[6]);
+
+    // A request and a discover arrive late, after the transfer started
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    assert_eq!(pxe_socket.process(&dhcp[5], now).unwrap(), dhcp[6]);
+    assert_eq!(pxe_socket.process(&dhcp[0], now).unwrap(), dhcp[1]);
+    assert_eq!(
+        pxe_socket.get_state(),
+        &PxeStates::Tftp(FirmwareType::Intel)
+    );
+
+    // The transfer is not disturbed by the duplicates
+    for (request, reply) in [(2, None), (3, Some(4)), (5, Some(6))] {
+        match reply {
+            Some(reply) => {
+                assert_eq!(
+                    pxe_socket.process(&tftp[request], now).unwrap(),
+                    tftp[reply]
+                )
+            }
+            None => assert!(pxe_socket.process(&tftp[request], now).is_err()),
+        }
+    }
+}
+
+#[test]
+pub fn fresh_discover_restarts_handshake() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let mut dhcp = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+    let tftp = read_frames(Path::new("./assets/intel_bios_tftp.pcapng"));
+
+    // The client boots up to the first read request with another transaction id
+    for frame in dhcp.iter_mut() {
+        set_dhcp_transaction_id(frame, 0x1337);
+    }
+    let got: Vec<DhcpMessageType> = [&dhcp[0], &dhcp[5]]
+        .iter()
+        .map(|frame| dhcp_message_type(&pxe_socket.process(frame, now).unwrap()))
+        .collect();
+    assert_eq!(got, vec![DhcpMessageType::Offer, DhcpMessageType::Ack]);
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+
+    // Then it resets and starts over with the captured handshake
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/intel_bios_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/intel_bios_tftp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+}
+
+#[test]
+pub fn tftp_retransmissions() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // Read request, option ack, ack 0, data 1, ack 1, data 2
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+
+    // Our option ack got lost and the client asks again
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    assert_eq!(pxe_socket.process(&tftp[2], now).unwrap(), tftp[3]);
+
+    // Late duplicates of the read request and the first ack are dropped
+    assert!(matches!(
+        pxe_socket.process(&tftp[0], now),
+        Err(Error::Ignore(_))
+    ));
+    assert!(matches!(
+        pxe_socket.process(&tftp[2], now),
+        Err(Error::Ignore(_))
+    ));
+    assert_eq!(pxe_socket.process(&tftp[4], now).unwrap(), tftp[5]);
+    assert!(matches!(
+        pxe_socket.process(&tftp[4], now),
+        Err(Error::Ignore(_))
+    ));
+}
+
+#[test]
+pub fn tftp_intel_retransmissions() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/intel_bios_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // Tsize request, option ack, error, read request, option ack, ack 0, data 1
+    let tftp = read_frames(Path::new("./assets/intel_bios_tftp.pcapng"));
+
+    // The error ending the tsize request got lost, the read request of the
+    // transfer arrives while the tsize transfer is still open
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    assert_eq!(pxe_socket.process(&tftp[3], now).unwrap(), tftp[4]);
+    assert_eq!(pxe_socket.process(&tftp[3], now).unwrap(), tftp[4]);
+
+    // The error and the first ack arrive late and out of order
+    assert_eq!(pxe_socket.process(&tftp[5], now).unwrap(), tftp[6]);
+    assert!(matches!(
+        pxe_socket.process(&tftp[2], now),
+        Err(Error::Ignore(_))
+    ));
+    assert!(matches!(
+        pxe_socket.process(&tftp[5], now),
+        Err(Error::Ignore(_))
+    ));
+    assert!(matches!(
+        pxe_socket.process(&tftp[0], now),
+        Err(Error::Ignore(_))
+    ));
+}
+
+#[test]
+pub fn tftp_closed_transfers_are_bounded() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // The client restarts the transfer from a new port every time
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+    let read_request = |port: u16| {
+        let mut frame = tftp[0].clone();
+        frame[34..36].copy_from_slice(&port.to_be_bytes());
+        // No udp checksum
+        frame[40..42].copy_from_slice(&[0, 0]);
+        frame
+    };
+    let ports: Vec<u16> = (0..MAX_CLOSED_TRANSFERS as u16 + 2)
+        .map(|i| 1000 + i)
+        .collect();
+    for port in ports.iter() {
+        assert!(pxe_socket.process(&read_request(*port), now).is_ok());
+    }
+
+    // Late read requests of recent transfers are dropped, only the oldest was forgotten
+    assert!(matches!(
+        pxe_socket.process(&read_request(ports[1]), now),
+        Err(Error::Ignore(_))
+    ));
+    assert!(pxe_socket.process(&read_request(ports[0]), now).is_ok());
+}
+
+#[test]
+pub fn tftp_missing_file_is_refused() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/missing.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // The read request is answered with a file not found error instead of failing the server
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+    let reply = pxe_socket.process(&tftp[0], now).unwrap();
+    assert_eq!(reply[42..46], [0, 5, 0, 1]);
+
+    // No transfer was left behind, a retry is refused again
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), reply);
+}
+
+#[test]
+pub fn malformed_frames_are_dropped() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let now = Instant::from_secs(0);
+
+    let mut frames = read_frames(Path::new("./assets/ipxe_dhcp.pcapng"));
+    frames.extend(read_frames(Path::new("./assets/ipxe_tftp.pcapng")));
+
+    // Every truncation of a captured frame has to be dropped without a panic
+    for frame in frames.iter() {
+        for len in 0..frame.len() {
+            let _ = pxe_socket.process(&frame[..len], now);
+        }
+    }
+
+    // Same for frames with a garbage payload behind valid headers
+    for frame in frames.iter() {
+        let mut garbage = frame.clone();
+        garbage[42..].iter_mut().for_each(|b| *b = 0xff);
+        let _ = pxe_socket.process(&garbage, now);
+    }
+}
+
+#[test]
+pub fn tftp_timeouts() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let mut now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // Read request, option ack, ack 0, data 1, ack 1, data 2
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+    let is_arp = |frame: &[u8]| {
+        EthernetFrame::new_checked(frame).unwrap().ethertype() == EthernetProtocol::Arp
+    };
+
+    // Nothing is due before the retry timeout expired
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    assert!(pxe_socket.process_timeout(now).is_err());
+
+    // The option ack got lost, the ARP announcement is due as well
+    now += RETRY_TIMEOUT;
+    let announced = now;
+    assert!(is_arp(&pxe_socket.process_timeout(now).unwrap()));
+    assert_eq!(pxe_socket.process_timeout(now).unwrap(), tftp[1]);
+    assert!(pxe_socket.process_timeout(now).is_err());
+
+    // The first data block got lost
+    assert_eq!(pxe_socket.process(&tftp[2], now).unwrap(), tftp[3]);
+    now += RETRY_TIMEOUT;
+    assert_eq!(pxe_socket.process_timeout(now).unwrap(), tftp[3]);
+
+    // The client is gone, the transfer is torn down with an error after the last retry.
+    // The ack of block 0 reset the retry counter.
+    for _ in 1..MAX_RETRIES {
+        now += RETRY_TIMEOUT;
+        assert_eq!(pxe_socket.process_timeout(now).unwrap(), tftp[3]);
+    }
+    now += RETRY_TIMEOUT;
+    let reply = pxe_socket.process_timeout(now).unwrap();
+    assert_eq!(reply[42..44], [0, 5]);
+    assert_eq!(pxe_socket.get_state(), &PxeStates::Dhcp);
+    assert!(pxe_socket.process_timeout(now).is_err());
+
+    // The address is announced again once the ARP timer expired
+    now = announced + crate::ARP_TIMEOUT;
+    assert!(pxe_socket.process_timeout(now).is_err());
+    now += RETRY_TIMEOUT;
+    assert!(is_arp(&pxe_socket.process_timeout(now).unwrap()));
+}
+
+#[test]
+pub fn tftp_timeouts_not_in_a_row() {
+    setup();
+
+    let server_ip = Ipv4Address::new(192, 168, 178, 97);
+    let server_mac = EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]);
+    let pxe_image = std::path::PathBuf::from_str("./assets/ipxe.pxe").unwrap();
+    let kernel_image = std::path::PathBuf::from_str("./assets/kernel.elf").unwrap();
+    let mut pxe_socket = PxeSocket::new(server_ip, server_mac, &pxe_image, &kernel_image);
+    let mut now = Instant::from_secs(0);
+
+    let res = cmp_impl_responses(
+        &mut pxe_socket,
+        Path::new("./assets/ipxe_dhcp.pcapng"),
+        |e| panic!("{}", e),
+    );
+    verify_responses(&res);
+
+    // Read request, option ack, ack 0, data 1, ack 1, data 2
+    let tftp = read_frames(Path::new("./assets/ipxe_tftp.pcapng"));
+    let retransmit = |pxe_socket: &mut PxeSocket, now: Instant| loop {
+        let frame = pxe_socket.process_timeout(now).unwrap();
+        if EthernetFrame::new_checked(&frame[..]).unwrap().ethertype() != EthernetProtocol::Arp {
+            return frame;
+        }
+    };
+
+    // The option ack and the first data block are both lost MAX_RETRIES times.
+    // Only losses in a row count, the ack in between resets the retry counter.
+    assert_eq!(pxe_socket.process(&tftp[0], now).unwrap(), tftp[1]);
+    for _ in 0..MAX_RETRIES {
+        now += RETRY_TIMEOUT;
+        assert_eq!(retransmit(&mut pxe_socket, now), tftp[1]);
+    }
+    assert_eq!(pxe_socket.process(&tftp[2], now).unwrap(), tftp[3]);
+    for _ in 0..MAX_RETRIES {
+        now += RETRY_TIMEOUT;
+        assert_eq!(retransmit(&mut pxe_socket, now), tftp[3]);
+    }
+    assert_eq!(pxe_socket.process(&tftp[4], now).unwrap(), tftp[5]);
+    assert!(matches!(pxe_socket.get_state(), PxeStates::Tftp(_)));
+}
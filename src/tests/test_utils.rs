@@ -1,14 +1,10 @@
-use crate::{prelude::Error, PxeSocket};
+use crate::{diff::Ignore, prelude::Error, replay, vlan, PxeSocket};
 use env_logger::fmt::Color;
 use log::*;
-use pcap_file::pcapng::{
-    blocks::{
-        enhanced_packet::EnhancedPacketBlock, interface_description::InterfaceDescriptionBlock,
-    },
-    PcapNgBlock, PcapNgReader, PcapNgWriter,
+use smoltcp::wire::{
+    DhcpMessageType, DhcpPacket, DhcpRepr, EthernetFrame, IpAddress, Ipv4Packet, UdpPacket,
 };
-use smoltcp::wire::{EthernetAddress, EthernetFrame, Ipv4Address};
-use std::{borrow::Cow, fs::File, path::Path, str::FromStr, time::Duration, vec};
+use std::path::Path;
 use std::{io::Write, sync::Once};
 
 static INIT: Once = Once::new();
@@ -51,88 +47,111 @@ pub fn setup_logging(level: LevelFilter) {
         .init();
 }
 
-pub struct Responses {
-    pub wanted: Vec<Vec<u8>>,
-    pub got: Vec<Vec<u8>>,
-}
+pub use crate::replay::Responses;
 
 pub fn cmp_impl_responses(
     pxe_socket: &mut PxeSocket,
     pcap_path: &Path,
     handle_error: impl Fn(Error),
 ) -> Responses {
-    let file_in = File::open(pcap_path).expect("Error opening file");
-    let mut pcapng_reader = PcapNgReader::new(file_in).unwrap();
-
-    let mut orig_send: Vec<Vec<u8>> = vec![];
-    let mut impl_send: Vec<Vec<u8>> = vec![];
-
-    // Read test.pcapng
-    while let Some(block) = pcapng_reader.next_block() {
-        //Check if there is no error
-        let block = block.unwrap();
-
-        // Get the data from the block
-        let block = match block.clone().into_enhanced_packet() {
-            Some(block) => block,
-            None => {
-                trace!("Not an enhanced packet block: {:?}", block);
-                continue;
-            }
-        };
-
-        // Check if the packet is from the server and ignore it
-        let ether = EthernetFrame::new_checked(&block.data).unwrap();
-        if ether.src_addr() == pxe_socket.get_server_mac() {
-            orig_send.push(block.data.to_vec());
-            continue;
-        }
-
-        // Process the packet
-        let response = pxe_socket.process(&block.data);
-        match response {
-            Ok(resp) => impl_send.push(resp.to_vec()),
-            Err(Error::IgnoreNoLog(e)) => trace!("IgnoreNoLog: {}", e),
-            Err(Error::Ignore(e)) => debug!("Ignore: {}", e),
-            Err(e) => handle_error(e),
-        }
-    }
-
-    Responses {
-        wanted: orig_send,
-        got: impl_send,
-    }
+    replay::cmp_impl_responses(pxe_socket, pcap_path, handle_error).expect("Error reading capture")
 }
 
 pub fn verify_responses(res: &Responses) {
     assert_eq!(res.wanted.len(), res.got.len());
 
     if res.wanted != res.got {
-        vec_to_pcap(res.got.as_slice(), Path::new("./target/got.pcapng"));
-        vec_to_pcap(res.wanted.as_slice(), Path::new("./target/wanted.pcapng"));
-        info!("Hint: Use qtwirediff to compare the two files");
+        log_diff(res, Ignore::default());
+        res.write_pcaps(Path::new("./target")).unwrap();
         panic!("Responses are not equal. See ./target/got.pcapng and ./target/wanted.pcapng");
     }
 }
 
+/// Logs the fields of every reply that differs from the captured one
+pub fn log_diff(res: &Responses, ignore: Ignore) {
+    for diff in res.diff(ignore) {
+        error!("{}:", diff);
+        for field in diff.fields {
+            error!("    {}", field);
+        }
+    }
+}
+
 pub fn vec_to_pcap(data: &[Vec<u8>], file_path: &Path) {
-    let interface = InterfaceDescriptionBlock {
-        linktype: pcap_file::DataLink::ETHERNET,
-        snaplen: 0xFFFF,
-        options: vec![],
-    };
-
-    let file = File::create(file_path).expect("Error creating file");
-    let mut pcap_ng_writer = PcapNgWriter::new(file).unwrap();
-    pcap_ng_writer.write_block(&interface.into_block()).unwrap();
-    for data in data {
-        let packet = EnhancedPacketBlock {
-            interface_id: 0,
-            timestamp: Duration::from_secs(0),
-            original_len: data.len() as u32,
-            data: Cow::Borrowed(data),
-            options: vec![],
-        };
-        pcap_ng_writer.write_block(&packet.into_block()).unwrap();
+    replay::write_frames(data, file_path).expect("Error writing pcap");
+}
+
+/// Returns all ethernet frames of a pcapng file
+pub fn read_frames(pcap_path: &Path) -> Vec<Vec<u8>> {
+    replay::read_frames(pcap_path).expect("Error reading pcap")
+}
+
+/// Returns the DHCP message type of an ethernet frame containing a DHCP packet
+pub fn dhcp_message_type(frame: &[u8]) -> DhcpMessageType {
+    let ether = EthernetFrame::new_checked(frame).unwrap();
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).unwrap();
+    let udp = UdpPacket::new_checked(ipv4.payload()).unwrap();
+    let dhcp = DhcpPacket::new_checked(udp.payload()).unwrap();
+    crate::dhcp::utils::dhcp_message_type(&dhcp).unwrap()
+}
+
+/// Returns the boot file name of an ethernet frame containing a DHCP packet
+pub fn dhcp_boot_file(frame: &[u8]) -> Option<String> {
+    let ether = EthernetFrame::new_checked(frame).unwrap();
+    let ipv4 = Ipv4Packet::new_checked(ether.payload()).unwrap();
+    let udp = UdpPacket::new_checked(ipv4.payload()).unwrap();
+    let dhcp = DhcpPacket::new_checked(udp.payload()).unwrap();
+    let repr = DhcpRepr::parse(&dhcp).unwrap();
+    repr.boot_file.map(|file| file.to_string())
+}
+
+/// Rewrites the DHCP message type option of a captured frame and fixes up the udp checksum
+pub fn set_dhcp_message_type(frame: &mut [u8], msg_type: DhcpMessageType) {
+    let mut ether = EthernetFrame::new_checked(frame).unwrap();
+    let mut ipv4 = Ipv4Packet::new_checked(ether.payload_mut()).unwrap();
+    let (src_addr, dst_addr) = (ipv4.src_addr(), ipv4.dst_addr());
+    let mut udp = UdpPacket::new_checked(ipv4.payload_mut()).unwrap();
+
+    // Walk the options behind the magic cookie
+    let payload = udp.payload_mut();
+    let mut idx = 240;
+    while idx < payload.len() && payload[idx] != 255 {
+        match payload[idx] {
+            0 => idx += 1,
+            53 => {
+                payload[idx + 2] = msg_type.into();
+                break;
+            }
+            _ => idx += 2 + payload[idx + 1] as usize,
+        }
     }
+
+    udp.fill_checksum(&IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr));
+}
+
+/// Rewrites the DHCP transaction id of a captured frame and fixes up the udp checksum
+pub fn set_dhcp_transaction_id(frame: &mut [u8], transaction_id: u32) {
+    let mut ether = EthernetFrame::new_checked(frame).unwrap();
+    let mut ipv4 = Ipv4Packet::new_checked(ether.payload_mut()).unwrap();
+    let (src_addr, dst_addr) = (ipv4.src_addr(), ipv4.dst_addr());
+    let mut udp = UdpPacket::new_checked(ipv4.payload_mut()).unwrap();
+
+    let mut dhcp = DhcpPacket::new_unchecked(udp.payload_mut());
+    dhcp.set_transaction_id(transaction_id);
+
+    udp.fill_checksum(&IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr));
+}
+
+/// Tags an untagged `frame` with `vlan_id` like a trunk port does
+pub fn insert_vlan_tag(frame: &[u8], vlan_id: u16) -> Vec<u8> {
+    let mut buffer = vec![0; vlan::VLAN_TAG_LEN + frame.len()];
+    buffer[vlan::VLAN_TAG_LEN..].copy_from_slice(frame);
+    vlan::insert_vlan_tag_in_place(&mut buffer, frame.len(), vlan_id).to_vec()
+}
+
+/// Returns the VLAN id and the untagged frame, `None` if `frame` is not tagged
+pub fn strip_vlan_tag(frame: &[u8]) -> Option<(u16, Vec<u8>)> {
+    let mut frame = frame.to_vec();
+    let vlan_id = vlan::strip_vlan_tag_in_place(&mut frame)?;
+    Some((vlan_id, frame.split_off(vlan::VLAN_TAG_LEN)))
 }
@@ -0,0 +1,219 @@
+//! Boot files shared by the TFTP transfers of all interfaces.
+//!
+//! A file is read from disk on its first transfer and served from memory
+//! afterwards. Every open compares the modification time and size, so a rebuilt
+//! image is picked up by the next transfer without a restart.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use log::*;
+
+use super::construct::Handle;
+use super::error::*;
+
+struct CacheEntry {
+    modified: SystemTime,
+    data: Rc<[u8]>,
+}
+
+/// Cached boot files. Clones share the cache, give every socket a clone of the same one.
+#[derive(Clone, Default)]
+pub struct FileCache {
+    files: Rc<RefCell<HashMap<PathBuf, CacheEntry>>>,
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.files
+                    .borrow()
+                    .iter()
+                    .map(|(path, entry)| (path.clone(), entry.data.len())),
+            )
+            .finish()
+    }
+}
+
+impl FileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens `path` for a transfer. The file is read on the first open and again
+    /// after it changed on disk.
+    pub fn open(&self, path: &Path) -> std::io::Result<CachedFile> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+
+        let mut files = self.files.borrow_mut();
+        if let Some(entry) = files.get(path) {
+            if entry.modified == modified && entry.data.len() as u64 == metadata.len() {
+                return Ok(CachedFile::new(entry.data.clone()));
+            }
+        }
+
+        let data: Rc<[u8]> = fs::read(path)?.into();
+        debug!("Cached {} with {} bytes", path.display(), data.len());
+        files.insert(
+            path.to_path_buf(),
+            CacheEntry {
+                modified,
+                data: data.clone(),
+            },
+        );
+        Ok(CachedFile::new(data))
+    }
+
+    /// Number of cached files
+    pub fn len(&self) -> usize {
+        self.files.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.borrow().is_empty()
+    }
+}
+
+/// A cached file opened for one transfer, it keeps its own read position
+#[derive(Clone)]
+pub struct CachedFile {
+    data: Rc<[u8]>,
+    pos: usize,
+    last_read: usize,
+}
+
+impl fmt::Debug for CachedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedFile")
+            .field("len", &self.data.len())
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl CachedFile {
+    fn new(data: Rc<[u8]>) -> Self {
+        Self {
+            data,
+            pos: 0,
+            last_read: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl Handle for CachedFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        self.last_read = len;
+        Ok(len)
+    }
+
+    fn repeat_last_read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.last_read == 0 {
+            return Err(Error::Ignore("No last read".to_string()));
+        } else if buf.len() < self.last_read {
+            return Err(Error::Generic(
+                "Buffer is smaller than the last read".to_string(),
+            ));
+        }
+
+        self.pos -= self.last_read;
+        let last_read = self.last_read;
+        self.read(&mut buf[..last_read])
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<()> {
+        self.pos = (pos as usize).min(self.data.len());
+        self.last_read = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::Tftp(
+            "tftp: write requests are not supported".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tftp::construct::DEFAULT_BLKSIZE;
+
+    #[test]
+    fn test_file_cache() {
+        let cache = FileCache::new();
+        let shared = cache.clone();
+        let path = Path::new("./assets/ipxe.pxe");
+
+        let mut first = cache.open(path).unwrap();
+        let second = shared.open(path).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(Rc::ptr_eq(&first.data, &second.data));
+        assert_eq!(first.len() as u64, fs::metadata(path).unwrap().len());
+
+        // Transfers keep their own position
+        let mut buf = [0; 512];
+        assert_eq!(first.read(&mut buf).unwrap(), 512);
+        assert_eq!(first.pos, 512);
+        assert_eq!(second.pos, 0);
+        assert_eq!(&buf[..], &fs::read(path).unwrap()[..512]);
+
+        assert!(matches!(
+            cache.open(Path::new("./assets/missing.pxe")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_file_cache_reloads_changed_file() {
+        let cache = FileCache::new();
+        let path = Path::new("./target/test_file_cache_reloads_changed_file.bin");
+        fs::write(path, b"stage one").unwrap();
+        assert_eq!(cache.open(path).unwrap().len(), 9);
+
+        fs::write(path, b"rebuilt stage one").unwrap();
+        let mut file = cache.open(path).unwrap();
+        let mut buf = [0; 512];
+        let len = file.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"rebuilt stage one");
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_cached_file_repeat_last_read() {
+        let path = Path::new("./assets/kernel.elf");
+        let cache = FileCache::new();
+        let mut file = cache.open(path).unwrap();
+        let mut buf = vec![0; DEFAULT_BLKSIZE];
+        let last_block = loop {
+            let len = file.read(&mut buf).unwrap();
+            if len < DEFAULT_BLKSIZE {
+                break buf[..len].to_vec();
+            }
+        };
+        assert_eq!(last_block.len(), file.len() % DEFAULT_BLKSIZE);
+
+        let mut repeated = vec![0; DEFAULT_BLKSIZE];
+        let len = file.repeat_last_read(&mut repeated).unwrap();
+        assert_eq!(&repeated[..len], &last_block[..]);
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+    }
+}
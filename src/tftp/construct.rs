@@ -13,13 +13,23 @@ use super::utils;
 use std::{collections::BTreeMap, fmt::Formatter, io::Seek};
 
 /// Maximum number of retransmissions attempted by the server before giving up.
-// const MAX_RETRIES: u8 = 10;
+pub const MAX_RETRIES: u8 = 10;
 
-// /// Interval between consecutive retries in case of no answer.
-// const RETRY_TIMEOUT: Duration = Duration::from_millis(200);
+/// Interval between consecutive retries in case of no answer.
+pub const RETRY_TIMEOUT: Duration = Duration::from_millis(200);
 
 // /// IANA port for TFTP servers.
 // const TFTP_PORT: u16 = 69;
+
+/// Block size of a transfer without the blksize option, RFC 1350
+pub const DEFAULT_BLKSIZE: usize = 512;
+
+/// Valid values of the blksize option, RFC 2348
+pub const BLKSIZE_RANGE: std::ops::RangeInclusive<usize> = 8..=65464;
+
+/// Largest window the server agrees to, RFC 7440 allows up to 65535 blocks.
+/// Larger requests are acked with this value.
+pub const MAX_WINDOWSIZE: usize = 64;
 use ouroboros::self_referencing;
 
 use std::{collections::HashMap, fmt::Display, fs::File, io::Read};
@@ -114,19 +124,27 @@ impl Handle for TestTftp {
     }
 
     fn repeat_last_read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if buf.len() != self.last_read && self.last_read != 0 {
-            return Err(Error::Generic(
-                "Buffer size does not match last read size".to_string(),
-            ));
-        } else if self.last_read == 0 {
+        if self.last_read == 0 {
             error!("Only repeat of data packets is currently supported. But TFTP Ack seems to need to be replayed. TBD.");
             return Err(Error::Ignore("No last read".to_string()));
+        } else if buf.len() < self.last_read {
+            return Err(Error::Generic(
+                "Buffer is smaller than the last read".to_string(),
+            ));
         }
 
         self.file
             .seek(std::io::SeekFrom::Current(-(self.last_read as i64)))?;
 
-        self.read(buf)
+        // The last block of a file is shorter than the buffer
+        let last_read = self.last_read;
+        self.read(&mut buf[..last_read])
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<()> {
+        self.file.seek(std::io::SeekFrom::Start(pos))?;
+        self.last_read = 0;
+        Ok(())
     }
 }
 
@@ -141,6 +159,9 @@ pub trait Handle {
 
     fn repeat_last_read(&mut self, buf: &mut [u8]) -> Result<usize>;
 
+    /// Moves to byte `pos` of the file, the next read starts there
+    fn seek(&mut self, pos: u64) -> Result<()>;
+
     /// Writes a buffer into this handle's buffer, returning how many bytes were written.
     ///
     /// `buf` can be anywhere from 0 to 512 bytes long.
@@ -213,6 +234,13 @@ pub struct Transfer<H> {
     pub connection: TftpConnection,
     pub is_write: bool,
     pub last_block_num: u16,
+    /// Data blocks sent so far, block numbers wrap around after 65535.
+    /// Goes back to `blocks_acked` when the window is sent again.
+    pub blocks_sent: usize,
+    /// Data blocks the client acknowledged
+    pub blocks_acked: usize,
+    /// Blocks of the current window that are not sent yet, they are due right away
+    pub window_due: usize,
     pub options: TftpOptions,
     pub retries: u8,
     pub timeout: Instant,
@@ -235,66 +263,94 @@ impl<H> Transfer<H>
 where
     H: Handle,
 {
-    pub fn new(xfer_idx: H, connection: TftpConnection, is_write: bool) -> Self {
+    pub fn new(xfer_idx: H, connection: TftpConnection, is_write: bool, now: Instant) -> Self {
         Self {
             handle: xfer_idx,
             connection,
             options: TftpOptions::new(),
             is_write,
             retries: 0,
-            timeout: Instant::now() + Duration::from_millis(200),
+            timeout: now + RETRY_TIMEOUT,
             last_block_num: 0,
+            blocks_sent: 0,
+            blocks_acked: 0,
+            window_due: 0,
         }
     }
 
-    pub fn process_timeout(&mut self) -> Result<Vec<u8>> {
-        if self.retries >= 10 {
+    /// Writes the next block of the window to `buffer`, or the retransmission of the
+    /// last frame once the timeout expired. Returns the frame length.
+    pub fn process_timeout(&mut self, buffer: &mut [u8], now: Instant) -> Result<usize> {
+        if self.window_due > 0 {
+            return self.send_next_block(buffer, now);
+        }
+
+        if self.retries >= MAX_RETRIES {
             return Err(Error::MaxRetriesExceeded);
         }
 
-        if self.timeout <= Instant::now() {
+        if self.timeout <= now {
             info!(
                 "Timeout detected. Resending last data packet. Attempt: {}",
                 self.retries
             );
             self.retries += 1;
-            self.reset_timeout();
-            return self.resend_last_data();
+            self.reset_timeout(now);
+            // No data sent yet, the option ack got lost
+            if self.blocks_sent == 0 {
+                return self.ack_options(buffer);
+            }
+            return self.resend_window(buffer, now);
         }
         Err(Error::IgnoreNoLog("".to_string()))
     }
 
-    pub fn reset_timeout(&mut self) {
-        self.timeout = Instant::now() + Duration::from_millis(200);
+    pub fn reset_timeout(&mut self, now: Instant) {
+        self.timeout = now + RETRY_TIMEOUT;
     }
 
-    pub fn resend_last_data(&mut self) -> Result<Vec<u8>> {
-        // Read file in chunks of blksize into buffer s
-        let blksize = self.options.get(TftpOptionEnum::Blksize).unwrap();
-        let mut s = vec![0u8; blksize];
-        let bytes_read = match self.handle.repeat_last_read(s.as_mut_slice()) {
-            Ok(len) => len,
-            Err(e) => {
-                return Err(Error::Tftp(f!("tftp: error reading file: {}", e)));
-            }
-        };
-        if bytes_read == 0 {
-            log::info!("End of file reached");
-            return Err(Error::TftpEndOfFile);
-        }
+    fn blksize(&self) -> usize {
+        self.options
+            .get(TftpOptionEnum::Blksize)
+            .unwrap_or(DEFAULT_BLKSIZE)
+    }
 
-        let data = Repr::Data {
-            block_num: self.last_block_num,
-            data: &s.as_slice()[..bytes_read],
-        };
-        log::debug!(
-            "Sending data block {} of size {}",
-            self.last_block_num,
-            bytes_read
-        );
+    /// Blocks sent before the client has to ack, RFC 7440
+    fn windowsize(&self) -> usize {
+        self.options.get(TftpOptionEnum::WindowSize).unwrap_or(1)
+    }
 
-        let packet = utils::tftp_to_ether_unicast(&data, &self.connection);
-        Ok(packet)
+    /// Returns the part of `buffer` the next data block is read into
+    fn data_buffer<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b mut [u8]> {
+        let blksize = self.blksize();
+        let buffer_len = buffer.len();
+        buffer
+            .get_mut(utils::TFTP_DATA_OFFSET..utils::TFTP_DATA_OFFSET + blksize)
+            .ok_or_else(|| {
+                Error::Device(f!(
+                    "tftp: transmit buffer of {} bytes is too small for blksize {}",
+                    buffer_len,
+                    blksize
+                ))
+            })
+    }
+
+    /// Sends the window again, starting with the block after the last ack
+    pub fn resend_window(&mut self, buffer: &mut [u8], now: Instant) -> Result<usize> {
+        self.rewind()?;
+        self.window_due = self.windowsize();
+        self.send_next_block(buffer, now)
+    }
+
+    /// Continues reading after the last acked block
+    fn rewind(&mut self) -> Result<()> {
+        let pos = self.blocks_acked as u64 * self.blksize() as u64;
+        if let Err(e) = self.handle.seek(pos) {
+            return Err(Error::Tftp(f!("tftp: error reading file: {}", e)));
+        }
+        self.blocks_sent = self.blocks_acked;
+        self.last_block_num = self.blocks_sent as u16;
+        Ok(())
     }
 
     pub fn send_timeout(&mut self) -> Result<Vec<u8>> {
@@ -307,8 +363,20 @@ where
         Ok(packet)
     }
 
-    pub fn send_data(&mut self, ack_block_num: u16) -> Result<Vec<u8>> {
-        if ack_block_num != self.last_block_num {
+    /// Handles the ack of `ack_block_num` and writes the first data frame of the next
+    /// window to `buffer`. Returns the frame length.
+    pub fn send_data(
+        &mut self,
+        ack_block_num: u16,
+        buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        // Any block of the window may be acked, the client lost the blocks after it.
+        // Repeated acks of the last acked block are dropped, answering them would
+        // send every following block twice.
+        let outstanding = self.blocks_sent - self.blocks_acked;
+        let acked = usize::from(ack_block_num.wrapping_sub(self.blocks_acked as u16));
+        if acked > outstanding || (acked == 0 && outstanding > 0) {
             return Err(Error::Ignore(f!(
                 "tftp: received ack for block {} but expected {}",
                 ack_block_num,
@@ -316,12 +384,28 @@ where
             )));
         }
 
-        self.reset_timeout();
+        // Only consecutive losses count against the retry limit
+        self.retries = 0;
+        self.blocks_acked += acked;
+        if acked < outstanding {
+            debug!(
+                "tftp: client lost the blocks after {}, resending them",
+                ack_block_num
+            );
+            self.rewind()?;
+        }
+        self.window_due = self.windowsize();
+        self.send_next_block(buffer, now)
+    }
+
+    /// Writes the next data frame of the window to `buffer`. Returns the frame length.
+    fn send_next_block(&mut self, buffer: &mut [u8], now: Instant) -> Result<usize> {
+        self.reset_timeout(now);
 
-        // Read file in chunks of blksize into buffer s
-        let blksize = self.options.get(TftpOptionEnum::Blksize).unwrap();
-        let mut s = vec![0u8; blksize];
-        let bytes_read = match self.handle.read(s.as_mut_slice()) {
+        // Read the file directly behind the headers of the frame
+        let data = self.data_buffer(buffer)?;
+        let blksize = data.len();
+        let bytes_read = match self.handle.read(data) {
             Ok(len) => len,
             Err(e) => {
                 return Err(Error::Tftp(f!("tftp: error reading file: {}", e)));
@@ -329,26 +413,35 @@ where
         };
 
         if bytes_read == 0 {
-            log::info!("End of file reached");
-            return Err(Error::TftpEndOfFile);
+            self.window_due = 0;
+            if self.blocks_sent == self.blocks_acked {
+                log::info!("End of file reached");
+                return Err(Error::TftpEndOfFile);
+            }
+            return Err(Error::IgnoreNoLog(
+                "tftp: the window ends with the file".to_string(),
+            ));
         }
 
-        let data = Repr::Data {
-            block_num: self.last_block_num + 1,
-            data: &s.as_slice()[..bytes_read],
-        };
-        self.last_block_num += 1;
+        // The short last block ends the window
+        if bytes_read < blksize {
+            self.window_due = 0;
+        } else {
+            self.window_due = self.window_due.saturating_sub(1);
+        }
+        self.last_block_num = self.last_block_num.wrapping_add(1);
+        self.blocks_sent += 1;
         log::debug!(
             "Sending data block {} of size {}",
             self.last_block_num,
             bytes_read
         );
 
-        let packet = utils::tftp_to_ether_unicast(&data, &self.connection);
-        Ok(packet)
+        utils::emit_tftp_data_unicast(self.last_block_num, bytes_read, &self.connection, buffer)
     }
 
-    pub fn ack_options(&self) -> Result<Vec<u8>> {
+    /// Writes the option acknowledgement frame to `buffer`. Returns the frame length.
+    pub fn ack_options(&self, buffer: &mut [u8]) -> Result<usize> {
         let ack_opts = self.options.to_str_str();
         let needed_bytes = ack_opts.iter().fold(0, |acc, (name, value)| {
             acc + (TftpOption { name, value }).len()
@@ -370,8 +463,7 @@ where
         let opts = parse::TftpOptsReader::new(&resp_opt_buf[..written_bytes]);
 
         let ack = Repr::OptionAck { opts };
-        let packet = utils::tftp_to_ether_unicast(&ack, &self.connection);
-        Ok(packet)
+        utils::emit_tftp_unicast(&ack, &self.connection, buffer)
     }
 }
 
@@ -394,3 +486,129 @@ impl Display for TftpConnection {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    fn connection() -> TftpConnection {
+        TftpConnection {
+            server_ip: Ipv4Address::new(192, 168, 178, 97),
+            server_mac: EthernetAddress::from_bytes(&[0x98, 0xfa, 0x9b, 0x4b, 0xb2, 0xc4]),
+            client_ip: Ipv4Address::new(192, 168, 178, 75),
+            client_mac: EthernetAddress::from_bytes(&[0x00, 0x01, 0x2e, 0x91, 0xf7, 0xfe]),
+            server_port: 69,
+            client_port: 2070,
+        }
+    }
+
+    /// Block number and data of a data frame
+    fn data_block(frame: &[u8]) -> (u16, Vec<u8>) {
+        let header = &frame[utils::TFTP_DATA_OFFSET - 2..utils::TFTP_DATA_OFFSET];
+        let block_num = u16::from_be_bytes([header[0], header[1]]);
+        (block_num, frame[utils::TFTP_DATA_OFFSET..].to_vec())
+    }
+
+    #[test]
+    fn test_repeat_last_short_read() {
+        let path = Path::new("./assets/kernel.elf");
+        let file_len = std::fs::metadata(path).unwrap().len() as usize;
+        assert_ne!(file_len % DEFAULT_BLKSIZE, 0);
+
+        let mut handle = TestTftp::new(std::fs::File::open(path).unwrap());
+        let mut buf = vec![0; DEFAULT_BLKSIZE];
+        let last_block = loop {
+            let len = handle.read(&mut buf).unwrap();
+            if len < DEFAULT_BLKSIZE {
+                break buf[..len].to_vec();
+            }
+        };
+        assert_eq!(last_block.len(), file_len % DEFAULT_BLKSIZE);
+
+        // The retransmission of the final block has its short length
+        let mut repeated = vec![0; DEFAULT_BLKSIZE];
+        let len = handle.repeat_last_read(&mut repeated).unwrap();
+        assert_eq!(&repeated[..len], &last_block[..]);
+        assert_eq!(handle.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_block_number_wraparound() {
+        let path = Path::new("./assets/kernel.elf");
+        let now = Instant::from_secs(0);
+        let handle = TestTftp::new(std::fs::File::open(path).unwrap());
+        let mut trans = Transfer::new(handle, connection(), false, now);
+        // More than 65535 blocks of the file
+        let blksize = 16;
+        trans.options.add(TftpOptionEnum::Blksize, blksize);
+        let file_len = std::fs::metadata(path).unwrap().len() as usize;
+        assert!(file_len / blksize > usize::from(u16::MAX));
+
+        let mut buffer = vec![0; utils::TFTP_DATA_OFFSET + blksize];
+        loop {
+            match trans.send_data(trans.last_block_num, &mut buffer, now) {
+                Ok(_) => (),
+                Err(Error::TftpEndOfFile) => break,
+                Err(e) => panic!("{}", e),
+            }
+            // Block 0 follows block 65535, only the counter tells it from the first ack
+            if trans.blocks_sent == usize::from(u16::MAX) + 1 {
+                assert_eq!(trans.last_block_num, 0);
+                let block = &buffer[utils::TFTP_DATA_OFFSET - 2..utils::TFTP_DATA_OFFSET];
+                assert_eq!(block, [0, 0]);
+            }
+        }
+        assert_eq!(trans.blocks_sent, file_len.div_ceil(blksize));
+        assert_eq!(trans.last_block_num, trans.blocks_sent as u16);
+    }
+
+    #[test]
+    fn test_windowsize() {
+        let path = Path::new("./assets/kernel.elf");
+        let file = std::fs::read(path).unwrap();
+        let now = Instant::from_secs(0);
+        let handle = TestTftp::new(std::fs::File::open(path).unwrap());
+        let mut trans = Transfer::new(handle, connection(), false, now);
+        trans.options.add(TftpOptionEnum::WindowSize, 4);
+        let mut buffer = vec![0; utils::TFTP_DATA_OFFSET + DEFAULT_BLKSIZE];
+        let block = |n: usize| file[(n - 1) * DEFAULT_BLKSIZE..n * DEFAULT_BLKSIZE].to_vec();
+
+        // The ack of the option ack sends the first block, the rest of the window is due
+        let len = trans.send_data(0, &mut buffer, now).unwrap();
+        assert_eq!(data_block(&buffer[..len]), (1, block(1)));
+        for n in 2..=4 {
+            let len = trans.process_timeout(&mut buffer, now).unwrap();
+            assert_eq!(data_block(&buffer[..len]), (n as u16, block(n)));
+        }
+        assert!(matches!(
+            trans.process_timeout(&mut buffer, now),
+            Err(Error::IgnoreNoLog(_))
+        ));
+
+        // The client lost block 3, the next window starts after its ack
+        let len = trans.send_data(2, &mut buffer, now).unwrap();
+        assert_eq!(data_block(&buffer[..len]), (3, block(3)));
+        assert_eq!((trans.blocks_acked, trans.blocks_sent), (2, 3));
+
+        // Repeated and unknown acks are dropped
+        assert!(matches!(
+            trans.send_data(2, &mut buffer, now),
+            Err(Error::Ignore(_))
+        ));
+        assert!(matches!(
+            trans.send_data(9, &mut buffer, now),
+            Err(Error::Ignore(_))
+        ));
+
+        // Without an ack the window is sent again
+        for n in 4..=6 {
+            trans.process_timeout(&mut buffer, now).unwrap();
+            assert_eq!(trans.blocks_sent, n);
+        }
+        let later = now + RETRY_TIMEOUT;
+        let len = trans.process_timeout(&mut buffer, later).unwrap();
+        assert_eq!(data_block(&buffer[..len]), (3, block(3)));
+        assert_eq!(trans.retries, 1);
+    }
+}
@@ -26,6 +26,9 @@ pub enum Error {
     #[error("Kill current tftp connection")]
     StopTftpConnection(Vec<u8>),
 
+    #[error("Device error: {0}")]
+    Device(String),
+
     #[error(transparent)]
     IO(#[from] std::io::Error),
 
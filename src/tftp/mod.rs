@@ -1,3 +1,4 @@
+pub mod cache;
 pub mod construct;
 pub mod parse;
 
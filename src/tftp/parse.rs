@@ -125,10 +125,39 @@ impl<T: AsRef<[u8]>> Packet<T> {
                 OpCode::Unknown(_) => return Err(Error),
             };
             if len < end {
-                Err(Error)
-            } else {
-                Ok(())
+                return Err(Error);
             }
+
+            // The strings are handed out as &str, reject frames that are not utf8
+            // or whose fields are not where the accessors expect them
+            let data = self.buffer.as_ref();
+            match self.opcode() {
+                OpCode::Read | OpCode::Write => {
+                    let filename_end = data[field::OPCODE.end..]
+                        .iter()
+                        .position(|b| *b == 0)
+                        .ok_or(Error)?
+                        + field::OPCODE.end;
+                    let mode = Mode::from(*data.get(filename_end + 1).ok_or(Error)?);
+                    let opts_start = filename_end + 1 + mode.as_str().len() + 1;
+                    if opts_start > len || data[opts_start - 1] != 0 {
+                        return Err(Error);
+                    }
+                    str::from_utf8(&data[field::OPCODE.end..filename_end]).map_err(|_| Error)?;
+                    str::from_utf8(&data[opts_start..]).map_err(|_| Error)?;
+                }
+                OpCode::Error => {
+                    if len <= field::ERROR_STRING.start {
+                        return Err(Error);
+                    }
+                    str::from_utf8(&data[field::ERROR_STRING.start..len - 1]).map_err(|_| Error)?;
+                }
+                OpCode::OptionAck => {
+                    str::from_utf8(&data[field::OPCODE.end..]).map_err(|_| Error)?;
+                }
+                _ => (),
+            }
+            Ok(())
         }
     }
 
@@ -217,7 +246,7 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Packet<T> {
         data[fn_start..mode_start - 1].copy_from_slice(fname.as_bytes());
         data[mode_start..mode_end].copy_from_slice(mode.as_bytes());
         data[mode_start - 1] = 0;
-        data[data.len() - 1] = 0;
+        data[mode_end] = 0;
     }
 
     /// Sets the block number of this packet.
@@ -352,9 +381,10 @@ impl TftpOptsReader<'_> {
             }
             let value = parts.next().unwrap_or(&[]);
 
+            // Options of a checked packet are utf8, stop at garbage in unchecked buffers
             return Some(TftpOption {
-                name: core::str::from_utf8(name).unwrap(),
-                value: core::str::from_utf8(value).unwrap(),
+                name: core::str::from_utf8(name).ok()?,
+                value: core::str::from_utf8(value).ok()?,
             });
         })
     }
@@ -465,7 +495,7 @@ impl<'a> Repr<'a> {
                 let start = field::OPCODE.end;
                 let mut opt_mut = packet.options_mut(start);
                 for option in opts.options() {
-                    opt_mut.emit(option).unwrap();
+                    opt_mut.emit(option)?;
                 }
             }
             Self::ReadRequest {
@@ -478,7 +508,7 @@ impl<'a> Repr<'a> {
                 let start = field::OPCODE.end + filename.len() + 1 + mode.as_str().len() + 1;
                 let mut opt_mut = packet.options_mut(start);
                 for option in opts.options() {
-                    opt_mut.emit(option).unwrap();
+                    opt_mut.emit(option)?;
                 }
             }
             Self::WriteRequest {
@@ -491,7 +521,7 @@ impl<'a> Repr<'a> {
                 let start = field::OPCODE.end + filename.len() + 1 + mode.as_str().len() + 1;
                 let mut opt_mut = packet.options_mut(start);
                 for option in opts.options() {
-                    opt_mut.emit(option).unwrap();
+                    opt_mut.emit(option)?;
                 }
             }
             Self::Data { block_num, data } => {
@@ -517,6 +547,7 @@ impl<'a> Repr<'a> {
 mod test {
     use super::*;
 
+    use proptest::prelude::*;
     use std::vec;
 
     static RRQ_BYTES: [u8; 20] = [
@@ -598,6 +629,35 @@ mod test {
         assert_eq!(packet.error_msg(), "Error");
     }
 
+    #[test]
+    fn test_check_len() {
+        for bytes in [
+            &RRQ_BYTES[..],
+            &WRQ_BYTES[..],
+            &DATA_BYTES[..],
+            &ACK_BYTES[..],
+            &ERR_BYTES[..],
+        ] {
+            assert!(Packet::new_checked(bytes).is_ok());
+        }
+
+        for bytes in [
+            // Read request without mode
+            &b"\x00\x01rfc1350.txt\x00"[..],
+            // Mode is shorter than its first letter promises
+            &b"\x00\x01rfc1350.txt\x00oct\x00"[..],
+            // Filename is not utf8
+            &b"\x00\x01\xff\xfe\x00octet\x00"[..],
+            // Option is not utf8
+            &b"\x00\x01rfc1350.txt\x00octet\x00blksize\x00\xff\x00"[..],
+            // Error without code
+            &b"\x00\x05\x00"[..],
+            &b"\x00\x09\x00\x00"[..],
+        ] {
+            assert!(Packet::new_checked(bytes).is_err(), "{:?}", bytes);
+        }
+    }
+
     #[test]
     fn test_construct() {
         let mut packet = Packet::new_unchecked(vec![0xa5; 20]);
@@ -714,4 +774,115 @@ mod test {
             assert_eq!(&packet.buffer[..], bytes);
         }
     }
+
+    /// A [`Repr`] that owns its fields, so it can be generated
+    #[derive(Debug, Clone)]
+    enum OwnedRepr {
+        ReadRequest(String, Mode, Vec<(String, String)>),
+        WriteRequest(String, Mode, Vec<(String, String)>),
+        Data(u16, Vec<u8>),
+        Ack(u16),
+        OptionAck(Vec<(String, String)>),
+        Error(ErrorCode, String),
+    }
+
+    impl OwnedRepr {
+        fn opts(&self) -> Vec<u8> {
+            let opts = match self {
+                OwnedRepr::ReadRequest(_, _, opts)
+                | OwnedRepr::WriteRequest(_, _, opts)
+                | OwnedRepr::OptionAck(opts) => opts.as_slice(),
+                _ => &[],
+            };
+            let len = opts
+                .iter()
+                .map(|(name, value)| name.len() + value.len() + 2)
+                .sum();
+            let mut buffer = vec![0; len];
+            let mut writer = TftpOptsWriter::new(&mut buffer);
+            for (name, value) in opts {
+                writer.emit(TftpOption::new(name, value)).unwrap();
+            }
+            buffer
+        }
+
+        fn repr<'a>(&'a self, opts: &'a [u8]) -> Repr<'a> {
+            match self {
+                OwnedRepr::ReadRequest(filename, mode, _) => Repr::ReadRequest {
+                    filename: filename.as_str(),
+                    mode: *mode,
+                    opts: TftpOptsReader(opts),
+                },
+                OwnedRepr::WriteRequest(filename, mode, _) => Repr::WriteRequest {
+                    filename: filename.as_str(),
+                    mode: *mode,
+                    opts: TftpOptsReader(opts),
+                },
+                OwnedRepr::Data(block_num, data) => Repr::Data {
+                    block_num: *block_num,
+                    data: data.as_slice(),
+                },
+                OwnedRepr::Ack(block_num) => Repr::Ack {
+                    block_num: *block_num,
+                },
+                OwnedRepr::OptionAck(_) => Repr::OptionAck {
+                    opts: TftpOptsReader(opts),
+                },
+                OwnedRepr::Error(code, msg) => Repr::Error {
+                    code: *code,
+                    msg: msg.as_str(),
+                },
+            }
+        }
+    }
+
+    fn mode() -> impl Strategy<Value = Mode> {
+        prop_oneof![
+            Just(Mode::NetAscii),
+            Just(Mode::Octet),
+            Just(Mode::Mail),
+            Just(Mode::Unknown),
+        ]
+    }
+
+    /// Options are NUL separated, a name of "0" or "" ends the list
+    fn options(min: usize) -> impl Strategy<Value = Vec<(String, String)>> {
+        prop::collection::vec(("[a-z][a-z0-9]{0,7}", "[a-z0-9]{0,8}"), min..4)
+    }
+
+    fn owned_repr() -> impl Strategy<Value = OwnedRepr> {
+        // Filenames and messages are NUL terminated
+        let text = "\\PC{0,32}";
+        prop_oneof![
+            (text, mode(), options(0)).prop_map(|(f, m, o)| OwnedRepr::ReadRequest(f, m, o)),
+            (text, mode(), options(0)).prop_map(|(f, m, o)| OwnedRepr::WriteRequest(f, m, o)),
+            (any::<u16>(), prop::collection::vec(any::<u8>(), 0..1500))
+                .prop_map(|(block_num, data)| OwnedRepr::Data(block_num, data)),
+            any::<u16>().prop_map(OwnedRepr::Ack),
+            // An oack without options is shorter than the packet header
+            options(1).prop_map(OwnedRepr::OptionAck),
+            (any::<u16>().prop_map(ErrorCode::from), text)
+                .prop_map(|(code, msg)| OwnedRepr::Error(code, msg)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_repr_round_trip(owned in owned_repr()) {
+            let opts = owned.opts();
+            let repr = owned.repr(&opts);
+            let len = repr.buffer_len();
+
+            // Emitting into buffers of different content only gives the same bytes
+            // if all of the buffer_len bytes were written
+            let mut zeros = vec![0x00; len];
+            let mut ones = vec![0xff; len];
+            repr.emit(&mut Packet::new_unchecked(&mut zeros[..])).unwrap();
+            repr.emit(&mut Packet::new_unchecked(&mut ones[..])).unwrap();
+            prop_assert_eq!(&zeros, &ones);
+
+            let packet = Packet::new_checked(&zeros[..]).unwrap();
+            prop_assert_eq!(Repr::parse(&packet).unwrap(), repr);
+        }
+    }
 }
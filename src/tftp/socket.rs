@@ -7,19 +7,19 @@ use smoltcp::{
 
 use crate::dhcp::parse::FirmwareType;
 
+use super::cache::{CachedFile, FileCache};
 use super::error::*;
 use super::utils;
 use super::{construct::TftpConnection, parse::Repr};
 use super::{
-    construct::{TestTftp, TftpError, TftpOptionEnum, Transfer},
-    parse::{self, TftpOption},
+    construct::{TftpError, TftpOptionEnum, Transfer, BLKSIZE_RANGE, MAX_WINDOWSIZE},
+    parse::{self, ErrorCode, TftpOption},
 };
 
 use ouroboros::self_referencing;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
     fmt::Display,
-    fs::File,
     io::Seek,
     path::{Path, PathBuf},
 };
@@ -58,6 +58,10 @@ impl Display for TftpStates {
     }
 }
 
+/// Closed transfers remembered to drop their late packets. Late packets arrive
+/// within a few retransmissions, only the most recent transfers matter.
+pub const MAX_CLOSED_TRANSFERS: usize = 8;
+
 #[derive(Debug)]
 pub struct TftpSocket {
     _state: TftpStates,
@@ -65,7 +69,10 @@ pub struct TftpSocket {
     server_ip: Ipv4Address,
     file_path: PathBuf,
     firmware_type: FirmwareType,
-    transfer: Option<Transfer<TestTftp>>,
+    files: FileCache,
+    transfer: Option<Transfer<CachedFile>>,
+    /// Connections of transfers that were given up, late packets of them are dropped
+    closed: VecDeque<TftpConnection>,
 }
 
 impl TftpSocket {
@@ -81,10 +88,17 @@ impl TftpSocket {
             file_path: file_path.to_path_buf(),
             server_mac,
             server_ip,
+            files: FileCache::new(),
             transfer: None,
+            closed: VecDeque::with_capacity(MAX_CLOSED_TRANSFERS),
         }
     }
 
+    /// Serves the boot file from `files`, share one cache between sockets to read it once
+    pub fn set_file_cache(&mut self, files: FileCache) {
+        self.files = files;
+    }
+
     pub fn set_state(&mut self, state: TftpStates) {
         self._state = state;
     }
@@ -93,87 +107,156 @@ impl TftpSocket {
         self._state
     }
 
-    pub fn process_timeout(&mut self) -> Result<Vec<u8>> {
+    /// Writes the next block of the window or a retransmission after a timeout to
+    /// `tx_buffer`. Returns the frame length.
+    pub fn process_timeout(&mut self, tx_buffer: &mut [u8], now: Instant) -> Result<usize> {
         if let Some(trans) = &mut self.transfer {
-            return match trans.process_timeout() {
-                Ok(packet) => Ok(packet),
+            return match trans.process_timeout(tx_buffer, now) {
+                Ok(len) => Ok(len),
                 Err(Error::MaxRetriesExceeded) => {
                     error!("Killing connection. Sending timeout");
-                    let packet = trans.send_timeout().unwrap();
+                    let packet = trans.send_timeout()?;
                     Err(Error::StopTftpConnection(packet))
                 }
                 Err(Error::Ignore(_) | Error::IgnoreNoLog(_)) => Err(Error::Ignore("".to_string())),
-                Err(e) => panic!("Error: {}", e),
+                Err(e) => Err(e),
             };
         }
         Err(Error::IgnoreNoLog("".to_string()))
     }
 
-    pub fn process(&mut self, rx_buffer: &[u8]) -> Result<Vec<u8>> {
+    /// Processes a received frame and writes the reply to `tx_buffer`. Returns the frame length.
+    pub fn process(
+        &mut self,
+        rx_buffer: &[u8],
+        tx_buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
         let (tftp_con, wrapper) = self.recv_tftp(rx_buffer)?;
 
-        match self.get_state() {
-            TftpStates::ReadRequest => {
-                let trans = match self.parse_ack_options(&wrapper, tftp_con) {
-                    Ok(transfer) => transfer,
-                    Err(e) => panic!("Received unexpected tftp error: {}", e),
-                };
+        // Wi-Fi links lose and duplicate frames. A repeated read request means our
+        // option ack got lost, one from a new port that the client gave up on the
+        // transfer and starts over.
+        if let (Repr::ReadRequest { .. }, Some(trans)) = (*wrapper.borrow_repr(), &self.transfer) {
+            if trans.connection == tftp_con {
+                if trans.blocks_sent != 0 {
+                    return Err(Error::Ignore(
+                        "tftp: duplicate read request of a running transfer".to_string(),
+                    ));
+                }
+                info!("Re-sending option ack to retransmitted read request");
+                return trans.ack_options(tx_buffer);
+            }
 
-                let packet = trans.ack_options().unwrap();
-                self.transfer = Some(trans);
+            if self.closed.contains(&tftp_con) {
+                return Err(Error::Ignore(
+                    "tftp: late read request of a closed transfer".to_string(),
+                ));
+            }
 
-                match self.firmware_type {
-                    FirmwareType::Intel => self.set_state(TftpStates::SecondReadRequest),
-                    FirmwareType::IPxe => self.set_state(TftpStates::Data),
-                }
+            info!(
+                "Client restarted the transfer from port {}",
+                tftp_con.client_port
+            );
+            self.close_transfer();
+            return self
+                .start_transfer(&wrapper, tftp_con, TftpStates::Data, tx_buffer, now)
+                .map_err(ignore_unexpected);
+        }
+        if let Some(trans) = &self.transfer {
+            if trans.connection != tftp_con {
+                return Err(Error::Ignore(f!(
+                    "tftp: packet from port {} does not belong to the transfer",
+                    tftp_con.client_port
+                )));
+            }
+        }
 
-                Ok(packet)
+        match self.get_state() {
+            TftpStates::ReadRequest => {
+                // Only the Intel firmware sends its tftp options in two read requests
+                let next_state = match self.firmware_type {
+                    FirmwareType::Intel => TftpStates::SecondReadRequest,
+                    _ => TftpStates::Data,
+                };
+                self.start_transfer(&wrapper, tftp_con, next_state, tx_buffer, now)
+                    .map_err(ignore_unexpected)
             }
             TftpStates::SecondReadRequest => {
-                let trans = {
-                    match self.parse_ack_options(&wrapper, tftp_con) {
-                        Ok(ack_opts) => ack_opts,
-                        Err(e) => {
-                            if let Error::TftpReceivedError(_, msg) = e {
-                                // Reset transfer because we received an error
-                                // This is expected and this is how the Intel firmware does
-                                // multiple tftp options in separate packets. This is not spec
-                                // compliant. Eyyy
-                                self.transfer = None;
-                                return Err(Error::IgnoreNoLog(msg));
-                            } else {
-                                panic!("Received unexpected tftp error: {}", e);
-                                //return Err(e);
-                            }
-                        }
+                match self.start_transfer(&wrapper, tftp_con, TftpStates::Data, tx_buffer, now) {
+                    Ok(len) => Ok(len),
+                    Err(Error::TftpReceivedError(_, msg)) => {
+                        // Reset transfer because we received an error
+                        // This is expected and this is how the Intel firmware does
+                        // multiple tftp options in separate packets. This is not spec
+                        // compliant. Eyyy
+                        self.close_transfer();
+                        Err(Error::IgnoreNoLog(msg))
                     }
-                };
-
-                let packet = trans.ack_options().unwrap();
-                self.transfer = Some(trans);
-                self.set_state(TftpStates::Data);
-                Ok(packet)
+                    Err(e) => Err(ignore_unexpected(e)),
+                }
             }
-            TftpStates::Data => match self.reply_data(&wrapper) {
-                Ok(packet) => Ok(packet),
+            TftpStates::Data => match self.reply_data(&wrapper, tx_buffer, now) {
+                Ok(len) => Ok(len),
                 Err(Error::TftpEndOfFile) => {
                     self.transfer = None;
                     Err(Error::TftpEndOfFile)
                 }
-                Err(Error::Ignore(e)) => Err(Error::Ignore(e)),
-                Err(e) => panic!("Received unexpected tftp error: {}", e),
+                Err(Error::TftpReceivedError(error, msg)) => {
+                    // The client aborted, wait for it to request the file again
+                    self.close_transfer();
+                    self.set_state(TftpStates::ReadRequest);
+                    Err(Error::Ignore(f!(
+                        "tftp: client aborted the transfer with {}: {}",
+                        error,
+                        msg
+                    )))
+                }
+                Err(e) => Err(ignore_unexpected(e)),
             },
-            TftpStates::Error => todo!(),
+            TftpStates::Error => Err(Error::Ignore("tftp: transfer failed".to_string())),
         }
     }
 
-    pub fn reply_data(&mut self, wrapper: &TftpPacketWrapper) -> Result<Vec<u8>> {
-        match (*wrapper.borrow_repr(), &mut self.transfer) {
-            (Repr::Ack { block_num }, Some(t)) => {
-                // Read file in chunks of blksize into buffer s
-                let packet = t.send_data(block_num)?;
-                Ok(packet)
+    fn close_transfer(&mut self) {
+        if let Some(trans) = self.transfer.take() {
+            if self.closed.len() == MAX_CLOSED_TRANSFERS {
+                self.closed.pop_front();
             }
+            self.closed.push_back(trans.connection);
+        }
+    }
+
+    /// Creates the transfer for a read request and writes the option ack to `tx_buffer`
+    fn start_transfer(
+        &mut self,
+        wrapper: &TftpPacketWrapper,
+        tftp_con: TftpConnection,
+        next_state: TftpStates,
+        tx_buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        let trans = match self.parse_ack_options(wrapper, tftp_con, now) {
+            Ok(trans) => trans,
+            Err(e) if matches!(wrapper.borrow_repr(), Repr::ReadRequest { .. }) => {
+                return refuse_request(e, &tftp_con, tx_buffer)
+            }
+            Err(e) => return Err(e),
+        };
+        let len = trans.ack_options(tx_buffer)?;
+        self.transfer = Some(trans);
+        self.set_state(next_state);
+        Ok(len)
+    }
+
+    pub fn reply_data(
+        &mut self,
+        wrapper: &TftpPacketWrapper,
+        tx_buffer: &mut [u8],
+        now: Instant,
+    ) -> Result<usize> {
+        match (*wrapper.borrow_repr(), &mut self.transfer) {
+            (Repr::Ack { block_num }, Some(t)) => t.send_data(block_num, tx_buffer, now),
             (Repr::Error { code, msg }, None | Some(_)) => {
                 let code: u16 = code.into();
                 let error = TftpError::from(code);
@@ -191,7 +274,8 @@ impl TftpSocket {
         &self,
         wrapper: &TftpPacketWrapper,
         tftp_con: TftpConnection,
-    ) -> Result<Transfer<TestTftp>> {
+        now: Instant,
+    ) -> Result<Transfer<CachedFile>> {
         {
             match (*wrapper.borrow_repr(), &self.transfer) {
                 (
@@ -211,11 +295,9 @@ impl TftpSocket {
                             "Creating TFTP transfer with file: {}",
                             self.file_path.display()
                         );
-                        let file = File::open(&self.file_path)?;
-                        let file_len = file.metadata()?.len();
-                        let xfer_idx = TestTftp::new(file);
-                        log::debug!("Opened file size: {}", file_len);
-                        Transfer::new(xfer_idx, tftp_con, *wrapper.borrow_is_write())
+                        let file = self.files.open(&self.file_path)?;
+                        log::debug!("Opened file size: {}", file.len());
+                        Transfer::new(file, tftp_con, *wrapper.borrow_is_write(), now)
                     };
 
                     for opt in opts.options() {
@@ -224,9 +306,15 @@ impl TftpSocket {
                         match name {
                             "blksize" => {
                                 match value.parse::<usize>() {
-                                    Ok(blksize) => {
+                                    Ok(blksize) if BLKSIZE_RANGE.contains(&blksize) => {
                                         t.options.add(TftpOptionEnum::Blksize, blksize);
                                     }
+                                    Ok(blksize) => {
+                                        return Err(Error::Tftp(f!(
+                                            "tftp: blksize {} is out of range",
+                                            blksize
+                                        )));
+                                    }
                                     Err(_) => {
                                         return Err(Error::Tftp(f!(
                                             "tftp: blksize option should be a number is however {}",
@@ -236,12 +324,23 @@ impl TftpSocket {
                                 };
                             }
                             "tsize" => {
-                                let tsize = t.handle.file.metadata()?.len();
+                                let tsize = t.handle.len();
                                 log::debug!("tftp: tsize: {}", tsize);
                                 t.options.add(TftpOptionEnum::Tsize, tsize as usize);
                             }
                             "windowsize" => {
-                                t.options.add(TftpOptionEnum::WindowSize, 1);
+                                match value.parse::<usize>() {
+                                    Ok(windowsize) if windowsize > 0 => {
+                                        let windowsize = windowsize.min(MAX_WINDOWSIZE);
+                                        t.options.add(TftpOptionEnum::WindowSize, windowsize);
+                                    }
+                                    _ => {
+                                        return Err(Error::Tftp(f!(
+                                            "tftp: windowsize option should be a positive number is however {}",
+                                            value
+                                        )));
+                                    }
+                                };
                             }
                             _ => warn!("Unhandled tftp option: {}={}", name, value),
                         }
@@ -308,3 +407,33 @@ impl TftpSocket {
         Ok((client, wrapper))
     }
 }
+
+/// Packets that do not fit the current state are late or duplicated, drop them
+fn ignore_unexpected(e: Error) -> Error {
+    match e {
+        Error::Tftp(msg) => Error::Ignore(msg),
+        Error::TftpReceivedError(error, msg) => {
+            Error::Ignore(f!("tftp: received error {}: {}", error, msg))
+        }
+        e => e,
+    }
+}
+
+/// Answers a read request we can not serve with a tftp error, so the client
+/// does not wait for a transfer that never starts
+fn refuse_request(e: Error, con: &TftpConnection, tx_buffer: &mut [u8]) -> Result<usize> {
+    let code = match &e {
+        Error::IO(io) if io.kind() == std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+        Error::IO(io) if io.kind() == std::io::ErrorKind::PermissionDenied => {
+            ErrorCode::AccessViolation
+        }
+        Error::IO(_) => ErrorCode::Undefined,
+        Error::Tftp(_) => ErrorCode::IllegalOperation,
+        _ => return Err(e),
+    };
+    warn!("Refusing tftp read request of {}: {}", con, e);
+
+    let msg = e.to_string();
+    let err = Repr::Error { code, msg: &msg };
+    utils::emit_tftp_unicast(&err, con, tx_buffer)
+}
@@ -44,7 +44,13 @@ pub fn unicast_ether_to_udp<'a>(
     server_mac: &'a EthernetAddress,
     server_ip: &'a Ipv4Address,
 ) -> Result<(UdpPacket<&'a [u8]>, IpEndpoint, EthernetAddress)> {
-    let ether = EthernetFrame::new_checked(buffer).unwrap();
+    let ether = match EthernetFrame::new_checked(buffer) {
+        Ok(e) => e,
+        Err(e) => {
+            let err = format!("Parsing ethernet frame failed: {}", e);
+            return Err(Error::IgnoreNoLog(err));
+        }
+    };
     if ether.dst_addr() != *server_mac {
         return Err(Error::IgnoreNoLog(
             "Mac address does not match with ours. And isn't broardcast".to_string(),
@@ -83,10 +89,51 @@ pub fn unicast_ether_to_udp<'a>(
     Ok((udp, src_endpoint, ether.src_addr()))
 }
 
+/// Offset of the file data in a TFTP data frame: ethernet, ipv4, udp and tftp headers
+pub const TFTP_DATA_OFFSET: usize = 14 + 20 + 8 + 4;
+
 pub fn tftp_to_ether_unicast<'a>(
     tftp: &'a tftp::parse::Repr<'a>,
     con: &'a TftpConnection,
 ) -> Vec<u8> {
+    let mut buffer = vec![0; TFTP_DATA_OFFSET - 4 + tftp.buffer_len()];
+    emit_tftp_unicast(tftp, con, &mut buffer).unwrap();
+    buffer
+}
+
+/// Writes the frame of `tftp` to the start of `buffer`. Returns the frame length.
+pub fn emit_tftp_unicast(
+    tftp: &tftp::parse::Repr,
+    con: &TftpConnection,
+    buffer: &mut [u8],
+) -> Result<usize> {
+    emit_udp_unicast(con, tftp.buffer_len(), buffer, |buf| {
+        let mut packet = tftp::parse::Packet::new_unchecked(buf);
+        tftp.emit(&mut packet).unwrap();
+    })
+}
+
+/// Writes the headers of a data frame around `data_len` bytes of file data
+/// that were already read to `buffer[TFTP_DATA_OFFSET..]`. Returns the frame length.
+pub fn emit_tftp_data_unicast(
+    block_num: u16,
+    data_len: usize,
+    con: &TftpConnection,
+    buffer: &mut [u8],
+) -> Result<usize> {
+    emit_udp_unicast(con, 4 + data_len, buffer, |buf| {
+        let mut packet = tftp::parse::Packet::new_unchecked(buf);
+        packet.set_opcode(tftp::parse::OpCode::Data);
+        packet.set_block_number(block_num);
+    })
+}
+
+fn emit_udp_unicast(
+    con: &TftpConnection,
+    payload_len: usize,
+    buffer: &mut [u8],
+    emit_payload: impl FnOnce(&mut [u8]),
+) -> Result<usize> {
     let mut checksum = ChecksumCapabilities::ignored();
     checksum.ipv4 = Checksum::Both;
     checksum.udp = Checksum::Both;
@@ -100,7 +147,7 @@ pub fn tftp_to_ether_unicast<'a>(
         src_addr: con.server_ip,
         dst_addr: con.client_ip,
         hop_limit: 128,
-        payload_len: tftp.buffer_len() + udp_packet.header_len(),
+        payload_len: payload_len + udp_packet.header_len(),
         next_header: IpProtocol::Udp,
     };
     log::trace!(
@@ -117,12 +164,17 @@ pub fn tftp_to_ether_unicast<'a>(
         ethertype: EthernetProtocol::Ipv4,
     };
 
-    let packet_size = eth_packet.buffer_len()
-        + ip_packet.buffer_len()
-        + udp_packet.header_len()
-        + tftp.buffer_len();
+    let packet_size =
+        eth_packet.buffer_len() + ip_packet.buffer_len() + udp_packet.header_len() + payload_len;
 
-    let mut buffer = vec![0; packet_size];
+    if buffer.len() < packet_size {
+        return Err(Error::Device(f!(
+            "Frame of {} bytes does not fit into transmit buffer of {} bytes",
+            packet_size,
+            buffer.len()
+        )));
+    }
+    let buffer = &mut buffer[..packet_size];
 
     let mut packet = EthernetFrame::new_unchecked(&mut buffer[..]);
     eth_packet.emit(&mut packet);
@@ -135,12 +187,9 @@ pub fn tftp_to_ether_unicast<'a>(
         &mut packet,
         &ip_packet.src_addr.into_address(),
         &ip_packet.dst_addr.into_address(),
-        tftp.buffer_len(),
-        |buf| {
-            let mut packet = tftp::parse::Packet::new_unchecked(buf);
-            tftp.emit(&mut packet).unwrap();
-        },
+        payload_len,
+        emit_payload,
         &checksum,
     );
-    buffer
+    Ok(packet_size)
 }
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
+use std::ffi::CStr;
 use std::net::IpAddr;
 use std::os::fd::AsRawFd;
 
@@ -68,31 +69,95 @@ pub fn build_arp_announce(address: EthernetAddress, ip_addr: Ipv4Address) -> Vec
     buffer
 }
 
+/// Copies a frame that was built in its own buffer to `tx_buffer`. Returns the frame length.
+pub fn copy_frame(packet: &[u8], tx_buffer: &mut [u8]) -> Result<usize> {
+    match tx_buffer.get_mut(..packet.len()) {
+        Some(buffer) => {
+            buffer.copy_from_slice(packet);
+            Ok(packet.len())
+        }
+        None => Err(Error::Device(f!(
+            "Frame of {} bytes does not fit into transmit buffer of {} bytes",
+            packet.len(),
+            tx_buffer.len()
+        ))),
+    }
+}
+
+/// Returns the ipv4 addresses and prefix lengths of `interface`, including
+/// addresses with a label like `eth0:1`. The kernel answers via netlink.
+pub fn interface_ipv4_addrs(interface: &str) -> Result<Vec<Ipv4Cidr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let mut addrs = Vec::new();
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+
+        if ifa.ifa_addr.is_null() || ifa.ifa_netmask.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy();
+        let is_label = name
+            .strip_prefix(interface)
+            .map_or(false, |label| label.starts_with(':'));
+        if name != interface && !is_label {
+            continue;
+        }
+        if i32::from(unsafe { (*ifa.ifa_addr).sa_family }) != libc::AF_INET {
+            continue;
+        }
+
+        let (addr, netmask) = unsafe {
+            (
+                &*(ifa.ifa_addr as *const libc::sockaddr_in),
+                &*(ifa.ifa_netmask as *const libc::sockaddr_in),
+            )
+        };
+        // Both are in network byte order
+        let ip = Ipv4Address::from_bytes(&addr.sin_addr.s_addr.to_ne_bytes());
+        let prefix_len = netmask.sin_addr.s_addr.count_ones() as u8;
+        addrs.push(Ipv4Cidr::new(ip, prefix_len));
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// Configures `iface` with the ipv4 address of `interface`.
+/// Requests an address with DHCP if the interface has none and
+/// fails if it has several, as we could advertise the wrong one.
 pub fn get_ip<DeviceT: AsRawFd + smoltcp::phy::Device>(
     device: &mut DeviceT,
     iface: &mut Interface,
-) {
-    match local_ip_address::local_ip() {
-        Ok(ip) => {
-            let ip = match ip {
-                IpAddr::V4(ip) => {
-                    let t = Ipv4Address::from_bytes(&ip.octets());
-                    Ipv4Cidr::new(t, 24)
-                }
-                IpAddr::V6(_ip) => {
-                    panic!("IPv6 not supported");
-                }
-            };
-            log::info!("Local IP address: {}", ip);
+    interface: &str,
+) -> Result<()> {
+    let addrs = interface_ipv4_addrs(interface)?;
+    match addrs.as_slice() {
+        [] => {
+            log::info!("{} has no ipv4 address", interface);
+            request_dhcp_ip(device, iface);
+        }
+        [ip] => {
+            log::info!("Local IP address of {}: {}", interface, ip);
             iface.update_ip_addrs(|ip_addr| {
-                ip_addr.push(smoltcp::wire::IpCidr::Ipv4(ip)).unwrap();
+                ip_addr.push(smoltcp::wire::IpCidr::Ipv4(*ip)).unwrap();
             });
         }
-        Err(e) => {
-            log::error!("Failed to get local ip address: {}", e);
-            request_dhcp_ip(device, iface);
+        _ => {
+            let addrs: Vec<String> = addrs.iter().map(|ip| ip.to_string()).collect();
+            return Err(Error::Config(f!(
+                "{} has multiple ipv4 addresses ({}). Select one with --ip",
+                interface,
+                addrs.join(", ")
+            )));
         }
-    };
+    }
+    Ok(())
 }
 
 pub fn request_dhcp_ip<DeviceT: AsRawFd>(device: &mut DeviceT, iface: &mut Interface)
@@ -169,3 +234,16 @@ where
         buffer.copy_from_slice(&packet);
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interface_ipv4_addrs() {
+        let addrs = interface_ipv4_addrs("lo").unwrap();
+        assert!(addrs.contains(&Ipv4Cidr::new(Ipv4Address::new(127, 0, 0, 1), 8)));
+
+        assert!(interface_ipv4_addrs("rs_pxe_none0").unwrap().is_empty());
+    }
+}
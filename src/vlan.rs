@@ -0,0 +1,194 @@
+//! IEEE 802.1Q VLAN tags.
+//!
+//! The DHCP and TFTP parsers only understand untagged Ethernet frames. On a
+//! trunk port the tag is stripped from received frames before they reach a
+//! [`crate::PxeSocket`] and inserted again into every frame it sends.
+//!
+//! Raw sockets never see the tags, the kernel removes them before AF_PACKET
+//! taps get the frame. There every VLAN is served on its own `<interface>.<id>`
+//! VLAN interface instead, the inline tags are only used on tap devices.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use smoltcp::wire::Ipv4Cidr;
+
+use crate::prelude::*;
+
+/// Tag protocol identifier of 802.1Q tagged frames
+pub const VLAN_TPID: u16 = 0x8100;
+
+/// Size of the tag inserted after the source mac address
+pub const VLAN_TAG_LEN: usize = 4;
+
+/// Highest VLAN id, 4095 is reserved
+pub const VLAN_ID_MAX: u16 = 4094;
+
+const ETHER_ADDRS_LEN: usize = 12;
+
+/// Removes the 802.1Q tag of `frame` without copying the payload.
+/// Returns the VLAN id, the untagged frame is `frame[VLAN_TAG_LEN..]` afterwards.
+/// Untagged frames are not modified and `None` is returned.
+pub fn strip_vlan_tag_in_place(frame: &mut [u8]) -> Option<u16> {
+    if frame.len() < ETHER_ADDRS_LEN + VLAN_TAG_LEN + 2 {
+        return None;
+    }
+    let tpid = u16::from_be_bytes([frame[12], frame[13]]);
+    if tpid != VLAN_TPID {
+        return None;
+    }
+    let vlan_id = u16::from_be_bytes([frame[14], frame[15]]) & 0x0fff;
+
+    frame.copy_within(..ETHER_ADDRS_LEN, VLAN_TAG_LEN);
+    Some(vlan_id)
+}
+
+/// Tags an untagged frame of `len` bytes at `buffer[VLAN_TAG_LEN..]` in place.
+/// The tagged frame starts at the beginning of `buffer`.
+pub fn insert_vlan_tag_in_place(buffer: &mut [u8], len: usize, vlan_id: u16) -> &[u8] {
+    debug_assert!(vlan_id <= VLAN_ID_MAX);
+
+    buffer.copy_within(VLAN_TAG_LEN..VLAN_TAG_LEN + ETHER_ADDRS_LEN, 0);
+    buffer[ETHER_ADDRS_LEN..ETHER_ADDRS_LEN + 2].copy_from_slice(&VLAN_TPID.to_be_bytes());
+    buffer[ETHER_ADDRS_LEN + 2..ETHER_ADDRS_LEN + 4]
+        .copy_from_slice(&(vlan_id & 0x0fff).to_be_bytes());
+    &buffer[..len + VLAN_TAG_LEN]
+}
+
+/// A VLAN to serve, parsed from `<id>[:<ip>/<prefix>][:ipxe=<path>][:kernel=<path>]`.
+/// Without an address the VLAN is served with the address of the interface,
+/// or of its VLAN interface with `--raw`. Without images it serves those of the interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VlanConfig {
+    pub id: u16,
+    pub ip: Option<Ipv4Cidr>,
+    pub ipxe: Option<PathBuf>,
+    pub kernel: Option<PathBuf>,
+}
+
+impl FromStr for VlanConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut settings = s.split(':');
+        let id = settings.next().unwrap_or_default();
+
+        let id = match id.parse::<u16>() {
+            Ok(id) if (1..=VLAN_ID_MAX).contains(&id) => id,
+            _ => {
+                return Err(Error::Config(f!(
+                    "Invalid vlan id {}. Expected a number between 1 and {}",
+                    id,
+                    VLAN_ID_MAX
+                )))
+            }
+        };
+        let mut config = VlanConfig {
+            id,
+            ip: None,
+            ipxe: None,
+            kernel: None,
+        };
+        for setting in settings {
+            match setting.split_once('=') {
+                Some(("ipxe", path)) => config.ipxe = Some(PathBuf::from(path)),
+                Some(("kernel", path)) => config.kernel = Some(PathBuf::from(path)),
+                None if config.ip.is_none() => {
+                    let ip = Ipv4Cidr::from_str(setting).map_err(|_| {
+                        Error::Config(f!("Invalid ipv4 cidr for vlan {}: {}", id, setting))
+                    })?;
+                    config.ip = Some(ip);
+                }
+                _ => {
+                    return Err(Error::Config(f!(
+                        "Unknown setting of vlan {}: {}",
+                        id,
+                        setting
+                    )))
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::test_utils::{insert_vlan_tag, read_frames, strip_vlan_tag};
+    use smoltcp::wire::{EthernetFrame, EthernetProtocol, Ipv4Address};
+    use std::path::Path;
+
+    #[test]
+    fn test_vlan_tag_roundtrip() {
+        let frames = read_frames(Path::new("./assets/intel_bios_dhcp.pcapng"));
+        let untagged = &frames[0];
+
+        assert_eq!(strip_vlan_tag(untagged), None);
+
+        let tagged = insert_vlan_tag(untagged, 100);
+        assert_eq!(tagged.len(), untagged.len() + VLAN_TAG_LEN);
+        let ether = EthernetFrame::new_checked(&tagged[..]).unwrap();
+        assert_eq!(ether.ethertype(), EthernetProtocol::Unknown(VLAN_TPID));
+        let src_addr = EthernetFrame::new_checked(&untagged[..])
+            .unwrap()
+            .src_addr();
+        assert_eq!(ether.src_addr(), src_addr);
+
+        assert_eq!(strip_vlan_tag(&tagged), Some((100, untagged.clone())));
+
+        let mut buffer = vec![0; VLAN_TAG_LEN + untagged.len()];
+        buffer[VLAN_TAG_LEN..].copy_from_slice(untagged);
+        assert_eq!(
+            insert_vlan_tag_in_place(&mut buffer, untagged.len(), 100),
+            &tagged[..]
+        );
+        assert_eq!(strip_vlan_tag_in_place(&mut buffer), Some(100));
+        assert_eq!(&buffer[VLAN_TAG_LEN..], &untagged[..]);
+    }
+
+    #[test]
+    fn test_vlan_config() {
+        assert_eq!(
+            "100".parse::<VlanConfig>().unwrap(),
+            VlanConfig {
+                id: 100,
+                ip: None,
+                ipxe: None,
+                kernel: None,
+            }
+        );
+        assert_eq!(
+            "200:10.0.200.1/24".parse::<VlanConfig>().unwrap(),
+            VlanConfig {
+                id: 200,
+                ip: Some(Ipv4Cidr::new(Ipv4Address::new(10, 0, 200, 1), 24)),
+                ipxe: None,
+                kernel: None,
+            }
+        );
+        assert_eq!(
+            "300:10.0.30.1/24:ipxe=assets/ipxe.efi:kernel=installer.elf"
+                .parse::<VlanConfig>()
+                .unwrap(),
+            VlanConfig {
+                id: 300,
+                ip: Some(Ipv4Cidr::new(Ipv4Address::new(10, 0, 30, 1), 24)),
+                ipxe: Some(PathBuf::from("assets/ipxe.efi")),
+                kernel: Some(PathBuf::from("installer.elf")),
+            }
+        );
+        assert_eq!(
+            "400:kernel=installer.elf"
+                .parse::<VlanConfig>()
+                .unwrap()
+                .kernel,
+            Some(PathBuf::from("installer.elf"))
+        );
+        assert!("0".parse::<VlanConfig>().is_err());
+        assert!("4095".parse::<VlanConfig>().is_err());
+        assert!("100:10.0.0.1".parse::<VlanConfig>().is_err());
+        assert!("100:10.0.0.1/24:10.0.1.1/24".parse::<VlanConfig>().is_err());
+        assert!("100:initrd=foo".parse::<VlanConfig>().is_err());
+    }
+}
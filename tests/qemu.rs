@@ -0,0 +1,297 @@
+//! Boots QEMU guests from rs_pxe.
+//!
+//! Every guest gets its own bridge with two tap devices, one for rs_pxe and one for
+//! QEMU, and a dnsmasq that only hands out addresses like the DHCP server of a LAN.
+//! Creating them needs root, the tests also need `qemu`, `dnsmasq` and the EDK2
+//! images in `$QEMU_SHARE` of the nix shell:
+//! ```text
+//! sudo -E cargo test --features qemu --test qemu
+//! ```
+//! The serial console and the rs_pxe log of each guest are written to `./target`.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const KERNEL: &str = "./assets/kernel.elf";
+
+#[derive(Debug, Clone, Copy)]
+enum Guest {
+    /// SeaBIOS booting the iPXE floppy, which loads the kernel
+    Bios,
+    /// OVMF loading iPXE with its own PXE client. iPXE then downloads the kernel,
+    /// under the boot file name of the offer, but cannot run it.
+    UefiX64,
+    /// AAVMF loading the boot file. The iPXE image is x86_64, so the guest
+    /// cannot run it and only the download is checked.
+    Aarch64,
+}
+
+impl Guest {
+    fn name(self) -> &'static str {
+        match self {
+            Guest::Bios => "bios",
+            Guest::UefiX64 => "uefi_x64",
+            Guest::Aarch64 => "aarch64",
+        }
+    }
+
+    /// Separates the networks of guests that boot at the same time
+    fn id(self) -> u8 {
+        self as u8
+    }
+
+    fn ipxe(self) -> &'static str {
+        match self {
+            Guest::Bios => "./assets/ipxe.pxe",
+            Guest::UefiX64 | Guest::Aarch64 => "./assets/ipxe.efi",
+        }
+    }
+
+    /// Printed to the serial console once the boot succeeded
+    fn marker(self) -> &'static str {
+        match self {
+            Guest::Bios => "Successfully booted KERNEL",
+            Guest::UefiX64 => "ipxe.efi... ok",
+            Guest::Aarch64 => "NBP file downloaded successfully",
+        }
+    }
+
+    fn timeout(self) -> Duration {
+        match self {
+            Guest::Bios | Guest::UefiX64 => Duration::from_secs(120),
+            // Emulated without KVM
+            Guest::Aarch64 => Duration::from_secs(600),
+        }
+    }
+
+    fn qemu(self, tap: &str) -> Command {
+        let mac = format!("52:54:00:12:34:{:02x}", 0x56 + self.id());
+        let netdev = format!("tap,id=net0,ifname={},script=no,downscript=no", tap);
+        let (binary, nic) = match self {
+            Guest::Bios => ("qemu-system-x86_64", "e1000"),
+            Guest::UefiX64 => ("qemu-system-x86_64", "virtio-net-pci"),
+            Guest::Aarch64 => ("qemu-system-aarch64", "virtio-net-pci"),
+        };
+
+        let mut cmd = Command::new(binary);
+        cmd.args(["-m", "512M", "-snapshot", "-no-reboot", "-nodefaults"])
+            .args(["-serial", "stdio", "-display", "none", "-monitor", "none"])
+            .args(["-netdev", &netdev]);
+        match self {
+            Guest::Bios => {
+                cmd.args(["-accel", "kvm", "-accel", "tcg"])
+                    .args(["-device", &format!("{},netdev=net0,mac={}", nic, mac)])
+                    .args(["-fda", "./assets/ipxe.dsk"]);
+            }
+            Guest::UefiX64 => {
+                cmd.args(["-accel", "kvm", "-accel", "tcg"])
+                    .args([
+                        "-device",
+                        &format!("{},netdev=net0,mac={},romfile=", nic, mac),
+                    ])
+                    .args(["-drive", &pflash("edk2-x86_64-code.fd")]);
+            }
+            Guest::Aarch64 => {
+                cmd.args(["-M", "virt", "-cpu", "cortex-a57"])
+                    .args(["-device", &format!("{},netdev=net0,mac={}", nic, mac)])
+                    .args(["-drive", &pflash("edk2-aarch64-code.fd")]);
+            }
+        }
+        cmd
+    }
+}
+
+/// Read only flash drive of an EDK2 image in `$QEMU_SHARE`
+fn pflash(image: &str) -> String {
+    let share = std::env::var("QEMU_SHARE").unwrap_or_else(|_| "/usr/share/qemu".to_owned());
+    let path = PathBuf::from(share).join(image);
+    assert!(path.exists(), "Missing {}, set QEMU_SHARE", path.display());
+    format!("if=pflash,format=raw,readonly=on,file={}", path.display())
+}
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .expect("Failed to run ip");
+    assert!(
+        status.success(),
+        "ip {} failed, the qemu tests need root",
+        args.join(" ")
+    );
+}
+
+/// Kills the process when the test ends, also when it panics
+struct Process(Child);
+
+impl Process {
+    fn spawn(cmd: &mut Command) -> Self {
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        Self(
+            cmd.spawn()
+                .unwrap_or_else(|e| panic!("Failed to start {}: {}", program, e)),
+        )
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Bridge with a tap for rs_pxe and one for QEMU, removed again on drop
+struct Network {
+    bridge: String,
+    server_tap: String,
+    guest_tap: String,
+    subnet: String,
+}
+
+impl Network {
+    fn new(guest: Guest) -> Self {
+        let id = guest.id();
+        let net = Self {
+            bridge: format!("pxe_br{}", id),
+            server_tap: format!("pxe_srv{}", id),
+            guest_tap: format!("pxe_vm{}", id),
+            subnet: format!("192.168.{}", 60 + id),
+        };
+        // Leftovers of an aborted run
+        net.remove();
+
+        ip(&["link", "add", "name", &net.bridge, "type", "bridge"]);
+        for tap in [&net.server_tap, &net.guest_tap] {
+            ip(&["tuntap", "add", "dev", tap, "mode", "tap"]);
+            ip(&["link", "set", tap, "master", &net.bridge]);
+            ip(&["link", "set", tap, "up"]);
+        }
+        ip(&[
+            "addr",
+            "add",
+            &format!("{}.1/24", net.subnet),
+            "dev",
+            &net.bridge,
+        ]);
+        ip(&["link", "set", &net.bridge, "up"]);
+        net
+    }
+
+    fn remove(&self) {
+        for dev in [&self.guest_tap, &self.server_tap, &self.bridge] {
+            let _ = Command::new("ip")
+                .args(["link", "del", dev])
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+
+    /// DHCP server of the network, rs_pxe only answers as proxy
+    fn dnsmasq(&self, log: File) -> Process {
+        let range = format!("{0}.50,{0}.100,12h", self.subnet);
+        let leases = format!("--dhcp-leasefile=./target/qemu_{}.leases", self.bridge);
+        Process::spawn(
+            Command::new("dnsmasq")
+                .args(["-d", "-C", "/dev/null", "--port", "0", "--pid-file"])
+                .args(["--bind-interfaces", "--interface", &self.bridge])
+                .args(["--dhcp-range", &range, &leases])
+                .stdout(log.try_clone().unwrap())
+                .stderr(log),
+        )
+    }
+
+    fn rs_pxe(&self, guest: Guest, log: File) -> Process {
+        Process::spawn(
+            Command::new(env!("CARGO_BIN_EXE_rs_pxe"))
+                .args(["-l", "DEBUG", "--ipxe", guest.ipxe(), "-k", KERNEL])
+                .args(["-i", &self.server_tap, "--tap"])
+                .args(["--ip", &format!("{}.2/24", self.subnet)])
+                .stdout(log.try_clone().unwrap())
+                .stderr(log),
+        )
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+fn log_file(guest: Guest, name: &str) -> File {
+    File::create(format!("./target/qemu_{}_{}.log", guest.name(), name)).unwrap()
+}
+
+/// Boots the guest and waits for its success marker on the serial console
+fn boot(guest: Guest) {
+    let net = Network::new(guest);
+    let _dnsmasq = net.dnsmasq(log_file(guest, "dnsmasq"));
+    let _rs_pxe = net.rs_pxe(guest, log_file(guest, "rs_pxe"));
+    // Give the server time to open its tap
+    thread::sleep(Duration::from_secs(1));
+
+    let mut qemu = Process::spawn(
+        guest
+            .qemu(&net.guest_tap)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped()),
+    );
+    let mut stdout = qemu.0.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 4096];
+        while let Ok(len) = stdout.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            if tx.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let console_path = format!("./target/qemu_{}_console.log", guest.name());
+    let deadline = Instant::now() + guest.timeout();
+    let mut console = vec![];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(output) => console.extend(output),
+            Err(e) => {
+                fs::write(&console_path, &console).unwrap();
+                panic!(
+                    "{} guest did not print {:?} ({}), see {} and the rs_pxe log",
+                    guest.name(),
+                    guest.marker(),
+                    e,
+                    console_path
+                );
+            }
+        }
+        if String::from_utf8_lossy(&console).contains(guest.marker()) {
+            fs::write(&console_path, &console).unwrap();
+            return;
+        }
+    }
+}
+
+#[test]
+fn qemu_bios() {
+    boot(Guest::Bios);
+}
+
+#[test]
+fn qemu_uefi_x64() {
+    boot(Guest::UefiX64);
+}
+
+#[test]
+fn qemu_aarch64() {
+    boot(Guest::Aarch64);
+}